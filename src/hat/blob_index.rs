@@ -19,6 +19,7 @@ use sodiumoxide::randombytes::{randombytes};
 use std::collections::{HashMap};
 use rustc_serialize::hex::{ToHex};
 
+use errors::{HatError, HatResult};
 use process::{Process, MsgHandler};
 use sqlite3::database::{Database};
 
@@ -29,12 +30,13 @@ use sqlite3::{open};
 
 pub type BlobIndexProcess = Process<Msg, Reply>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct BlobDesc {
   pub name: Vec<u8>,
   pub id: i64,
 }
 
+/// Any message is answered with `Error` instead when the index cannot be read or written.
 pub enum Msg {
   /// Reserve an internal `BlobDesc` for a new blob.
   Reserve,
@@ -47,11 +49,22 @@ pub enum Msg {
   /// Report that this blob has been fully committed to persistent storage. We can now use its
   /// reference internally. Only committed blobs are considered "safe to use".
   CommitDone(BlobDesc),
+
+  /// List all blobs that have been committed to persistent storage. Blobs that are reserved or in
+  /// air are left out, as chunks may still be added to them.
+  /// Returns `Listing`.
+  List,
+
+  /// Forget a blob that has been deleted from persistent storage.
+  /// Returns `CommitOK`.
+  Delete(BlobDesc),
 }
 
 pub enum Reply {
   Reserved(BlobDesc),
   CommitOK,
+  Listing(Vec<BlobDesc>),
+  Error(HatError),
 }
 
 pub struct BlobIndex {
@@ -63,34 +76,35 @@ pub struct BlobIndex {
 
 impl BlobIndex {
 
-  pub fn new(path: String) -> BlobIndex {
+  pub fn new(path: String) -> HatResult<BlobIndex> {
     let mut hi = match open(&path) {
       Ok(dbh) => BlobIndex{
         dbh: dbh,
         next_id: -1,
         reserved: HashMap::new(),
       },
-      Err(err) => panic!("{:?}", err),
+      Err(err) => return Err(HatError::index(format!("Could not open blob index {}: {:?}",
+                                                  path, err))),
     };
-    hi.initialize();
-    hi
+    try!(hi.initialize());
+    Ok(hi)
   }
 
   #[cfg(test)]
   pub fn new_for_testing() -> BlobIndex {
-    BlobIndex::new(":memory:".to_string())
+    BlobIndex::new(":memory:".to_string()).unwrap()
   }
 
-  fn initialize(&mut self) {
-    self.exec_or_die("CREATE TABLE IF NOT EXISTS
-                      blob_index (id        INTEGER PRIMARY KEY,
-                                  name      BLOB,
-                                  tag       INT)");
-    self.exec_or_die("CREATE UNIQUE INDEX IF NOT EXISTS
-                      BlobIndex_UniqueName ON blob_index(name)");
-    self.exec_or_die("BEGIN");
+  fn initialize(&mut self) -> HatResult<()> {
+    try!(self.exec("CREATE TABLE IF NOT EXISTS
+                    blob_index (id        INTEGER PRIMARY KEY,
+                                name      BLOB,
+                                tag       INT)"));
+    try!(self.exec("CREATE UNIQUE INDEX IF NOT EXISTS
+                    BlobIndex_UniqueName ON blob_index(name)"));
+    try!(self.exec("BEGIN"));
 
-    self.refresh_next_id();
+    self.refresh_next_id()
   }
 
   fn new_blob_desc(&mut self) -> BlobDesc {
@@ -98,33 +112,37 @@ impl BlobIndex {
              id: self.next_id()}
   }
 
-  fn exec_or_die(&mut self, sql: &str) {
+  fn exec(&mut self, sql: &str) -> HatResult<()> {
     match self.dbh.exec(sql) {
-      Ok(true) => (),
-      Ok(false) => panic!("exec: {}", self.dbh.get_errmsg()),
-      Err(msg) => panic!("exec: {:?}, {:?}\nIn sql: '{}'\n",
-                         msg, self.dbh.get_errmsg(), sql)
+      Ok(true) => Ok(()),
+      Ok(false) => Err(HatError::index(format!("Blob index: {}, in '{}'",
+                                            self.dbh.get_errmsg(), sql))),
+      Err(msg) => Err(HatError::index(format!("Blob index: {} ({:?}), in '{}'",
+                                           self.dbh.get_errmsg(), msg, sql))),
     }
   }
 
-  fn prepare_or_die<'a>(&'a self, sql: &str) -> Cursor<'a> {
+  fn prepare<'a>(&'a self, sql: &str) -> HatResult<Cursor<'a>> {
     match self.dbh.prepare(sql, &None) {
-      Ok(s)  => s,
-      Err(x) => panic!("sqlite error: {:?} ({:?})",
-                       self.dbh.get_errmsg(), x),
+      Ok(s)  => Ok(s),
+      Err(x) => Err(HatError::index(format!("Blob index: {} ({:?})", self.dbh.get_errmsg(), x))),
     }
   }
 
-  fn select1<'a>(&'a mut self, sql: &str) -> Option<Cursor<'a>> {
-    let mut cursor = self.prepare_or_die(sql);
+  fn select1<'a>(&'a mut self, sql: &str) -> HatResult<Option<Cursor<'a>>> {
+    let mut cursor = try!(self.prepare(sql));
     if cursor.step() == SQLITE_ROW {
-      Some(cursor)
-    } else { None }
+      Ok(Some(cursor))
+    } else { Ok(None) }
   }
 
-  fn refresh_next_id(&mut self) {
-    let id = self.select1("SELECT MAX(id) FROM blob_index").unwrap().get_int(0);
+  fn refresh_next_id(&mut self) -> HatResult<()> {
+    let id = match try!(self.select1("SELECT MAX(id) FROM blob_index")) {
+      Some(cursor) => cursor.get_int(0),
+      None => 0,
+    };
     self.next_id = (id as i64) + 1;
+    Ok(())
   }
 
   fn next_id(&mut self) -> i64 {
@@ -139,28 +157,44 @@ impl BlobIndex {
     blob
   }
 
-  fn in_air(&mut self, blob: &BlobDesc) {
+  fn in_air(&mut self, blob: &BlobDesc) -> HatResult<()> {
     assert!(self.reserved.get(&blob.name).is_some(), "blob was not reserved!");
-    self.exec_or_die(&format!(
+    try!(self.exec(&format!(
       "INSERT INTO blob_index (id, name, tag) VALUES ({}, x'{}', {})",
-      blob.id, blob.name.to_hex(), 1));
-    self.new_transaction();
+      blob.id, blob.name.to_hex(), 1)));
+    self.new_transaction()
   }
 
-  fn new_transaction(&mut self) {
-    self.exec_or_die("COMMIT; BEGIN");
+  fn new_transaction(&mut self) -> HatResult<()> {
+    self.exec("COMMIT; BEGIN")
   }
 
-  fn commit_blob(&mut self, blob: &BlobDesc) {
+  fn commit_blob(&mut self, blob: &BlobDesc) -> HatResult<()> {
     assert!(self.reserved.get(&blob.name).is_some(), "blob was not reserved!");
-    self.exec_or_die(&format!("UPDATE blob_index SET tag=0 WHERE id={}", blob.id));
-    self.new_transaction();
+    try!(self.exec(&format!("UPDATE blob_index SET tag=0 WHERE id={}", blob.id)));
+    self.new_transaction()
+  }
+
+  fn list(&mut self) -> HatResult<Vec<BlobDesc>> {
+    let mut cursor = try!(self.prepare("SELECT id, name FROM blob_index WHERE tag=0"));
+    let mut out = Vec::new();
+    while cursor.step() == SQLITE_ROW {
+      out.push(BlobDesc{id: cursor.get_i64(0),
+                        name: cursor.get_blob(1).unwrap_or(&[]).to_vec()});
+    }
+    Ok(out)
+  }
+
+  fn delete(&mut self, blob: &BlobDesc) -> HatResult<()> {
+    try!(self.exec(&format!("DELETE FROM blob_index WHERE id={}", blob.id)));
+    self.new_transaction()
   }
 }
 
 impl Drop for BlobIndex {
   fn drop(&mut self) {
-    self.exec_or_die("COMMIT");
+    // Every change is committed as it is made, so there is nothing left to report here:
+    self.exec("COMMIT").ok();
   }
 }
 
@@ -171,13 +205,62 @@ impl MsgHandler<Msg, Reply> for BlobIndex {
         return reply(Reply::Reserved(self.reserve()));
       },
       Msg::InAir(blob) => {
-        self.in_air(&blob);
-        return reply(Reply::CommitOK);
+        match self.in_air(&blob) {
+          Ok(()) => return reply(Reply::CommitOK),
+          Err(e) => return reply(Reply::Error(e)),
+        }
       },
       Msg::CommitDone(blob) => {
-        self.commit_blob(&blob);
-        return reply(Reply::CommitOK);
-      }
+        match self.commit_blob(&blob) {
+          Ok(()) => return reply(Reply::CommitOK),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
+      Msg::List => {
+        match self.list() {
+          Ok(blobs) => return reply(Reply::Listing(blobs)),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
+      Msg::Delete(blob) => {
+        match self.delete(&blob) {
+          Ok(()) => return reply(Reply::CommitOK),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
     }
   }
 }
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use process::{Process};
+
+  #[test]
+  fn list_leaves_out_uncommitted_blobs() {
+    let bi_p: BlobIndexProcess = Process::new(Box::new(move|| { BlobIndex::new_for_testing() }));
+    let reserve = || match bi_p.send_reply(Msg::Reserve) {
+      Reply::Reserved(blob) => blob,
+      _ => panic!("Unexpected reply from blob index."),
+    };
+    let list = || match bi_p.send_reply(Msg::List) {
+      Reply::Listing(blobs) => blobs,
+      _ => panic!("Unexpected reply from blob index."),
+    };
+
+    let committed = reserve();
+    bi_p.send_reply(Msg::InAir(committed.clone()));
+    bi_p.send_reply(Msg::CommitDone(committed.clone()));
+    let in_air = reserve();
+    bi_p.send_reply(Msg::InAir(in_air.clone()));
+    reserve();
+
+    assert_eq!(list(), vec![committed.clone()]);
+
+    bi_p.send_reply(Msg::CommitDone(in_air.clone()));
+    assert_eq!(list(), vec![committed, in_air]);
+  }
+}
@@ -24,7 +24,9 @@ use rustc_serialize::hex::{ToHex};
 use std::collections::{BTreeMap};
 
 use std::fs;
+use std::io;
 use std::io::{Read, Write};
+use std::mem;
 use std::path::PathBuf;
 use std::str;
 
@@ -32,6 +34,7 @@ use process::{Process, MsgHandler};
 
 use blob_index;
 use blob_index::{BlobIndexProcess};
+use errors::{HatError, HatResult};
 
 #[cfg(test)]
 use blob_index::{BlobIndex};
@@ -40,15 +43,20 @@ use blob_index::{BlobIndex};
 pub type BlobStoreProcess = Process<Msg, Reply>;
 
 pub trait BlobStoreBackend {
-  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String>;
-  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String>;
+  fn store(&mut self, name: &[u8], data: &[u8]) -> HatResult<()>;
+
+  /// Read a blob. Fails with `HatError::MissingBlob` if it does not exist.
+  fn retrieve(&mut self, name: &[u8]) -> HatResult<Vec<u8>>;
+
+  /// Delete a blob. Deleting a blob that does not exist is not an error.
+  fn delete(&mut self, name: &[u8]) -> HatResult<()>;
 }
 
 
 #[derive(Clone)]
 pub struct FileBackend {
   root: PathBuf,
-  read_cache: Arc<Mutex<BTreeMap<Vec<u8>, Result<Vec<u8>, String>>>>,
+  read_cache: Arc<Mutex<BTreeMap<Vec<u8>, HatResult<Vec<u8>>>>>,
   max_cache_size: usize,
 }
 
@@ -58,11 +66,11 @@ impl FileBackend {
     FileBackend{root: root, read_cache: Arc::new(Mutex::new(BTreeMap::new())), max_cache_size: 10}
   }
 
-  fn guarded_cache_get(&self, name: &Vec<u8>) -> Option<Result<Vec<u8>, String>> {
+  fn guarded_cache_get(&self, name: &Vec<u8>) -> Option<HatResult<Vec<u8>>> {
     self.read_cache.lock().unwrap().get(name).map(|v| v.clone())
   }
 
-  fn guarded_cache_put(&mut self, name: Vec<u8>, result: Result<Vec<u8>, String>) {
+  fn guarded_cache_put(&mut self, name: Vec<u8>, result: HatResult<Vec<u8>>) {
     let mut cache = self.read_cache.lock().unwrap();
     if cache.len() >= self.max_cache_size {
       cache.clear();
@@ -73,22 +81,22 @@ impl FileBackend {
 
 impl BlobStoreBackend for FileBackend {
 
-  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+  fn store(&mut self, name: &[u8], data: &[u8]) -> HatResult<()> {
     let mut path = self.root.clone();
     path.push(&name.to_hex());
 
     let mut file = match fs::File::create(&path) {
-      Err(e) => return Err(e.to_string()),
+      Err(e) => return Err(HatError::Backend(e.to_string())),
       Ok(f) => f,
     };
 
-    match file.write_all(data) {
-      Err(e) => Err(e.to_string()),
+    match file.write_all(data).and_then(|()| file.sync_all()) {
+      Err(e) => Err(HatError::Backend(e.to_string())),
       Ok(()) => Ok(()),
     }
   }
 
-  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+  fn retrieve(&mut self, name: &[u8]) -> HatResult<Vec<u8>> {
     // Check for key in cache:
     let name = name.to_vec();
     let value_opt = self.guarded_cache_get(&name);
@@ -102,11 +110,11 @@ impl BlobStoreBackend for FileBackend {
                  p.push(&name.to_hex());
                  p };
 
-    let mut fd = fs::File::open(&path).unwrap();
     let mut buf = Vec::new();
-    let res = match fd.read_to_end(&mut buf) {
+    let res = match fs::File::open(&path).and_then(|mut fd| fd.read_to_end(&mut buf)) {
       Ok(_) => Ok(buf),
-      Err(e) => Err(e.to_string()),
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => Err(HatError::MissingBlob(name.clone())),
+      Err(e) => Err(HatError::Backend(e.to_string())),
     };
 
     // Update cache to contain key:
@@ -115,6 +123,18 @@ impl BlobStoreBackend for FileBackend {
     return res;
   }
 
+  fn delete(&mut self, name: &[u8]) -> HatResult<()> {
+    self.read_cache.lock().unwrap().remove(&name.to_vec());
+
+    let mut path = self.root.clone();
+    path.push(&name.to_hex());
+    match fs::remove_file(&path) {
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+      Err(e) => Err(HatError::Backend(e.to_string())),
+      Ok(()) => Ok(()),
+    }
+  }
+
 }
 
 
@@ -127,10 +147,33 @@ pub struct BlobID {
 
 impl BlobID {
 
+  pub fn new(name: Vec<u8>, begin: usize, end: usize) -> BlobID {
+    BlobID{name: name, begin: begin, end: end}
+  }
+
   pub fn from_bytes(bytes: Vec<u8>) -> BlobID {
     return rustc_serialize::json::decode(str::from_utf8(bytes.as_slice()).unwrap()).unwrap();
   }
 
+  /// Like `from_bytes`, but returns `None` if `bytes` is not a valid `BlobID`.
+  pub fn try_from_bytes(bytes: &[u8]) -> Option<BlobID> {
+    str::from_utf8(bytes).ok().and_then(|s| rustc_serialize::json::decode(s).ok())
+  }
+
+  /// Name of the blob containing the chunk.
+  pub fn name(&self) -> &[u8] {
+    &self.name[..]
+  }
+
+  /// Byte range of the chunk inside its blob.
+  pub fn begin(&self) -> usize { self.begin }
+  pub fn end(&self) -> usize { self.end }
+
+  /// Whether this is the reference given to empty chunks, which are not stored in any blob.
+  pub fn is_empty(&self) -> bool {
+    self.begin == 0 && self.end == 0
+  }
+
   pub fn as_bytes(&self) -> Vec<u8> {
     return rustc_serialize::json::encode(&self).unwrap().as_bytes().to_vec();
   }
@@ -143,9 +186,11 @@ pub enum Msg {
   /// containing the chunk has been committed to persistent storage (it is then safe to use the
   /// `BlobID` as persistent reference).
   Store(Vec<u8>, Thunk<'static, (BlobID,)>),
-  /// Retrieve the data chunk identified by `BlobID`.
+  /// Retrieve the data chunk identified by `BlobID`. Returns `RetrieveOK` or `Error`.
   Retrieve(BlobID),
-  /// Flush the current blob, independent of its size.
+  /// Flush the current blob, independent of its size. Returns `FlushOK` or `Error`; on error, the
+  /// chunks stay buffered and are stored by a later flush. Errors from flushes started by `Store`
+  /// (when the blob is full) are returned here too.
   Flush,
 }
 
@@ -155,6 +200,7 @@ pub enum Reply {
   StoreOK(BlobID),
   RetrieveOK(Vec<u8>),
   FlushOK,
+  Error(HatError),
 }
 
 
@@ -162,12 +208,19 @@ pub struct BlobStore<B> {
   backend: B,
 
   blob_index: BlobIndexProcess,
+  /// The blob being filled; empty until the first chunk after a flush reserves one.
   blob_desc: blob_index::BlobDesc,
 
   buffer_data: Vec<(BlobID, Vec<u8>, Thunk<'static, (BlobID,)>)>,
   buffer_data_len: usize,
 
+  /// Whether the current blob has been reported to the blob index as in air (by a failed flush).
+  blob_in_air: bool,
+
   max_blob_size: usize,
+
+  /// The first error from a flush that was not asked for; reported by the next `Flush`.
+  error: Option<HatError>,
 }
 
 
@@ -180,97 +233,95 @@ impl <B: BlobStoreBackend> BlobStore<B> {
 
   pub fn new(index: BlobIndexProcess, backend: B,
              max_blob_size: usize) -> BlobStore<B> {
-    let mut bs = BlobStore{
+    BlobStore{
       backend: backend,
       blob_index: index,
       blob_desc: empty_blob_desc(),
       buffer_data: Vec::new(),
       buffer_data_len: 0,
+      blob_in_air: false,
       max_blob_size: max_blob_size,
-    };
-    bs.reserve_new_blob();
-    bs
+      error: None,
+    }
   }
 
   #[cfg(test)]
   pub fn new_for_testing(backend: B, max_blob_size: usize) -> BlobStore<B> {
     let bi_p = Process::new(Box::new(move|| { BlobIndex::new_for_testing() }));
-    let mut bs = BlobStore{backend: backend,
-                           blob_index: bi_p,
-                           blob_desc: empty_blob_desc(),
-                           buffer_data: Vec::new(),
-                           buffer_data_len: 0,
-                           max_blob_size: max_blob_size,
-                          };
-    bs.reserve_new_blob();
-    bs
+    BlobStore{backend: backend,
+              blob_index: bi_p,
+              blob_desc: empty_blob_desc(),
+              buffer_data: Vec::new(),
+              buffer_data_len: 0,
+              blob_in_air: false,
+              max_blob_size: max_blob_size,
+              error: None,
+             }
   }
 
-  fn reserve_new_blob(&mut self) -> blob_index::BlobDesc {
-    let old_blob_desc = self.blob_desc.clone();
-
-    let res = self.blob_index.send_reply(blob_index::Msg::Reserve);
-    match res {
+  fn reserve_new_blob(&mut self) -> HatResult<()> {
+    match self.blob_index.send_reply(blob_index::Msg::Reserve) {
       blob_index::Reply::Reserved(blob_desc) => {
         self.blob_desc = blob_desc;
+        Ok(())
       },
-      _ => panic!("Could not reserve blob."),
+      blob_index::Reply::Error(e) => Err(e),
+      _ => Err(HatError::unexpected_reply("blob index")),
     }
-
-    old_blob_desc
   }
 
-  fn backend_store(&mut self, name: &[u8], blob: &[u8]) {
-    match self.backend.store(name, blob) {
-      Ok(()) => (),
-      Err(s) => panic!(s),
-    }
-  }
+  fn flush(&mut self) -> HatResult<()> {
+    if self.buffer_data_len == 0 { return Ok(()) }
 
-  fn backend_read(&mut self, name: &[u8]) -> Vec<u8> {
-    match self.backend.retrieve(name) {
-      Ok(data) => data,
-      Err(s) => panic!(s),
+    // Prepare blob
+    let mut blob = Vec::with_capacity(self.buffer_data_len);
+    for &(_, ref chunk, _) in self.buffer_data.iter() {
+      for c in chunk.iter() {
+        blob.push(*c);
+      }
     }
-  }
-
-  fn flush(&mut self) {
-    if self.buffer_data_len == 0 { return }
-
-    // Replace blob id
-    let old_blob_desc = self.reserve_new_blob();
-    self.buffer_data_len = 0;
 
-    // Prepare blob
-    let mut ready_callback = Vec::new();
-    let mut blob = Vec::new();
-
-    self.buffer_data.reverse();
-    loop {
-      match self.buffer_data.pop() {
-        Some((chunk_ref, chunk, cb)) => {
-          ready_callback.push((chunk_ref, cb));
-          for c in chunk {
-            blob.push(c);
-          }
-        },
-        None => break,
+    let blob_desc = self.blob_desc.clone();
+    if !self.blob_in_air {
+      match self.blob_index.send_reply(blob_index::Msg::InAir(blob_desc.clone())) {
+        blob_index::Reply::CommitOK => (),
+        blob_index::Reply::Error(e) => return Err(e),
+        _ => return Err(HatError::unexpected_reply("blob index")),
       }
+      self.blob_in_air = true;
+    }
+    // The chunks have already been given their `BlobID`s, so on failure we keep them buffered and
+    // store the same blob again on the next flush:
+    try!(self.backend.store(&blob_desc.name[..], &blob[..]));
+    match self.blob_index.send_reply(blob_index::Msg::CommitDone(blob_desc)) {
+      blob_index::Reply::CommitOK => (),
+      blob_index::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("blob index")),
     }
 
-    self.blob_index.send_reply(blob_index::Msg::InAir(old_blob_desc.clone()));
-    self.backend_store(old_blob_desc.name.as_slice(), blob.as_slice());
-    self.blob_index.send_reply(blob_index::Msg::CommitDone(old_blob_desc));
+    // The next chunk reserves a new blob:
+    self.blob_desc = empty_blob_desc();
+    self.blob_in_air = false;
+    self.buffer_data_len = 0;
 
     // Go through callbacks
-    for (blobid, callback) in ready_callback.into_iter() {
+    let buffer_data = mem::replace(&mut self.buffer_data, Vec::new());
+    for (blobid, _, callback) in buffer_data.into_iter() {
       callback(blobid);
     }
+
+    Ok(())
   }
 
   fn maybe_flush(&mut self) {
     if self.buffer_data_len >= self.max_blob_size {
-      self.flush();
+      // Nobody is waiting for this flush, so its error is kept for the next explicit flush (which
+      // also retries the store):
+      if let Err(e) = self.flush() {
+        if self.error.is_none() {
+          self.error = Some(e);
+        }
+      }
     }
   }
 }
@@ -287,6 +338,12 @@ impl <B: BlobStoreBackend> MsgHandler<Msg, Reply> for BlobStore<B> {
           return reply(Reply::StoreOK(id));
         }
 
+        if self.blob_desc.name.is_empty() {
+          if let Err(e) = self.reserve_new_blob() {
+            return reply(Reply::Error(e));
+          }
+        }
+
         let new_size = self.buffer_data_len + blob.len();
         let id = BlobID{name: self.blob_desc.name.clone(),
                         begin: self.buffer_data_len,
@@ -302,16 +359,28 @@ impl <B: BlobStoreBackend> MsgHandler<Msg, Reply> for BlobStore<B> {
         self.maybe_flush();
        },
       Msg::Retrieve(id) => {
-        if id.begin == 0 && id.end == 0 {
+        if id.is_empty() {
           return reply(Reply::RetrieveOK(vec![]));
         }
-        let blob = self.backend_read(id.name.as_slice());
+        let blob = match self.backend.retrieve(id.name.as_slice()) {
+          Ok(blob) => blob,
+          Err(e) => return reply(Reply::Error(e)),
+        };
+        if id.begin > id.end || blob.len() < id.end {
+          return reply(Reply::Error(HatError::CorruptData(format!(
+            "Blob {} is too short for chunk {}..{}", id.name.to_hex(), id.begin, id.end))));
+        }
         let chunk = &blob[id.begin .. id.end];
         return reply(Reply::RetrieveOK(chunk.to_vec()));
       },
       Msg::Flush => {
-        self.flush();
-        return reply(Reply::FlushOK)
+        if let Err(e) = self.flush() {
+          return reply(Reply::Error(e));
+        }
+        match self.error.take() {
+          None => return reply(Reply::FlushOK),
+          Some(e) => return reply(Reply::Error(e)),
+        }
       },
 
     }
@@ -324,9 +393,10 @@ impl <B: BlobStoreBackend> MsgHandler<Msg, Reply> for BlobStore<B> {
 pub mod tests {
   use super::*;
 
+  use errors::{HatError, HatResult};
   use process::{Process};
 
-  use std::sync::{Arc, Mutex};
+  use std::sync::{mpsc, Arc, Mutex};
   use std::collections::{BTreeMap};
 
   #[derive(Clone)]
@@ -339,45 +409,129 @@ pub mod tests {
       MemoryBackend{files: Arc::new(Mutex::new(BTreeMap::new()))}
     }
 
-    fn guarded_insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), String>{
+    fn guarded_insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> HatResult<()>{
       let mut guarded_files = self.files.lock().unwrap();
       if guarded_files.contains_key(&key) {
-        return Err(format!("Key already exists: '{:?}'", key));
+        return Err(HatError::Backend(format!("Key already exists: '{:?}'", key)));
       }
       guarded_files.insert(key, value);
       Ok(())
     }
 
-    fn guarded_retrieve(&mut self, key: &[u8]) -> Result<Vec<u8>, String> {
+    fn guarded_retrieve(&mut self, key: &[u8]) -> HatResult<Vec<u8>> {
       let value_opt = self.files.lock().unwrap().get(&key.to_vec()).map(|v| v.clone());
-      value_opt.map(|v| Ok(v)).unwrap_or_else(|| Err(format!("Unknown key: '{:?}'", key)))
+      value_opt.map(|v| Ok(v)).unwrap_or_else(|| Err(HatError::MissingBlob(key.to_vec())))
     }
   }
 
   impl BlobStoreBackend for MemoryBackend {
 
-    fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    fn store(&mut self, name: &[u8], data: &[u8]) -> HatResult<()> {
       self.guarded_insert(name.to_vec(), data.to_vec())
     }
 
-    fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    fn retrieve(&mut self, name: &[u8]) -> HatResult<Vec<u8>> {
       self.guarded_retrieve(name)
     }
+
+    fn delete(&mut self, name: &[u8]) -> HatResult<()> {
+      self.files.lock().unwrap().remove(&name.to_vec());
+      Ok(())
+    }
   }
 
   #[derive(Clone)]
   pub struct DevNullBackend;
 
   impl BlobStoreBackend for DevNullBackend {
-    fn store(&mut self, _name: &[u8], _data: &[u8]) -> Result<(), String> {
+    fn store(&mut self, _name: &[u8], _data: &[u8]) -> HatResult<()> {
       Ok(())
     }
-    fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
-      Err(format!("Unknown key: '{:?}'", name))
+    fn retrieve(&mut self, name: &[u8]) -> HatResult<Vec<u8>> {
+      Err(HatError::MissingBlob(name.to_vec()))
+    }
+    fn delete(&mut self, _name: &[u8]) -> HatResult<()> {
+      Ok(())
     }
   }
 
 
+  /// Fails to store anything while `full` is set.
+  #[derive(Clone)]
+  pub struct FullBackend {
+    full: Arc<Mutex<bool>>,
+    backend: MemoryBackend,
+  }
+
+  impl BlobStoreBackend for FullBackend {
+    fn store(&mut self, name: &[u8], data: &[u8]) -> HatResult<()> {
+      if *self.full.lock().unwrap() {
+        return Err(HatError::Backend("No space left on device".to_string()));
+      }
+      self.backend.store(name, data)
+    }
+    fn retrieve(&mut self, name: &[u8]) -> HatResult<Vec<u8>> {
+      self.backend.retrieve(name)
+    }
+    fn delete(&mut self, name: &[u8]) -> HatResult<()> {
+      self.backend.delete(name)
+    }
+  }
+
+
+  #[test]
+  fn failed_flush_is_retried() {
+    let backend = FullBackend{full: Arc::new(Mutex::new(true)), backend: MemoryBackend::new()};
+    let local_backend = backend.clone();
+    let bs_p: BlobStoreProcess = Process::new(Box::new(move|| {
+      BlobStore::new_for_testing(local_backend, 1024) }));
+
+    let (tx, rx) = mpsc::channel();
+    let id = match bs_p.send_reply(Msg::Store(b"chunk".to_vec(),
+                                              Box::new(move|id| { tx.send(id).unwrap(); }))) {
+      Reply::StoreOK(id) => id,
+      _ => panic!("Unexpected reply from blob store."),
+    };
+
+    match bs_p.send_reply(Msg::Flush) {
+      Reply::Error(HatError::Backend(_)) => (),
+      r => panic!("Unexpected reply from blob store: {:?}", r),
+    }
+    assert!(rx.try_recv().is_err());
+    match bs_p.send_reply(Msg::Retrieve(id.clone())) {
+      Reply::Error(HatError::MissingBlob(_)) => (),
+      r => panic!("Unexpected reply from blob store: {:?}", r),
+    }
+
+    *backend.full.lock().unwrap() = false;
+    assert_eq!(bs_p.send_reply(Msg::Flush), Reply::FlushOK);
+    assert_eq!(rx.try_recv().unwrap(), id);
+    assert_eq!(bs_p.send_reply(Msg::Retrieve(id)), Reply::RetrieveOK(b"chunk".to_vec()));
+  }
+
+  #[test]
+  fn failed_implicit_flush_is_reported() {
+    let backend = FullBackend{full: Arc::new(Mutex::new(true)), backend: MemoryBackend::new()};
+    let local_backend = backend.clone();
+    let bs_p: BlobStoreProcess = Process::new(Box::new(move|| {
+      BlobStore::new_for_testing(local_backend, 1024) }));
+
+    // A chunk that fills the blob is flushed right away, which fails:
+    let id = match bs_p.send_reply(Msg::Store(vec![1u8; 2048], Box::new(move|_| {}))) {
+      Reply::StoreOK(id) => id,
+      _ => panic!("Unexpected reply from blob store."),
+    };
+
+    // The next flush stores the blob, but still reports the earlier failure:
+    *backend.full.lock().unwrap() = false;
+    match bs_p.send_reply(Msg::Flush) {
+      Reply::Error(HatError::Backend(_)) => (),
+      r => panic!("Unexpected reply from blob store: {:?}", r),
+    }
+    assert_eq!(bs_p.send_reply(Msg::Flush), Reply::FlushOK);
+    assert_eq!(bs_p.send_reply(Msg::Retrieve(id)), Reply::RetrieveOK(vec![1u8; 2048]));
+  }
+
   #[quickcheck]
   fn identity(chunks: Vec<Vec<u8>>) -> bool {
     let mut backend = MemoryBackend::new();
@@ -401,7 +555,7 @@ pub mod tests {
       if chunk.len() > 0 {
         match backend.retrieve(id.name.as_slice()) {
           Ok(_) => (),
-          Err(e) => panic!(e),
+          Err(e) => panic!("{}", e),
         }
       }
     }
@@ -442,7 +596,7 @@ pub mod tests {
       if chunk.len() > 0 {
         match backend.retrieve(id.name.as_slice()) {
           Ok(_) => (),
-          Err(e) => panic!(e),
+          Err(e) => panic!("{}", e),
         }
       }
     }
@@ -0,0 +1,341 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Chunking of data streams.
+//!
+//! The key store cuts the data of every entry into chunks with a `Chunker`, and chunks are the
+//! unit of deduplication. `FixedSize` cuts blocks of equal size. `ContentDefined` places
+//! boundaries where a rolling hash of the last bytes matches a mask (FastCDC with a gear hash), so
+//! inserting or removing bytes only changes the chunks around the edit, and the rest of the data
+//! still deduplicates against earlier snapshots.
+
+use std::io;
+use std::io::{Read};
+use std::mem;
+
+
+/// Cuts a stream of bytes into the chunks stored by the key store.
+///
+/// Chunkers must be deterministic: the same data must always give the same chunks, or it will not
+/// deduplicate against earlier snapshots. A read error is the last item of the chunks, so that
+/// the data is never mistaken for complete.
+pub trait Chunker: Send {
+  fn chunks(&self, source: Box<Read + Send>) -> Box<Iterator<Item=io::Result<Vec<u8>>> + Send>;
+
+  /// Name and parameters of the chunker (e.g. `fixed:65536`). Chunkers with the same description
+  /// must cut the same data into the same chunks.
+  fn describe(&self) -> String;
+}
+
+
+/// Reads `source` in blocks of `size` bytes; only the last block can be shorter. A read error
+/// ends the blocks, and the partial block before it is dropped.
+pub struct Blocks {
+  source: Box<Read + Send>,
+  size: usize,
+  failed: bool,
+}
+
+impl Blocks {
+  pub fn new(source: Box<Read + Send>, size: usize) -> Blocks {
+    Blocks{source: source, size: size, failed: false}
+  }
+}
+
+impl Iterator for Blocks {
+  type Item = io::Result<Vec<u8>>;
+
+  fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+    if self.failed {
+      return None;
+    }
+    let mut buf = vec![0u8; self.size];
+    let mut len = 0;
+    while len < self.size {
+      match self.source.read(&mut buf[len..]) {
+        Ok(0) => break,
+        Ok(n) => len += n,
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+        Err(e) => {
+          self.failed = true;
+          return Some(Err(e));
+        },
+      }
+    }
+    if len == 0 {
+      return None;
+    }
+    buf.truncate(len);
+    Some(Ok(buf))
+  }
+}
+
+
+/// Chunks of `size` bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedSize {
+  size: usize,
+}
+
+impl FixedSize {
+  pub fn new(size: usize) -> FixedSize {
+    assert!(size > 0);
+    FixedSize{size: size}
+  }
+}
+
+impl Chunker for FixedSize {
+  fn chunks(&self, source: Box<Read + Send>) -> Box<Iterator<Item=io::Result<Vec<u8>>> + Send> {
+    Box::new(Blocks::new(source, self.size))
+  }
+
+  fn describe(&self) -> String {
+    format!("fixed:{}", self.size)
+  }
+}
+
+
+/// Parameters of a content-defined chunker.
+#[derive(Clone)]
+pub struct ContentDefined {
+  min_size: usize,
+  avg_size: usize,
+  max_size: usize,
+
+  /// Stricter mask used before `avg_size` and looser mask used after it, which keeps chunk sizes
+  /// close to the average ("normalized chunking").
+  mask_small: u64,
+  mask_large: u64,
+
+  gear: Vec<u64>,
+}
+
+/// Random values for each byte, mixed into the rolling hash.
+///
+/// The table must never change, as that would move all chunk boundaries and defeat deduplication
+/// against existing repositories.
+fn gear_table() -> Vec<u64> {
+  // SplitMix64 with a fixed seed:
+  let mut state = 0x6861742d62616b75u64;
+  (0..256).map(|_| {
+    state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+  }).collect()
+}
+
+/// A mask of the `bits` most significant bits. The gear hash is shifted left for every byte, so
+/// its high bits depend on the most bytes.
+fn high_bits(bits: u32) -> u64 {
+  !0u64 << (64 - bits)
+}
+
+impl ContentDefined {
+  /// A chunker for chunks of `avg_size` bytes on average, and between a quarter and four times
+  /// that. `avg_size` must be at least 64.
+  pub fn new(avg_size: usize) -> ContentDefined {
+    assert!(avg_size >= 64);
+    let bits = 63 - (avg_size as u64).leading_zeros();
+    ContentDefined{min_size: avg_size / 4,
+                   avg_size: avg_size,
+                   max_size: avg_size * 4,
+                   mask_small: high_bits(bits + 1),
+                   mask_large: high_bits(bits - 1),
+                   gear: gear_table()}
+  }
+
+  /// Length of the first chunk of `data`. Unless `data` is the end of the stream, it must hold at
+  /// least `max_size` bytes.
+  pub fn cut(&self, data: &[u8]) -> usize {
+    if data.len() <= self.min_size {
+      return data.len();
+    }
+    let end = if data.len() < self.max_size { data.len() } else { self.max_size };
+    let normal = if end < self.avg_size { end } else { self.avg_size };
+
+    // Chunks are never cut before `min_size`, so there is no need to hash those bytes:
+    let mut hash = 0u64;
+    let mut i = self.min_size;
+    while i < normal {
+      hash = (hash << 1).wrapping_add(self.gear[data[i] as usize]);
+      if hash & self.mask_small == 0 {
+        return i + 1;
+      }
+      i += 1;
+    }
+    while i < end {
+      hash = (hash << 1).wrapping_add(self.gear[data[i] as usize]);
+      if hash & self.mask_large == 0 {
+        return i + 1;
+      }
+      i += 1;
+    }
+    end
+  }
+
+  /// Re-cut the data of `source` into content-defined chunks. The boundaries of the chunks
+  /// produced by `source` do not matter; an error from it ends the chunks.
+  pub fn recut<I: Iterator<Item=io::Result<Vec<u8>>>>(self, source: I) -> ContentDefinedChunks<I> {
+    ContentDefinedChunks{chunker: self, source: source, buf: Vec::new(), eof: false}
+  }
+}
+
+impl Chunker for ContentDefined {
+  fn chunks(&self, source: Box<Read + Send>) -> Box<Iterator<Item=io::Result<Vec<u8>>> + Send> {
+    let blocks = Blocks::new(source, self.max_size);
+    Box::new(self.clone().recut(blocks))
+  }
+
+  fn describe(&self) -> String {
+    format!("content-defined:{}", self.avg_size)
+  }
+}
+
+
+pub struct ContentDefinedChunks<I> {
+  chunker: ContentDefined,
+  source: I,
+  buf: Vec<u8>,
+  eof: bool,
+}
+
+impl <I: Iterator<Item=io::Result<Vec<u8>>>> Iterator for ContentDefinedChunks<I> {
+  type Item = io::Result<Vec<u8>>;
+
+  fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+    while !self.eof && self.buf.len() < self.chunker.max_size {
+      match self.source.next() {
+        Some(Ok(data)) => self.buf.extend(data.into_iter()),
+        Some(Err(e)) => {
+          self.eof = true;
+          self.buf.clear();
+          return Some(Err(e));
+        },
+        None => self.eof = true,
+      }
+    }
+    if self.buf.len() == 0 {
+      return None;
+    }
+
+    let len = self.chunker.cut(&self.buf[..]);
+    let rest = self.buf[len..].to_vec();
+    self.buf.truncate(len);
+    Some(Ok(mem::replace(&mut self.buf, rest)))
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use rand::{Rng, SeedableRng, XorShiftRng};
+  use std::fs;
+  use std::io;
+  use std::io::{Read};
+
+  fn random_bytes(len: usize) -> Vec<u8> {
+    let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+    (0..len).map(|_| rng.gen()).collect()
+  }
+
+  fn chunk(data: &Vec<u8>, source_size: usize) -> Vec<Vec<u8>> {
+    let source: Vec<io::Result<Vec<u8>>> =
+      data.chunks(source_size).map(|c| Ok(c.to_vec())).collect();
+    ContentDefined::new(4096).recut(source.into_iter()).collect::<io::Result<_>>().unwrap()
+  }
+
+  fn read_chunks<C: Chunker>(chunker: C, data: &Vec<u8>) -> Vec<Vec<u8>> {
+    chunker.chunks(Box::new(io::Cursor::new(data.clone()))).collect::<io::Result<_>>().unwrap()
+  }
+
+  /// Gives `data`, and then fails (directories cannot be read as files).
+  fn failing_reader(data: &Vec<u8>) -> Box<Read + Send> {
+    Box::new(io::Cursor::new(data.clone()).chain(fs::File::open("/").unwrap()))
+  }
+
+  #[test]
+  fn fixed_size() {
+    let data = random_bytes(10000);
+    let chunks = read_chunks(FixedSize::new(4096), &data);
+    assert_eq!(chunks, vec![data[..4096].to_vec(), data[4096..8192].to_vec(),
+                            data[8192..].to_vec()]);
+    assert_eq!(read_chunks(FixedSize::new(4096), &vec![]), Vec::<Vec<u8>>::new());
+  }
+
+  #[test]
+  fn content_defined_reader() {
+    let data = random_bytes(200000);
+    assert_eq!(read_chunks(ContentDefined::new(4096), &data), chunk(&data, 1000));
+  }
+
+  #[test]
+  fn chunks_cover_data() {
+    let data = random_bytes(1000000);
+    let chunks = chunk(&data, 128 * 1024);
+
+    let mut joined = Vec::new();
+    for c in chunks.iter() {
+      joined.extend(c.iter().cloned());
+    }
+    assert_eq!(joined, data);
+
+    // All chunks but the last are within bounds:
+    for c in chunks[..chunks.len() - 1].iter() {
+      assert!(c.len() >= 1024 && c.len() <= 4 * 4096);
+    }
+  }
+
+  #[test]
+  fn boundaries_depend_on_content_only() {
+    let data = random_bytes(200000);
+    assert_eq!(chunk(&data, 1000), chunk(&data, 128 * 1024));
+  }
+
+  #[test]
+  fn insertion_changes_few_chunks() {
+    let data = random_bytes(1000000);
+    let mut edited = data[..100].to_vec();
+    edited.push(42);
+    edited.extend(data[100..].iter().cloned());
+
+    let old = chunk(&data, 128 * 1024);
+    let new = chunk(&edited, 128 * 1024);
+    let shared = new.iter().filter(|c| old.contains(c)).count();
+    assert!(shared + 2 >= old.len());
+  }
+
+  #[test]
+  fn read_errors_end_the_chunks() {
+    let data = random_bytes(10000);
+    let chunks: Vec<io::Result<Vec<u8>>> = FixedSize::new(4096).chunks(failing_reader(&data))
+      .collect();
+    assert_eq!(chunks.len(), 3);
+    assert_eq!(chunks[0].as_ref().ok(), Some(&data[..4096].to_vec()));
+    assert_eq!(chunks[1].as_ref().ok(), Some(&data[4096..8192].to_vec()));
+    assert!(chunks[2].is_err());
+
+    let chunks: Vec<io::Result<Vec<u8>>> = ContentDefined::new(4096).chunks(failing_reader(&data))
+      .collect();
+    assert!(chunks.last().unwrap().is_err());
+  }
+
+  #[test]
+  fn empty_source() {
+    assert_eq!(chunk(&vec![], 10), Vec::<Vec<u8>>::new());
+  }
+}
@@ -0,0 +1,164 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-repository configuration, stored as JSON in the repository root.
+
+use rustc_serialize::json;
+
+use std::default::{Default};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+
+static CONFIG_FILENAME: &'static str = "config.json";
+
+
+/// How file data is cut into chunks (which are the unit of deduplication).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum Chunking {
+  /// Blocks of `chunk_size` bytes. An insertion moves all following block boundaries.
+  Fixed,
+
+  /// Boundaries chosen by the data itself (see `chunker`), with chunks of `chunk_size` bytes on
+  /// average. Insertions and deletions only change the chunks around them.
+  ContentDefined,
+}
+
+impl Chunking {
+  pub fn from_name(name: &str) -> Option<Chunking> {
+    match name {
+      "fixed" => Some(Chunking::Fixed),
+      "content-defined" => Some(Chunking::ContentDefined),
+      _ => None,
+    }
+  }
+}
+
+
+/// Settings for a single repository.
+///
+/// The configuration is written once when the repository is created and read back every time the
+/// repository is opened, so that all processes of a repository agree on its tunables.
+#[derive(Clone, Debug, Eq, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct RepositoryConfig {
+  /// Directory used as root by the file backend. Relative paths are relative to the repository.
+  pub blob_dir: String,
+
+  /// Size at which the blob store sends its current blob to the backend.
+  pub max_blob_size: usize,
+
+  /// Number of threads used for traversing directories while taking a snapshot.
+  pub traversal_threads: usize,
+
+  /// Size of the blocks that files are read in (and thus the size of their data chunks, or their
+  /// average size with content-defined chunking).
+  pub chunk_size: usize,
+
+  /// How file data is cut into chunks. Repositories created before this could be chosen have
+  /// none, and use fixed-size chunks.
+  pub chunking: Option<Chunking>,
+
+  /// Node order of the hash trees used for file data and directory listings.
+  pub hash_tree_order: usize,
+}
+
+impl Default for RepositoryConfig {
+  fn default() -> RepositoryConfig {
+    RepositoryConfig{
+      blob_dir: "blobs".to_string(),
+      max_blob_size: 4 * 1024 * 1024,
+      traversal_threads: 10,
+      chunk_size: 128 * 1024,
+      chunking: Some(Chunking::Fixed),
+      hash_tree_order: 8,
+    }
+  }
+}
+
+impl RepositoryConfig {
+
+  /// Location of the configuration file in the repository at `root`.
+  pub fn path(root: &PathBuf) -> PathBuf {
+    let mut path = root.clone();
+    path.push(CONFIG_FILENAME);
+    path
+  }
+
+  /// Location of the blob backend root for the repository at `root`.
+  pub fn blob_path(&self, root: &PathBuf) -> PathBuf {
+    let mut path = root.clone();
+    path.push(&self.blob_dir);
+    path
+  }
+
+  pub fn chunking(&self) -> Chunking {
+    self.chunking.unwrap_or(Chunking::Fixed)
+  }
+
+  /// Check that the settings can be used, describing the first one that can not. Configurations
+  /// are checked both when they are written and when they are loaded.
+  pub fn validate(&self) -> Result<(), String> {
+    if self.max_blob_size == 0 {
+      return Err("max_blob_size must be positive".to_string());
+    }
+    if self.traversal_threads == 0 {
+      return Err("traversal_threads must be positive".to_string());
+    }
+    if self.chunk_size == 0 {
+      return Err("chunk_size must be positive".to_string());
+    }
+    if self.chunking() == Chunking::ContentDefined && self.chunk_size < 64 {
+      return Err("chunk_size must be at least 64 with content-defined chunking".to_string());
+    }
+    if self.hash_tree_order < 2 {
+      return Err("hash_tree_order must be at least 2".to_string());
+    }
+    Ok(())
+  }
+
+  /// Read the configuration of the repository at `root`.
+  pub fn load(root: &PathBuf) -> Result<RepositoryConfig, String> {
+    let path = RepositoryConfig::path(root);
+    let mut fd = match fs::File::open(&path) {
+      Ok(fd) => fd,
+      Err(e) => return Err(format!("Could not open '{}': {}", path.display(), e)),
+    };
+    let mut text = String::new();
+    if let Err(e) = fd.read_to_string(&mut text) {
+      return Err(format!("Could not read '{}': {}", path.display(), e));
+    }
+    let config: RepositoryConfig = match json::decode(&text) {
+      Ok(c) => c,
+      Err(e) => return Err(format!("Invalid configuration in '{}': {:?}", path.display(), e)),
+    };
+    try!(config.validate());
+    Ok(config)
+  }
+
+  /// Write this configuration to the repository at `root`.
+  pub fn write(&self, root: &PathBuf) -> Result<(), String> {
+    try!(self.validate());
+    let path = RepositoryConfig::path(root);
+    let text = json::as_pretty_json(self).to_string();
+    let mut fd = match fs::File::create(&path) {
+      Ok(fd) => fd,
+      Err(e) => return Err(format!("Could not create '{}': {}", path.display(), e)),
+    };
+    match fd.write_all(text.as_bytes()) {
+      Ok(()) => Ok(()),
+      Err(e) => Err(format!("Could not write '{}': {}", path.display(), e)),
+    }
+  }
+}
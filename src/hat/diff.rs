@@ -0,0 +1,144 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Comparison of committed directory trees.
+//!
+//! Two snapshots are compared one directory at a time, starting from their roots. A directory is
+//! only descended into when its hash differs between the two snapshots, so unchanged subtrees are
+//! skipped without reading their listings.
+
+use std::collections::{BTreeMap};
+use std::path::PathBuf;
+
+use tree_entry::{TreeEntry};
+
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Change {
+  Added,
+  Removed,
+
+  /// The content (or the kind) of the entry differs.
+  Modified,
+
+  /// The content is the same, but the metadata differs.
+  MetadataChanged,
+}
+
+/// A single difference between two snapshots.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Difference {
+  pub path: PathBuf,
+  pub change: Change,
+}
+
+
+/// Result of comparing two listings of the same directory.
+pub struct ListingDiff {
+  /// Names of the entries that differ, in name order.
+  pub changes: Vec<(Vec<u8>, Change)>,
+
+  /// Sub-directories that exist in both listings with different content, as (old, new) pairs.
+  /// Their own metadata changes are reported in `changes`.
+  pub subdirs: Vec<(TreeEntry, TreeEntry)>,
+}
+
+
+/// Compare the `old` and `new` listings of a directory.
+pub fn compare_listings(old: Vec<TreeEntry>, new: Vec<TreeEntry>) -> ListingDiff {
+  let mut old_by_name = BTreeMap::new();
+  for entry in old.into_iter() {
+    old_by_name.insert(entry.name.clone(), entry);
+  }
+
+  let mut changes = BTreeMap::new();
+  let mut subdirs = Vec::new();
+
+  for new_entry in new.into_iter() {
+    match old_by_name.remove(&new_entry.name) {
+      None => { changes.insert(new_entry.name.clone(), Change::Added); },
+      Some(old_entry) => {
+        if old_entry.kind != new_entry.kind {
+          changes.insert(new_entry.name.clone(), Change::Modified);
+        } else if old_entry.is_directory() {
+          if !old_entry.same_metadata(&new_entry) {
+            changes.insert(new_entry.name.clone(), Change::MetadataChanged);
+          }
+          if old_entry.hash != new_entry.hash {
+            subdirs.push((old_entry, new_entry));
+          }
+        } else if old_entry.hash != new_entry.hash ||
+                  old_entry.link_target != new_entry.link_target ||
+                  old_entry.device != new_entry.device {
+          changes.insert(new_entry.name.clone(), Change::Modified);
+        } else if !old_entry.same_metadata(&new_entry) {
+          changes.insert(new_entry.name.clone(), Change::MetadataChanged);
+        }
+      },
+    }
+  }
+
+  for (name, _) in old_by_name.into_iter() {
+    changes.insert(name, Change::Removed);
+  }
+
+  ListingDiff{changes: changes.into_iter().collect(), subdirs: subdirs}
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use hash_index::{Hash};
+  use std::collections::{BTreeMap};
+  use tree_entry::{TreeEntry, EntryKind};
+
+  fn file(name: &str, content: &str, modified: u64) -> TreeEntry {
+    TreeEntry{id: 0, name: name.as_bytes().to_vec(), kind: EntryKind::File,
+              size: Some(content.len() as u64), created: 0, modified: modified, accessed: 0,
+              permissions: None, user_id: None, group_id: None, link_target: None,
+              hard_link: None, xattrs: BTreeMap::new(), device: None,
+              hash: Hash::new(content.as_bytes()), persistent_ref: vec![]}
+  }
+
+  fn dir(name: &str, content: &str) -> TreeEntry {
+    TreeEntry{kind: EntryKind::Directory, size: None, ..file(name, content, 0)}
+  }
+
+  #[test]
+  fn compare() {
+    let old = vec![file("same", "a", 1), file("changed", "b", 1), file("touched", "c", 1),
+                   file("removed", "d", 1), dir("dir_same", "e"), dir("dir_changed", "f"),
+                   dir("kind", "g"), dir("dir_chmod", "i"), dir("dir_both", "j")];
+    let new = vec![file("same", "a", 1), file("changed", "B", 2), file("touched", "c", 2),
+                   file("added", "h", 1), dir("dir_same", "e"), dir("dir_changed", "F"),
+                   file("kind", "g", 0),
+                   TreeEntry{permissions: Some(0o40700), ..dir("dir_chmod", "i")},
+                   TreeEntry{modified: 2, ..dir("dir_both", "J")}];
+
+    let diff = compare_listings(old, new);
+    assert_eq!(diff.changes,
+               vec![(b"added".to_vec(), Change::Added),
+                    (b"changed".to_vec(), Change::Modified),
+                    (b"dir_both".to_vec(), Change::MetadataChanged),
+                    (b"dir_chmod".to_vec(), Change::MetadataChanged),
+                    (b"kind".to_vec(), Change::Modified),
+                    (b"removed".to_vec(), Change::Removed),
+                    (b"touched".to_vec(), Change::MetadataChanged)]);
+    assert_eq!(diff.subdirs, vec![(dir("dir_changed", "f"), dir("dir_changed", "F")),
+                                  (dir("dir_both", "j"),
+                                   TreeEntry{modified: 2, ..dir("dir_both", "J")})]);
+  }
+}
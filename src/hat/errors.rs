@@ -0,0 +1,94 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Errors reported by the storage pipeline and the high level Hat API.
+
+use rustc_serialize::hex::{ToHex};
+use std::error;
+use std::fmt;
+use std::io;
+
+use hash_index::{Hash};
+
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HatError {
+  /// A local file or index could not be read or written. The kind tells e.g. a missing file from
+  /// one we may not access; index (SQLite) failures are of kind `Other`.
+  Io(io::ErrorKind, String),
+
+  /// The blob backend failed to store, read or delete a blob (e.g. because it is full or
+  /// unreachable).
+  Backend(String),
+
+  /// The blob with this name does not exist in the backend.
+  MissingBlob(Vec<u8>),
+
+  /// The hash is not known to the hash index, or its data has not been committed yet.
+  MissingHash(Hash),
+
+  /// Stored data could not be decoded, or does not match what refers to it.
+  CorruptData(String),
+
+  /// The repository is in a state that does not allow the operation.
+  Repository(String),
+}
+
+pub type HatResult<T> = Result<T, HatError>;
+
+
+impl HatError {
+  /// A local index (SQLite) failure; these have no `io::ErrorKind` of their own.
+  pub fn index(message: String) -> HatError {
+    HatError::Io(io::ErrorKind::Other, message)
+  }
+
+  /// A process answered with a reply that does not fit the message it was sent.
+  pub fn unexpected_reply(process: &str) -> HatError {
+    HatError::Backend(format!("Unexpected reply from {}", process))
+  }
+}
+
+
+impl fmt::Display for HatError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      HatError::Io(_, ref e) => write!(f, "I/O error: {}", e),
+      HatError::Backend(ref e) => write!(f, "Backend error: {}", e),
+      HatError::MissingBlob(ref name) => write!(f, "Missing blob {}", name.to_hex()),
+      HatError::MissingHash(ref hash) => write!(f, "Missing hash {}", hash.bytes.to_hex()),
+      HatError::CorruptData(ref e) => write!(f, "Corrupt data: {}", e),
+      HatError::Repository(ref e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl error::Error for HatError {
+  fn description(&self) -> &str {
+    match *self {
+      HatError::Io(..) => "I/O error",
+      HatError::Backend(_) => "backend error",
+      HatError::MissingBlob(_) => "missing blob",
+      HatError::MissingHash(_) => "missing hash",
+      HatError::CorruptData(_) => "corrupt data",
+      HatError::Repository(_) => "repository error",
+    }
+  }
+}
+
+impl From<io::Error> for HatError {
+  fn from(e: io::Error) -> HatError {
+    HatError::Io(e.kind(), e.to_string())
+  }
+}
@@ -0,0 +1,315 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Mark-and-sweep garbage collection of chunks and blobs.
+//!
+//! The mark phase collects every hash reachable from the retained snapshots (and from the key
+//! indexes of snapshots in progress). Internal hash tree nodes are followed through the child
+//! hashes stored as their payload in the hash index, so only directory listings have to be read
+//! from the blobs.
+//!
+//! The sweep removes all other hashes from the hash index and then deletes the blobs that no longer
+//! contain any live chunk. Blobs with both live and dead chunks are kept as they are.
+
+use rustc_serialize::json;
+use std::collections::{HashSet};
+use std::str;
+
+use blob_index::{BlobDesc};
+use blob_store::{BlobID};
+use errors::{HatError, HatResult};
+use hash_index;
+use hash_index::{Hash, HashIndexProcess, HASH_LENGTH};
+use hash_tree::{HashTreeBackend};
+use key_store::{HashStoreBackend};
+use tree_entry::{TreeEntry};
+
+
+/// Collects the set of live hashes.
+pub struct Marker {
+  hash_index: HashIndexProcess,
+  backend: HashStoreBackend,
+  live: HashSet<Vec<u8>>,
+  marked_dirs: HashSet<Vec<u8>>,
+}
+
+impl Marker {
+
+  pub fn new(hash_index: HashIndexProcess, backend: HashStoreBackend) -> Marker {
+    Marker{hash_index: hash_index, backend: backend,
+           live: HashSet::new(), marked_dirs: HashSet::new()}
+  }
+
+  pub fn live_hashes(self) -> HashSet<Vec<u8>> {
+    self.live
+  }
+
+  /// Mark the directory tree with top hash `dir_hash` and everything it refers to.
+  pub fn mark_dir(&mut self, dir_hash: Hash) -> HatResult<()> {
+    if !self.marked_dirs.insert(dir_hash.bytes.clone()) {
+      return Ok(());
+    }
+
+    let mut leaves = Vec::new();
+    try!(self.mark_tree_collecting(dir_hash, Some(&mut leaves)));
+
+    for leaf in leaves.into_iter() {
+      let chunk = try!(self.backend.fetch_chunk(leaf.clone()));
+      if chunk.len() == 0 {
+        continue;
+      }
+      let listing_opt = str::from_utf8(&chunk[..]).ok()
+        .and_then(|s| json::Json::from_str(s).ok());
+      let listing = match listing_opt {
+        Some(j) => j,
+        None => return Err(HatError::CorruptData(
+          format!("Could not decode directory listing {:?}", leaf))),
+      };
+      for entry in try!(TreeEntry::list_from_json(&listing)).into_iter() {
+        if entry.is_directory() {
+          try!(self.mark_dir(entry.hash));
+        } else if entry.hash.bytes.len() > 0 {
+          try!(self.mark_tree(entry.hash));
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Mark all nodes of the hash tree with top hash `hash`.
+  pub fn mark_tree(&mut self, hash: Hash) -> HatResult<()> {
+    self.mark_tree_collecting(hash, None)
+  }
+
+  fn mark_tree_collecting(&mut self, hash: Hash, mut leaves: Option<&mut Vec<Hash>>)
+                          -> HatResult<()> {
+    if !self.live.insert(hash.bytes.clone()) && leaves.is_none() {
+      return Ok(());
+    }
+
+    let payload_opt = match self.hash_index.send_reply(
+      hash_index::Msg::FetchPayload(hash.clone())) {
+      hash_index::Reply::Payload(p) => p,
+      hash_index::Reply::HashNotKnown => return Err(HatError::MissingHash(hash)),
+      hash_index::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("hash index")),
+    };
+
+    match payload_opt {
+      // Internal nodes have their child hashes as payload:
+      Some(payload) => {
+        for child in payload.chunks(HASH_LENGTH) {
+          let child_leaves = match leaves {
+            Some(ref mut l) => Some(&mut **l),
+            None => None,
+          };
+          try!(self.mark_tree_collecting(Hash{bytes: child.to_vec()}, child_leaves));
+        }
+      },
+      None => {
+        if let Some(l) = leaves {
+          l.push(hash);
+        }
+      },
+    }
+    Ok(())
+  }
+}
+
+
+/// What a sweep removes (or would remove).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Plan {
+  pub live_hashes: usize,
+  pub dead_hashes: Vec<Hash>,
+
+  pub live_blobs: usize,
+  pub dead_blobs: Vec<BlobDesc>,
+
+  /// Size of the dead chunks stored in dead blobs (reclaimed by deleting them).
+  pub reclaimed_bytes: usize,
+
+  /// Size of the dead chunks stored in blobs that also contain live chunks.
+  pub unreclaimed_bytes: usize,
+}
+
+/// Decide which hashes and blobs to remove, given the `live` hashes, all `hashes` in the hash index
+/// (with their persistent references) and all `blobs` in the blob index.
+pub fn plan(live: &HashSet<Vec<u8>>, hashes: Vec<(Hash, Vec<u8>)>, blobs: Vec<BlobDesc>) -> Plan {
+  let mut live_blob_names = HashSet::new();
+  let mut dead = Vec::new();
+
+  for (hash, persistent_ref) in hashes.into_iter() {
+    let blob_id_opt = BlobID::try_from_bytes(&persistent_ref[..]);
+    if live.contains(&hash.bytes) {
+      if let Some(id) = blob_id_opt {
+        live_blob_names.insert(id.name().to_vec());
+      }
+    } else {
+      dead.push((hash, blob_id_opt));
+    }
+  }
+
+  let (live_blobs, dead_blobs): (Vec<BlobDesc>, Vec<BlobDesc>) =
+    blobs.into_iter().partition(|b| live_blob_names.contains(&b.name));
+
+  let mut reclaimed_bytes = 0;
+  let mut unreclaimed_bytes = 0;
+  let mut dead_hashes = Vec::new();
+  for (hash, blob_id_opt) in dead.into_iter() {
+    if let Some(id) = blob_id_opt {
+      if !id.is_empty() {
+        if live_blob_names.contains(id.name()) {
+          unreclaimed_bytes += id.end() - id.begin();
+        } else {
+          reclaimed_bytes += id.end() - id.begin();
+        }
+      }
+    }
+    dead_hashes.push(hash);
+  }
+
+  Plan{live_hashes: live.len(), dead_hashes: dead_hashes,
+       live_blobs: live_blobs.len(), dead_blobs: dead_blobs,
+       reclaimed_bytes: reclaimed_bytes, unreclaimed_bytes: unreclaimed_bytes}
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::collections::{HashSet};
+  use std::default::{Default};
+  use std::fs;
+
+  use blob_index::{BlobDesc};
+  use blob_store;
+  use blob_store::{BlobID};
+  use blob_store::tests::{MemoryBackend};
+  use errors::{HatError};
+  use hash_index;
+  use hash_index::{Hash, HashIndex};
+  use hash_tree::{SimpleHashTreeWriter};
+  use hat::{SnapshotSelector};
+  use hat::tests::{random_data, read_file, test_repository, write_file};
+  use key_store::{HashStoreBackend};
+  use process::{Process};
+  use retention;
+
+  #[test]
+  fn plan_keeps_live_blobs() {
+    let blob = |id: i64| BlobDesc{name: vec![id as u8], id: id};
+    let chunk = |content: &str, blob: u8, begin: usize| {
+      (Hash::new(content.as_bytes()),
+       BlobID::new(vec![blob], begin, begin + content.len()).as_bytes())
+    };
+
+    // Blob 1 is all live, blob 2 is mixed and blob 3 is all dead:
+    let hashes = vec![chunk("a", 1, 0), chunk("bb", 2, 0), chunk("ccc", 2, 2),
+                      chunk("dddd", 3, 0)];
+    let mut live = HashSet::new();
+    live.insert(Hash::new(b"a").bytes);
+    live.insert(Hash::new(b"bb").bytes);
+
+    let p = plan(&live, hashes, vec![blob(1), blob(2), blob(3)]);
+    assert_eq!(p.live_hashes, 2);
+    assert_eq!(p.dead_hashes, vec![Hash::new(b"ccc"), Hash::new(b"dddd")]);
+    assert_eq!(p.live_blobs, 2);
+    assert_eq!(p.dead_blobs.iter().map(|b| b.id).collect::<Vec<i64>>(), vec![3]);
+    assert_eq!(p.reclaimed_bytes, 4);
+    assert_eq!(p.unreclaimed_bytes, 3);
+  }
+
+  #[test]
+  fn mark_fails_on_invalid_listing() {
+    let backend = MemoryBackend::new();
+    let hi_p = Process::new(Box::new(move|| { HashIndex::new_for_testing() }));
+    let bs_p = Process::new(Box::new(move|| { blob_store::BlobStore::new_for_testing(backend,
+                                                                                      1024) }));
+    let tree_backend = HashStoreBackend::new(hi_p.clone(), bs_p.clone());
+
+    let mut dir = SimpleHashTreeWriter::new(4, tree_backend.clone());
+    dir.append(br#"[{"name": [120]}]"#.to_vec()).unwrap();
+    let (dir_hash, _) = dir.hash().unwrap();
+    bs_p.send_reply(blob_store::Msg::Flush);
+    hi_p.send_reply(hash_index::Msg::Flush);
+
+    // The data of the undecodable entry is unknown, so nothing may be collected:
+    let mut marker = Marker::new(hi_p, tree_backend);
+    match marker.mark_dir(dir_hash) {
+      Err(HatError::CorruptData(_)) => (),
+      r => panic!("Unexpected result from marker: {:?}", r),
+    }
+  }
+
+  #[test]
+  fn collect_forgotten_snapshot() {
+    let (root, hat, data) = test_repository("gc");
+    let family = "family".to_string();
+
+    fs::create_dir_all(&data).unwrap();
+    let in_data = |name: &str| { let mut p = data.clone(); p.push(name); p };
+    let (kept, removed, added) = (random_data(5000), random_data(5000), random_data(5000));
+
+    write_file(&in_data("kept"), &kept[..]);
+    write_file(&in_data("removed"), &removed[..]);
+    hat.backup(family.clone(), data.clone(), vec![], String::new()).unwrap();
+    fs::remove_file(&in_data("removed")).unwrap();
+    write_file(&in_data("added"), &added[..]);
+    hat.backup(family.clone(), data.clone(), vec![], String::new()).unwrap();
+
+    let policy = retention::Policy{last: 1, ..Default::default()};
+    assert_eq!(hat.forget(&family, &policy, false).unwrap().forget.len(), 1);
+
+    // A snapshot in progress of another family, committed only after collecting:
+    let mut pending_dir = root.clone();
+    pending_dir.push("pending");
+    fs::create_dir_all(&pending_dir).unwrap();
+    let pending = random_data(5000);
+    let mut pending_file = pending_dir.clone();
+    pending_file.push("file");
+    write_file(&pending_file, &pending[..]);
+    {
+      let pending_family = hat.open_family("pending".to_string()).unwrap();
+      pending_family.snapshot_dir(pending_dir.clone()).unwrap();
+      pending_family.flush().unwrap();
+    }
+
+    let plan = hat.gc(false).unwrap();
+    assert!(plan.dead_hashes.len() > 0);
+    assert!(plan.reclaimed_bytes + plan.unreclaimed_bytes >= removed.len());
+
+    // The retained snapshot is intact:
+    let report = hat.verify(None, true).unwrap();
+    assert!(report.is_ok(), "{:?}", report.damage);
+    let mut out = root.clone();
+    out.push("out");
+    hat.checkout_in_dir(family.clone(), out.clone(), SnapshotSelector::Latest, None, false)
+      .unwrap();
+    let in_out = |name: &str| { let mut p = out.clone(); p.push(name); p };
+    assert_eq!(read_file(&in_out("kept")), kept);
+    assert_eq!(read_file(&in_out("added")), added);
+    assert!(fs::metadata(&in_out("removed")).is_err());
+
+    // And so is the data of the snapshot in progress:
+    hat.commit("pending".to_string(), vec![], String::new()).unwrap();
+    let mut pending_out = root.clone();
+    pending_out.push("pending-out");
+    hat.checkout_in_dir("pending".to_string(), pending_out.clone(), SnapshotSelector::Latest,
+                        None, false).unwrap();
+    pending_out.push("file");
+    assert_eq!(read_file(&pending_out), pending);
+  }
+}
@@ -19,6 +19,7 @@ use std::time::duration::{Duration};
 use rustc_serialize::hex::{ToHex};
 
 use callback_container::{CallbackContainer};
+use errors::{HatError, HatResult};
 use cumulative_counter::{CumulativeCounter};
 use unique_priority_queue::{UniquePriorityQueue};
 use process::{Process, MsgHandler};
@@ -26,6 +27,7 @@ use process::{Process, MsgHandler};
 use sqlite3::database::{Database};
 use sqlite3::cursor::{Cursor};
 use sqlite3::types::ResultCode::{SQLITE_DONE, SQLITE_OK, SQLITE_ROW};
+use sqlite3::BindArg;
 use sqlite3::BindArg::{Integer64, Blob};
 use sqlite3::{open};
 
@@ -37,6 +39,10 @@ use sodiumoxide::crypto::hash::{sha512};
 pub type HashIndexProcess = Process<Msg, Reply>;
 
 
+/// Length in bytes of every `Hash`.
+pub static HASH_LENGTH: usize = sha512::HASHBYTES;
+
+
 /// A wrapper around Hash digests.
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub struct Hash{
@@ -72,6 +78,7 @@ pub struct HashEntry{
   pub persistent_ref: Option<Vec<u8>>,
 }
 
+/// Any message is answered with `Error` instead when the index cannot be read or written.
 pub enum Msg {
   /// Check whether this `Hash` already exists in the system.
   /// Returns `HashKnown` or `HashNotKnown`.
@@ -105,7 +112,17 @@ pub enum Msg {
   /// Returns `CallbackRegistered` or `HashNotKnown`.
   CallAfterHashIsComitted(Hash, Thunk<'static>),
 
+  /// List all committed hashes with their persistent references.
+  /// Returns `Listing`.
+  List,
+
+  /// Remove a committed `Hash` from the index. The data it refers to is not touched.
+  /// Returns `DeleteOK`, or `Retry` if the `Hash` is reserved and not yet committed (it is then
+  /// kept, as its data is still being stored).
+  Delete(Hash),
+
   /// Flush the hash index to clear internal buffers and commit the underlying database.
+  /// Returns `CommitOK`, or `Error` if this or an earlier unanswered `Commit` failed.
   Flush,
 }
 
@@ -121,7 +138,11 @@ pub enum Reply {
   CommitOK,
   CallbackRegistered,
 
+  Listing(Vec<(Hash, Vec<u8>)>),
+  DeleteOK,
+
   Retry,
+  Error(HatError),
 }
 
 
@@ -144,11 +165,14 @@ pub struct HashIndex {
 
   flush_timer: PeriodicTimer,
 
+  /// An error that could not be replied to when it happened (e.g. from a `Commit` sent by a blob
+  /// store callback); it is reported by the next `Flush`.
+  error: Option<HatError>,
 }
 
 impl HashIndex {
 
-  pub fn new(path: String) -> HashIndex {
+  pub fn new(path: String) -> HatResult<HashIndex> {
     let mut hi = match open(&path) {
       Ok(dbh) => {
         HashIndex{dbh: dbh,
@@ -156,62 +180,74 @@ impl HashIndex {
                   queue: UniquePriorityQueue::new(),
                   callbacks: CallbackContainer::new(),
                   flush_timer: PeriodicTimer::new(Duration::seconds(10)),
+                  error: None,
         }
       },
-      Err(err) => panic!("{:?}", err),
+      Err(err) => return Err(HatError::index(format!("Could not open hash index {}: {:?}",
+                                                  path, err))),
     };
-    hi.exec_or_die("CREATE TABLE IF NOT EXISTS
+    try!(hi.exec("CREATE TABLE IF NOT EXISTS
                   hash_index (id        INTEGER PRIMARY KEY,
                               hash      BLOB,
                               height    INTEGER,
                               payload   BLOB,
-                              blob_ref  BLOB)");
+                              blob_ref  BLOB)"));
 
-    hi.exec_or_die("CREATE UNIQUE INDEX IF NOT EXISTS
+    try!(hi.exec("CREATE UNIQUE INDEX IF NOT EXISTS
                   HashIndex_UniqueHash
-                  ON hash_index(hash)");
+                  ON hash_index(hash)"));
 
-    hi.exec_or_die("BEGIN");
+    try!(hi.exec("BEGIN"));
 
-    hi.refresh_id_counter();
-    hi
+    try!(hi.refresh_id_counter());
+    Ok(hi)
   }
 
   #[cfg(test)]
   pub fn new_for_testing() -> HashIndex {
-    HashIndex::new(":memory:".to_string())
+    HashIndex::new(":memory:".to_string()).unwrap()
   }
 
-  fn exec_or_die(&mut self, sql: &str) {
+  fn exec(&mut self, sql: &str) -> HatResult<()> {
     match self.dbh.exec(sql) {
-      Ok(true) => (),
-      Ok(false) => panic!("exec: {}", self.dbh.get_errmsg()),
-      Err(msg) => panic!("exec: {:?}, {:?}\nIn sql: '{}'\n",
-                         msg, self.dbh.get_errmsg(), sql)
+      Ok(true) => Ok(()),
+      Ok(false) => Err(HatError::index(format!("Hash index: {}, in '{}'",
+                                            self.dbh.get_errmsg(), sql))),
+      Err(msg) => Err(HatError::index(format!("Hash index: {} ({:?}), in '{}'",
+                                           self.dbh.get_errmsg(), msg, sql))),
     }
   }
 
-  fn prepare_or_die<'a>(&'a self, sql: &str) -> Cursor<'a> {
+  fn prepare<'a>(&'a self, sql: &str) -> HatResult<Cursor<'a>> {
     match self.dbh.prepare(sql, &None) {
-      Ok(s)  => s,
-      Err(x) => panic!("sqlite error: {} ({:?})",
-                       self.dbh.get_errmsg(), x),
+      Ok(s)  => Ok(s),
+      Err(x) => Err(HatError::index(format!("Hash index: {} ({:?})", self.dbh.get_errmsg(), x))),
+    }
+  }
+
+  /// Bind `params` to the parameters of `cursor`, in order.
+  fn bind(&self, cursor: &mut Cursor, params: Vec<BindArg>) -> HatResult<()> {
+    for (i, param) in params.into_iter().enumerate() {
+      if cursor.bind_param(i as i32 + 1, &param) != SQLITE_OK {
+        return Err(HatError::index(format!("Hash index: {}", self.dbh.get_errmsg())));
+      }
     }
+    Ok(())
   }
 
-  fn select1<'a>(&'a mut self, sql: &str) -> Option<Cursor<'a>> {
-    let mut cursor = self.prepare_or_die(sql);
-    if cursor.step() == SQLITE_ROW { Some(cursor) } else { None }
+  fn select1<'a>(&'a mut self, sql: &str) -> HatResult<Option<Cursor<'a>>> {
+    let mut cursor = try!(self.prepare(sql));
+    Ok(if cursor.step() == SQLITE_ROW { Some(cursor) } else { None })
   }
 
-  fn index_locate(&mut self, hash: &Hash) -> Option<QueueEntry> {
+  fn index_locate(&mut self, hash: &Hash) -> HatResult<Option<QueueEntry>> {
     assert!(hash.bytes.len() > 0);
 
-    let result_opt = self.select1(&format!(
+    let result_opt = try!(self.select1(&format!(
       "SELECT id, height, payload, blob_ref FROM hash_index WHERE hash=x'{}'",
       hash.bytes.to_hex()
-    ));
-    result_opt.map(|result| {
+    )));
+    Ok(result_opt.map(|result| {
       let mut result = result;
       let id = result.get_int(0) as i64;
       let level = result.get_int(1) as i64;
@@ -221,25 +257,31 @@ impl HashIndex {
                  payload: if payload.len() == 0 { None }
                           else {Some(payload) },
                  persistent_ref: Some(persistent_ref)
-      } })
+      } }))
   }
 
-  fn locate(&mut self, hash: &Hash) -> Option<QueueEntry> {
-    let result_opt = self.queue.find_value_of_key(&hash.bytes);
-    result_opt.map(|x| x).or_else(|| self.index_locate(hash))
+  fn locate(&mut self, hash: &Hash) -> HatResult<Option<QueueEntry>> {
+    match self.queue.find_value_of_key(&hash.bytes) {
+      Some(queue_entry) => Ok(Some(queue_entry)),
+      None => self.index_locate(hash),
+    }
   }
 
-  fn refresh_id_counter(&mut self) {
-    let id = self.select1("SELECT MAX(id) FROM hash_index").expect("id").get_int(0);
+  fn refresh_id_counter(&mut self) -> HatResult<()> {
+    let id = match try!(self.select1("SELECT MAX(id) FROM hash_index")) {
+      Some(cursor) => cursor.get_int(0),
+      None => 0,
+    };
     self.id_counter = CumulativeCounter::new(id as i64);
+    Ok(())
   }
 
   fn next_id(&mut self) -> i64 {
     self.id_counter.next()
   }
 
-  fn reserve(&mut self, hash_entry: HashEntry) -> i64 {
-    self.maybe_flush();
+  fn reserve(&mut self, hash_entry: HashEntry) -> HatResult<i64> {
+    try!(self.maybe_flush());
 
     let HashEntry{hash, level, payload, persistent_ref} = hash_entry;
     assert!(hash.bytes.len() > 0);
@@ -253,13 +295,16 @@ impl HashIndex {
                                     payload: payload,
                                     persistent_ref: persistent_ref
                          });
-    my_id
+    Ok(my_id)
   }
 
-  fn update_reserved(&mut self, hash_entry: HashEntry) {
+  fn update_reserved(&mut self, hash_entry: HashEntry) -> HatResult<()> {
     let HashEntry{hash, level, payload, persistent_ref} = hash_entry;
     assert!(hash.bytes.len() > 0);
-    let old_entry = self.locate(&hash).expect("hash was reserved");
+    let old_entry = match try!(self.locate(&hash)) {
+      Some(entry) => entry,
+      None => return Err(HatError::MissingHash(hash)),
+    };
 
     // If we didn't already commit and pop() the hash, update it:
     let id_opt = self.queue.find_key(&hash.bytes).map(|id| id.clone());
@@ -271,28 +316,34 @@ impl HashIndex {
                                               persistent_ref: persistent_ref.clone(),
                                               ..qe.clone()});
     }
+    Ok(())
   }
 
-  fn register_hash_callback(&mut self, hash: &Hash, callback: Thunk<'static>) -> bool {
+  fn register_hash_callback(&mut self, hash: &Hash, callback: Thunk<'static>)
+                            -> HatResult<bool> {
     assert!(hash.bytes.len() > 0);
 
     if self.queue.find_value_of_key(&hash.bytes).is_some() {
       self.callbacks.add(hash.bytes.clone(), callback);
-    } else if self.locate(hash).is_some() {
+    } else if try!(self.locate(hash)).is_some() {
       // Hash was already committed
       callback();
     } else {
       // We cannot register this callback, since the hash doesn't exist anywhere
-      return false
+      return Ok(false)
     }
 
-    return true;
+    return Ok(true);
   }
 
-  fn insert_completed_in_order(&mut self) {
-    let mut insert_stm = self.dbh.prepare(
+  fn insert_completed_in_order(&mut self) -> HatResult<()> {
+    let mut insert_stm = match self.dbh.prepare(
       "INSERT INTO hash_index (id, hash, height, payload, blob_ref) VALUES (?, ?, ?, ?, ?)",
-      &None).unwrap();
+      &None) {
+      Ok(s) => s,
+      Err(x) => return Err(HatError::index(format!("Hash index: {} ({:?})",
+                                                self.dbh.get_errmsg(), x))),
+    };
 
     loop {
       match self.queue.pop_min_if_complete() {
@@ -303,50 +354,75 @@ impl HashIndex {
           let child_refs_opt = queue_entry.payload;
           let payload = child_refs_opt.unwrap_or_else(|| vec!());
           let level = queue_entry.level;
-          let persistent_ref = queue_entry.persistent_ref.expect("hash was comitted");
-
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(1, &Integer64(id)));
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(2, &Blob(hash_bytes.clone())));
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(3, &Integer64(level)));
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(4, &Blob(payload)));
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(5, &Blob(persistent_ref)));
-
-          assert_eq!(SQLITE_DONE, insert_stm.step());
-
-          assert_eq!(SQLITE_OK, insert_stm.clear_bindings());
-          assert_eq!(SQLITE_OK, insert_stm.reset());
+          let persistent_ref = match queue_entry.persistent_ref {
+            Some(persistent_ref) => persistent_ref,
+            None => return Err(HatError::CorruptData(format!(
+              "Hash {} was committed without a reference", hash_bytes.to_hex()))),
+          };
+
+          try!(self.bind(&mut insert_stm, vec![Integer64(id),
+                                               Blob(hash_bytes.clone()),
+                                               Integer64(level),
+                                               Blob(payload),
+                                               Blob(persistent_ref)]));
+
+          if insert_stm.step() != SQLITE_DONE ||
+             insert_stm.clear_bindings() != SQLITE_OK || insert_stm.reset() != SQLITE_OK {
+            return Err(HatError::index(format!("Hash index: {}", self.dbh.get_errmsg())));
+          }
 
           self.callbacks.allow_flush_of(&hash_bytes);
         },
       }
     }
+    Ok(())
   }
 
-  fn commit(&mut self, hash: &Hash, blob_ref: &Vec<u8>) {
+  fn commit(&mut self, hash: &Hash, blob_ref: &Vec<u8>) -> HatResult<()> {
     // Update persistent reference for ready hash
-    let queue_entry = self.locate(hash).expect("hash was committed");
+    let queue_entry = match try!(self.locate(hash)) {
+      Some(entry) => entry,
+      None => return Err(HatError::MissingHash(hash.clone())),
+    };
     self.queue.update_value(&hash.bytes,
                             |old_qe| QueueEntry{persistent_ref: Some(blob_ref.clone()),
                                                 ..old_qe.clone()});
     self.queue.set_ready(queue_entry.id);
 
-    self.insert_completed_in_order();
+    try!(self.insert_completed_in_order());
+
+    self.maybe_flush()
+  }
+
+  fn list(&mut self) -> HatResult<Vec<(Hash, Vec<u8>)>> {
+    let mut cursor = try!(self.prepare("SELECT hash, blob_ref FROM hash_index"));
+    let mut out = Vec::new();
+    while cursor.step() == SQLITE_ROW {
+      let hash = Hash{bytes: cursor.get_blob(0).unwrap_or(&[]).to_vec()};
+      let persistent_ref = cursor.get_blob(1).unwrap_or(&[]).to_vec();
+      out.push((hash, persistent_ref));
+    }
+    Ok(out)
+  }
 
-    self.maybe_flush();
+  fn delete(&mut self, hash: &Hash) -> HatResult<()> {
+    self.exec(&format!("DELETE FROM hash_index WHERE hash=x'{}'", hash.bytes.to_hex()))
   }
 
-  fn maybe_flush(&mut self) {
+  fn maybe_flush(&mut self) -> HatResult<()> {
     if self.flush_timer.did_fire() {
-      self.flush();
+      try!(self.flush());
     }
+    Ok(())
   }
 
-  fn flush(&mut self) {
+  fn flush(&mut self) -> HatResult<()> {
     // Callbacks assume their data is safe, so commit before calling them
-    self.exec_or_die("COMMIT; BEGIN");
+    try!(self.exec("COMMIT; BEGIN"));
 
     // Run ready callbacks
     self.callbacks.flush();
+    Ok(())
   }
 }
 
@@ -370,26 +446,29 @@ impl MsgHandler<Msg, Reply> for HashIndex {
       Msg::HashExists(hash) => {
         assert!(hash.bytes.len() > 0);
         return reply(match self.locate(&hash) {
-          Some(_) => Reply::HashKnown,
-          None => Reply::HashNotKnown,
+          Ok(Some(_)) => Reply::HashKnown,
+          Ok(None) => Reply::HashNotKnown,
+          Err(e) => Reply::Error(e),
         });
       },
 
       Msg::FetchPayload(hash) => {
         assert!(hash.bytes.len() > 0);
         return reply(match self.locate(&hash) {
-          Some(ref queue_entry) => Reply::Payload(queue_entry.payload.clone()),
-          None => Reply::HashNotKnown,
+          Ok(Some(ref queue_entry)) => Reply::Payload(queue_entry.payload.clone()),
+          Ok(None) => Reply::HashNotKnown,
+          Err(e) => Reply::Error(e),
         });
       },
 
       Msg::FetchPersistentRef(hash) => {
         assert!(hash.bytes.len() > 0);
         return reply(match self.locate(&hash) {
-          Some(ref queue_entry) if queue_entry.persistent_ref.is_none() => Reply::Retry,
-          Some(queue_entry) =>
+          Ok(Some(ref queue_entry)) if queue_entry.persistent_ref.is_none() => Reply::Retry,
+          Ok(Some(queue_entry)) =>
             Reply::PersistentRef(queue_entry.persistent_ref.expect("persistent_ref")),
-          None => Reply::HashNotKnown,
+          Ok(None) => Reply::HashNotKnown,
+          Err(e) => Reply::Error(e),
         });
       },
 
@@ -399,36 +478,116 @@ impl MsgHandler<Msg, Reply> for HashIndex {
         // This allows us to continue after a crash without needing to scan through and delete
         // uncommitted entries.
         return reply(match self.locate(&hash_entry.hash) {
-          Some(_) => Reply::HashKnown,
-          None => { self.reserve(hash_entry); Reply::ReserveOK },
+          Ok(Some(_)) => Reply::HashKnown,
+          Ok(None) => match self.reserve(hash_entry) {
+            Ok(_) => Reply::ReserveOK,
+            Err(e) => Reply::Error(e),
+          },
+          Err(e) => Reply::Error(e),
         });
       },
 
       Msg::UpdateReserved(hash_entry) => {
         assert!(hash_entry.hash.bytes.len() > 0);
-        self.update_reserved(hash_entry);
-        return reply(Reply::ReserveOK);
+        match self.update_reserved(hash_entry) {
+          Ok(()) => return reply(Reply::ReserveOK),
+          Err(e) => return reply(Reply::Error(e)),
+        }
       }
 
       Msg::Commit(hash, persistent_ref) => {
         assert!(hash.bytes.len() > 0);
-        self.commit(&hash, &persistent_ref);
-        return reply(Reply::CommitOK);
+        match self.commit(&hash, &persistent_ref) {
+          Ok(()) => return reply(Reply::CommitOK),
+          Err(e) => {
+            // Commits are sent from blob store callbacks, which do not look at the reply:
+            self.error = Some(e.clone());
+            return reply(Reply::Error(e));
+          },
+        }
       },
 
       Msg::CallAfterHashIsComitted(hash, callback) => {
         assert!(hash.bytes.len() > 0);
-        if self.register_hash_callback(&hash, callback) {
-          return reply(Reply::CallbackRegistered);
-        } else {
-          return reply(Reply::HashNotKnown);
+        match self.register_hash_callback(&hash, callback) {
+          Ok(true) => return reply(Reply::CallbackRegistered),
+          Ok(false) => return reply(Reply::HashNotKnown),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
+
+      Msg::List => {
+        match self.list() {
+          Ok(listing) => return reply(Reply::Listing(listing)),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
+
+      Msg::Delete(hash) => {
+        if hash.bytes.len() == 0 {
+          return reply(Reply::Error(HatError::CorruptData("Cannot delete an empty hash"
+                                                          .to_string())));
+        }
+        if self.queue.find_key(&hash.bytes).is_some() {
+          return reply(Reply::Retry);
+        }
+        match self.delete(&hash) {
+          Ok(()) => return reply(Reply::DeleteOK),
+          Err(e) => return reply(Reply::Error(e)),
         }
       },
 
       Msg::Flush => {
-        self.flush();
-        return reply(Reply::CommitOK);
+        if let Err(e) = self.flush() {
+          return reply(Reply::Error(e));
+        }
+        match self.error.take() {
+          None => return reply(Reply::CommitOK),
+          Some(e) => return reply(Reply::Error(e)),
+        }
       }
     }
   }
 }
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use errors::{HatError};
+  use process::{Process};
+
+  #[test]
+  fn delete_keeps_reserved_hashes() {
+    let hi_p: HashIndexProcess = Process::new(Box::new(move|| { HashIndex::new_for_testing() }));
+    let entry = |data: &[u8]| HashEntry{hash: Hash::new(data), level: 0, payload: None,
+                                        persistent_ref: None};
+    let exists = |hash: Hash| match hi_p.send_reply(Msg::HashExists(hash)) {
+      Reply::HashKnown => true,
+      Reply::HashNotKnown => false,
+      _ => panic!("Unexpected reply from hash index."),
+    };
+
+    hi_p.send_reply(Msg::Reserve(entry(b"committed")));
+    hi_p.send_reply(Msg::Commit(Hash::new(b"committed"), b"ref".to_vec()));
+    hi_p.send_reply(Msg::Reserve(entry(b"reserved")));
+
+    match hi_p.send_reply(Msg::Delete(Hash::new(b"reserved"))) {
+      Reply::Retry => (),
+      _ => panic!("Reserved hash was deleted."),
+    }
+    assert!(exists(Hash::new(b"reserved")));
+
+    match hi_p.send_reply(Msg::Delete(Hash::new(b"committed"))) {
+      Reply::DeleteOK => (),
+      _ => panic!("Committed hash was not deleted."),
+    }
+    assert!(!exists(Hash::new(b"committed")));
+
+    match hi_p.send_reply(Msg::Delete(Hash{bytes: vec![]})) {
+      Reply::Error(HatError::CorruptData(_)) => (),
+      _ => panic!("Empty hash was not rejected."),
+    }
+  }
+}
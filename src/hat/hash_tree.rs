@@ -21,28 +21,30 @@
 // use serialize::json;
 
 use rustc_serialize::json;
+use errors::{HatResult};
 use hash_index::{Hash};
 use std::{str};
 
 
+/// Reference from an internal node of a hash tree to one of its children.
 #[derive(Clone, Debug, Eq, PartialEq, RustcEncodable, RustcDecodable)]
-struct HashRef {
+pub struct HashRef {
   pub hash: Vec<u8>,
   pub persistent_ref: Vec<u8>,
 }
 
 impl HashRef {
-  fn new(hash: Vec<u8>, persistent_ref: Vec<u8>) -> HashRef {
+  pub fn new(hash: Vec<u8>, persistent_ref: Vec<u8>) -> HashRef {
     HashRef{hash: hash, persistent_ref: persistent_ref}
   }
 }
 
 
 pub trait HashTreeBackend {
-  fn fetch_chunk(&mut self, Hash) -> Option<Vec<u8>>;
-  fn fetch_payload(&mut self, Hash) -> Option<Vec<u8>>;
-  fn fetch_persistent_ref(&mut self, Hash) -> Option<Vec<u8>>;
-  fn insert_chunk(&mut self, Hash, i64, Option<Vec<u8>>, Vec<u8>) -> Vec<u8>;
+  fn fetch_chunk(&mut self, Hash) -> HatResult<Vec<u8>>;
+  fn fetch_payload(&mut self, Hash) -> HatResult<Option<Vec<u8>>>;
+  fn fetch_persistent_ref(&mut self, Hash) -> HatResult<Option<Vec<u8>>>;
+  fn insert_chunk(&mut self, Hash, i64, Option<Vec<u8>>, Vec<u8>) -> HatResult<Vec<u8>>;
 }
 
 
@@ -50,10 +52,20 @@ fn hash_refs_to_bytes(refs: &Vec<HashRef>) -> Vec<u8> {
   json::encode(refs).unwrap().as_bytes().to_vec()
 }
 
-fn hash_refs_from_bytes(bytes: &[u8]) -> Option<Vec<HashRef>> {
+/// Decode the child references of an internal node. Returns `None` for data (leaf) chunks.
+pub fn hash_refs_from_bytes(bytes: &[u8]) -> Option<Vec<HashRef>> {
   return str::from_utf8(bytes).ok().and_then(|s| { json::decode(s).ok() })
 }
 
+/// The hash of an internal node: the hash of its children's hashes, concatenated.
+pub fn internal_node_hash(refs: &Vec<HashRef>) -> Hash {
+  let mut hashes = Vec::new();
+  for hashref in refs.iter() {
+    hashes.extend(hashref.hash.iter().cloned());
+  }
+  Hash::new(&hashes[..])
+}
+
 #[quickcheck]
 fn test_json(count: u8, hash: Vec<u8>, pref: Vec<u8>) -> bool {
   let mut refs = Vec::new();
@@ -107,18 +119,20 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeWriter<B> {
   /// When reading back the tree, these blocks will be returned exactly as they were written, in the
   /// same order, and split at the same boundaries (i.e. pushing 1-bytes blocks will give 1-byte
   /// blocks when reading; if needed, accummulation of data must be handled by the `backend`).
-  pub fn append(&mut self, chunk: Vec<u8>) {
+  pub fn append(&mut self, chunk: Vec<u8>) -> HatResult<()> {
     let hash = Hash::new(chunk.as_slice());
-    self.append_at(0, hash, chunk, None);
+    self.append_at(0, hash, chunk, None)
   }
 
-  fn append_at(&mut self, level: usize, hash: Hash, data: Vec<u8>, metadata: Option<Vec<u8>>) {
-    let persistent_ref = self.backend.insert_chunk(hash.clone(), level as i64, metadata, data);
+  fn append_at(&mut self, level: usize, hash: Hash, data: Vec<u8>, metadata: Option<Vec<u8>>)
+               -> HatResult<()> {
+    let persistent_ref = try!(self.backend.insert_chunk(hash.clone(), level as i64, metadata,
+                                                        data));
     let hash_ref = HashRef::new(hash.bytes, persistent_ref);
-    self.append_hashref_at(level, hash_ref);
+    self.append_hashref_at(level, hash_ref)
   }
 
-  fn append_hashref_at(&mut self, level: usize, hashref: HashRef) {
+  fn append_hashref_at(&mut self, level: usize, hashref: HashRef) -> HatResult<()> {
     assert!(self.levels.len() >= level);
     self.grow_to(level);
 
@@ -129,11 +143,12 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeWriter<B> {
     };
 
     if new_level_len == self.order {
-      self.collapse_level(level);
+      try!(self.collapse_level(level));
     }
+    Ok(())
   }
 
-  fn collapse_level(&mut self, level: usize) {
+  fn collapse_level(&mut self, level: usize) -> HatResult<()> {
     // Extract-replace level with a new empty level
     assert!(self.levels.len() > level);
     self.levels.push(Vec::new());
@@ -153,7 +168,7 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeWriter<B> {
     };
 
     let hash = Hash::new(metadata_bytes.as_slice());
-    self.append_at(level + 1, hash, data, Some(metadata_bytes));
+    self.append_at(level + 1, hash, data, Some(metadata_bytes))
   }
 
   /// Retrieve the hash and backend persistent reference that identified this tree.
@@ -161,10 +176,10 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeWriter<B> {
   /// This also flushes and finalizes the tree. It should be considered frozen after calling
   /// `hash()`, i.e. it's OK to call `hash()` multiple times, but it's **not OK** to call `append()`
   /// after `hash()`.
-  pub fn hash(&mut self) -> (Hash, Vec<u8>) {
+  pub fn hash(&mut self) -> HatResult<(Hash, Vec<u8>)> {
     // Empty hash tree is equivalent to hash tree of one empty block:
     if self.levels.len() == 0 {
-      self.append(vec!());
+      try!(self.append(vec!()));
     }
 
     // Locate first level that isn't empty (has data to collapse)
@@ -172,19 +187,21 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeWriter<B> {
 
     // Unless only root has data, collapse all levels up to top level (which is handled next)
     let top_level = self.top_level().expect("levels.len() > 0");
-    (first_non_empty_level_idx..top_level).map(|l| self.collapse_level(l)).last();
+    for l in first_non_empty_level_idx..top_level {
+      try!(self.collapse_level(l));
+    }
 
     // Collapse top level if possible
     let top_level = self.top_level().expect("levels.len() > 0");
     if self.levels.get(top_level).expect("top level").len() > 1 {
-      self.collapse_level(top_level);
+      try!(self.collapse_level(top_level));
     }
 
     // After this point, only root exists and root has exactly one entry:
     assert_eq!(self.levels.last().map(|x| x.len()), Some(1));
     let hashref = self.levels.last().and_then(|x| x.last()).expect("asserted");
 
-    (Hash{bytes:hashref.hash.clone()}, hashref.persistent_ref.clone())
+    Ok((Hash{bytes:hashref.hash.clone()}, hashref.persistent_ref.clone()))
   }
 }
 
@@ -192,17 +209,14 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeWriter<B> {
 /// A structure for reading hash-trees written with `SimpleHashTreeWriter`.
 ///
 /// The hash-tree is "opened" as read-only and is streamed from first to last data-block. The data
-/// blocks are read in the same order as they were written. The reader implement a `Vec<u8>` iterator
-/// used for extracting the tree blocks.
+/// blocks are read in the same order as they were written. The reader implement an iterator over
+/// `HatResult<Vec<u8>>` used for extracting the tree blocks; it stops after the first error.
 ///
 /// ```rust,ignore
-/// let tree_it = match SimpleHashTreeReader::new(backend, top_hash, top_persisitent_ref) {
-///     SingleBlock(block) => return println!("{}", block),
-///     Tree(it) => it,
-/// };
+/// let tree_it = SimpleHashTreeReader::open(backend, top_hash, top_persisitent_ref).unwrap();
 ///
 /// for data_chunk in tree_it {
-///     println!("{}", data_chunk);
+///     println!("{}", try!(data_chunk));
 /// }
 /// ```
 pub struct SimpleHashTreeReader<B> {
@@ -211,17 +225,15 @@ pub struct SimpleHashTreeReader<B> {
 }
 
 
-/// Wrapper for the result of `SimpleHashTreeReader::new()`.
+/// Wrapper for the result of `SimpleHashTreeReader::open()`.
 ///
-/// We wrap the output of `SimpleHashTreeReader::new(...)` in a `ReaderResult` to fast-path the
-/// single-block case (alternatively we could build a one-block iterator).
+/// Nothing is read from the backend before the first block is requested, so opening a tree cannot
+/// fail; errors are returned by the iterator instead.
 pub enum ReaderResult<B> {
+  /// All blocks have been read (or reading failed).
   Empty,
 
-  /// The tree consists of just a single node and its data-chunk is given here.
-  SingleBlock(Vec<u8>),
-
-  /// The tree has more than one node, so a data-chunk iterator is returned.
+  /// A data-chunk iterator over the remaining blocks.
   Tree(SimpleHashTreeReader<B>),
 }
 
@@ -229,34 +241,28 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeReader<B> {
 
   /// Creates a new `HashTreeReader` that reads through the `backend` the blocks of the hash tree
   /// defined by `root_hash` and `root_ref`.
-  pub fn open(backend: B, root_hash: Hash, _root_ref: Vec<u8>) -> Option<ReaderResult<B>>
+  pub fn open(backend: B, root_hash: Hash, root_ref: Vec<u8>) -> Option<ReaderResult<B>>
   {
     if root_hash.bytes.len() == 0 {
       return None
     }
 
-    let data = backend.clone().fetch_chunk(root_hash.clone()).expect(
-      "Could not find tree root hash.");
-
-    match hash_refs_from_bytes(data.as_slice()) {
-      None => Some(ReaderResult::SingleBlock(data)), // There's no tree top, just a data block
-      Some(childs) => {
-        let mut childs = childs;
-        childs.reverse();
-        Some(ReaderResult::Tree(SimpleHashTreeReader{stack: childs, backend: backend}))
-      },
-    }
+    let root = HashRef::new(root_hash.bytes, root_ref);
+    Some(ReaderResult::Tree(SimpleHashTreeReader{stack: vec![root], backend: backend}))
   }
 
-  fn extract(&mut self) -> Option<Vec<u8>> {
+  fn extract(&mut self) -> Option<HatResult<Vec<u8>>> {
     while self.stack.len() > 0 {
       let child = self.stack.pop().expect("len() > 0");
 
       let hash = Hash{bytes: child.hash};
-      let data = self.backend.fetch_chunk(hash).expect("Invalid hash ref");
+      let data = match self.backend.fetch_chunk(hash) {
+        Ok(data) => data,
+        Err(e) => return Some(Err(e)),
+      };
 
       match hash_refs_from_bytes(data.as_slice()) {
-        None => return Some(data),
+        None => return Some(Ok(data)),
         Some(new_childs) => {
           let mut new_childs = new_childs;
           new_childs.reverse();
@@ -272,18 +278,18 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeReader<B> {
 
 
 impl <B: HashTreeBackend + Clone> Iterator for ReaderResult<B> {
-  type Item = Vec<u8>;
+  type Item = HatResult<Vec<u8>>;
 
   /// Read the next block of the hash-tree.
   /// This operation can be expensive, as it may require fetching a file through the backend.
-  fn next(&mut self) -> Option<Vec<u8>> {
-    let (force_empty, res) = match *self {
-      ReaderResult::Tree(ref mut it) => (false, it.extract()),
-      ReaderResult::SingleBlock(ref b) => (true, Some(b.clone())),
-      ReaderResult::Empty => (true, None),
+  fn next(&mut self) -> Option<HatResult<Vec<u8>>> {
+    let res = match *self {
+      ReaderResult::Tree(ref mut it) => it.extract(),
+      ReaderResult::Empty => None,
     };
-    if force_empty || res.is_none() {
-      *self = ReaderResult::Empty;
+    match res {
+      Some(Ok(_)) => (),
+      _ => *self = ReaderResult::Empty,
     }
     return res;
   }
@@ -295,8 +301,10 @@ mod tests {
   use super::*;
   use test::{Bencher};
 
+  use std::io;
   use std::sync::{Arc, Mutex};
 
+  use errors::{HatError, HatResult};
   use hash_index::{Hash};
   use std::collections::{BTreeMap, BTreeSet};
 
@@ -321,34 +329,57 @@ mod tests {
 
   impl HashTreeBackend for MemoryBackend {
 
-    fn fetch_chunk(&mut self, hash:Hash) -> Option<Vec<u8>> {
+    fn fetch_chunk(&mut self, hash:Hash) -> HatResult<Vec<u8>> {
       let guarded_chunks = self.chunks.lock().unwrap();
-      guarded_chunks.get(&hash.bytes).map(|&(_, _, ref chunk)| chunk.clone())
+      match guarded_chunks.get(&hash.bytes) {
+        Some(&(_, _, ref chunk)) => Ok(chunk.clone()),
+        None => Err(HatError::MissingHash(hash.clone())),
+      }
     }
 
-    fn fetch_payload(&mut self, hash:Hash) -> Option<Vec<u8>> {
+    fn fetch_payload(&mut self, hash:Hash) -> HatResult<Option<Vec<u8>>> {
       let guarded_chunks = self.chunks.lock().unwrap();
-      guarded_chunks.get(&hash.bytes).and_then(|&(_, ref payload, _)| payload.clone())
+      Ok(guarded_chunks.get(&hash.bytes).and_then(|&(_, ref payload, _)| payload.clone()))
     }
 
-    fn fetch_persistent_ref(&mut self, hash:Hash) -> Option<Vec<u8>> {
+    fn fetch_persistent_ref(&mut self, hash:Hash) -> HatResult<Option<Vec<u8>>> {
       let guarded_chunks = self.chunks.lock().unwrap();
       if guarded_chunks.contains_key(&hash.bytes) {
-        Some(hash.bytes)
+        Ok(Some(hash.bytes))
       } else {
-        None
+        Ok(None)
       }
     }
 
     fn insert_chunk(&mut self, hash:Hash, level:i64,
-                    payload:Option<Vec<u8>>, chunk:Vec<u8>) -> Vec<u8> {
+                    payload:Option<Vec<u8>>, chunk:Vec<u8>) -> HatResult<Vec<u8>> {
       let mut guarded_seen = self.seen_chunks.lock().unwrap();
       guarded_seen.insert(chunk.clone());
 
       let mut guarded_chunks = self.chunks.lock().unwrap();
       guarded_chunks.insert(hash.bytes.clone(), (level, payload, chunk));
 
-      hash.bytes
+      Ok(hash.bytes)
+    }
+  }
+
+  /// Fails to store anything, like a full disk.
+  #[derive(Clone)]
+  struct FullBackend;
+
+  impl HashTreeBackend for FullBackend {
+    fn fetch_chunk(&mut self, hash:Hash) -> HatResult<Vec<u8>> {
+      Err(HatError::MissingHash(hash))
+    }
+    fn fetch_payload(&mut self, _hash:Hash) -> HatResult<Option<Vec<u8>>> {
+      Ok(None)
+    }
+    fn fetch_persistent_ref(&mut self, _hash:Hash) -> HatResult<Option<Vec<u8>>> {
+      Ok(None)
+    }
+    fn insert_chunk(&mut self, _hash:Hash, _level:i64,
+                    _payload:Option<Vec<u8>>, _chunk:Vec<u8>) -> HatResult<Vec<u8>> {
+      Err(HatError::Io(io::ErrorKind::Other, "No space left on device".to_string()))
     }
   }
 
@@ -358,16 +389,16 @@ mod tests {
     let mut ht = SimpleHashTreeWriter::new(4, backend.clone());
 
     for _ in 0..chunks_count {
-      ht.append(b"a".to_vec());
+      ht.append(b"a".to_vec()).unwrap();
     }
 
-    let (hash, hash_ref) = ht.hash();
+    let (hash, hash_ref) = ht.hash().unwrap();
 
     let mut tree_it = SimpleHashTreeReader::open(backend, hash, hash_ref).expect("tree not found");
 
     if chunks_count == 0 {
       // An empty tree is a tree with a single empty chunk (as opposed to no chunks).
-      assert_eq!(Some(vec![]), tree_it.next());
+      assert_eq!(Some(Ok(vec![])), tree_it.next());
       assert_eq!(0, tree_it.count());
       return true;
     }
@@ -375,7 +406,7 @@ mod tests {
     // We have a tree, let's investigate!
     let mut actual_count = 0;
     for chunk in tree_it {
-      assert_eq!(chunk, b"a".to_vec());
+      assert_eq!(chunk, Ok(b"a".to_vec()));
       actual_count += 1;
     }
     assert_eq!(chunks_count, actual_count);
@@ -390,13 +421,13 @@ mod tests {
     let backend = MemoryBackend::new();
     let mut ht = SimpleHashTreeWriter::new(4, backend.clone());
 
-    ht.append(block.clone());
+    ht.append(block.clone()).unwrap();
 
-    let (hash, hash_ref) = ht.hash();
+    let (hash, hash_ref) = ht.hash().unwrap();
 
     let mut it = SimpleHashTreeReader::open(backend, hash, hash_ref).expect("tree not found");
 
-    assert_eq!(Some(block), it.next());
+    assert_eq!(Some(Ok(block)), it.next());
     assert_eq!(0, it.count());
   }
 
@@ -407,12 +438,12 @@ mod tests {
     let backend = MemoryBackend::new();
     let mut ht = SimpleHashTreeWriter::new(4, backend.clone());
 
-    ht.append(block.clone());
+    ht.append(block.clone()).unwrap();
 
-    let (hash, hash_ref) = ht.hash();
+    let (hash, hash_ref) = ht.hash().unwrap();
 
     let mut it = SimpleHashTreeReader::open(backend, hash, hash_ref).expect("tree not found");
-    assert_eq!(Some(block), it.next());
+    assert_eq!(Some(Ok(block)), it.next());
     assert_eq!(0, it.count());
   }
 
@@ -426,7 +457,7 @@ mod tests {
     {
       for i in 1u8..(order * 4 + 1) as u8 {
         bytes.as_mut_slice()[0] = i;
-        ht.append(bytes.clone());
+        ht.append(bytes.clone()).unwrap();
       }
     }
 
@@ -435,13 +466,13 @@ mod tests {
       assert!(backend.saw_chunk(&bytes));
     }
 
-    let (hash, hash_ref) = ht.hash();
+    let (hash, hash_ref) = ht.hash().unwrap();
 
     let it = SimpleHashTreeReader::open(backend, hash, hash_ref).expect("tree not found");
 
     for (i, chunk) in it.enumerate() {
       bytes.as_mut_slice()[0] = (i+1) as u8;
-      assert_eq!(bytes, chunk);
+      assert_eq!(Ok(bytes.clone()), chunk);
     }
   }
 
@@ -454,10 +485,10 @@ mod tests {
 
     for i in 1u8..order as u8 {
       bytes.as_mut_slice()[0] = i;
-      ht.append(bytes.clone());
+      ht.append(bytes.clone()).unwrap();
     }
 
-    let (hash, hash_ref) = ht.hash();
+    let (hash, hash_ref) = ht.hash().unwrap();
 
     for i in 1u8..order as u8 {
       bytes.as_mut_slice()[0] = i;
@@ -468,11 +499,34 @@ mod tests {
 
     for (i, chunk) in it.enumerate() {
       bytes.as_mut_slice()[0] = (i+1) as u8;
-      assert_eq!(bytes, chunk);
+      assert_eq!(Ok(bytes.clone()), chunk);
     }
   }
 
 
+  #[test]
+  fn missing_chunk_is_an_error() {
+    let backend = MemoryBackend::new();
+    let mut ht = SimpleHashTreeWriter::new(4, backend.clone());
+    ht.append(b"foobar".to_vec()).unwrap();
+    let (_, hash_ref) = ht.hash().unwrap();
+
+    let missing = Hash::new(b"missing");
+    let mut it = SimpleHashTreeReader::open(backend, missing.clone(), hash_ref)
+      .expect("tree not found");
+    assert_eq!(Some(Err(HatError::MissingHash(missing))), it.next());
+    assert_eq!(None, it.next());
+  }
+
+  #[test]
+  fn failed_insert_is_an_error() {
+    let mut ht = SimpleHashTreeWriter::new(4, FullBackend);
+    assert_eq!(ht.append(b"foobar".to_vec()),
+               Err(HatError::Io(io::ErrorKind::Other, "No space left on device".to_string())));
+    assert!(ht.hash().is_err());
+  }
+
+
   #[bench]
   fn append_unknown_16x128_kb(bench: &mut Bencher) {
     let mut bytes = vec![0u8; 128*1024];
@@ -481,9 +535,9 @@ mod tests {
       let mut ht = SimpleHashTreeWriter::new(8, MemoryBackend::new());
       for i in 0u8..16 {
         bytes.as_mut_slice()[0] = i;
-        ht.append(bytes.clone());
+        ht.append(bytes.clone()).unwrap();
       };
-      ht.hash();
+      ht.hash().unwrap();
     });
 
     bench.bytes = 128*1024*16;
@@ -496,9 +550,9 @@ mod tests {
     bench.iter(|| {
       let mut ht = SimpleHashTreeWriter::new(8, MemoryBackend::new());
       for _ in 0i32..16 {
-        ht.append(bytes.clone());
+        ht.append(bytes.clone()).unwrap();
       }
-      ht.hash();
+      ht.hash().unwrap();
     });
 
     bench.bytes = 128*1024*16;
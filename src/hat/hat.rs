@@ -18,44 +18,89 @@
 use rustc_serialize::{json};
 use rustc_serialize::json::{ToJson};
 use std::str;
-use std::collections::{BTreeMap};
 
 use process::{Process};
 
-use blob_index::{BlobIndex};
+use config::{Chunking, RepositoryConfig};
+use diff::{Difference};
+use diff;
+use errors::{HatError, HatResult};
+use repository;
+use retention;
+
+use blob_index::{BlobIndex, BlobIndexProcess};
+use blob_index;
 use blob_store::{BlobStore, BlobStoreProcess, BlobStoreBackend};
+use blob_store;
 
+use gc::{Marker};
+use gc;
 use hash_index::{Hash, HashIndex, HashIndexProcess};
-use key_index::{KeyIndex, KeyEntry};
+use hash_index;
+use key_index::{KeyIndex, KeyIndexProcess, KeyEntry};
+use key_index;
 use key_store::{KeyStore, KeyStoreProcess};
 use key_store;
-use snapshot_index::{SnapshotIndex, SnapshotIndexProcess};
+use snapshot_index::{SnapshotIndex, SnapshotIndexProcess, SnapshotInfo, SnapshotMetadata};
 use snapshot_index;
+use tree_entry::{TreeEntry, EntryKind, XAttrs};
+use verify::{Verifier};
+use verify;
 
+use chunker::{Chunker, ContentDefined, FixedSize};
 use hash_tree;
 use listdir;
+use unix;
 
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::default::{Default};
+use std::ffi::{OsStr};
+use std::path::{Component, Path, PathBuf};
+use std::env;
 use std::fs;
 use std::io;
 use std::io::{Read, Write};
+use std::os::unix::ffi::{OsStrExt};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 
 use std::sync;
 use std::sync::atomic;
 
+use libc;
 use time;
 
 
+/// Identifies a single snapshot of a family.
+#[derive(Clone, Debug)]
+pub enum SnapshotSelector {
+  /// The most recent snapshot.
+  Latest,
+
+  /// The snapshot with this id.
+  Id(u64),
+
+  /// The most recent snapshot created at or before this time (in seconds since the epoch).
+  AsOf(i64),
+}
+
+
 pub struct Hat<B> {
   repository_root: PathBuf,
+  config: RepositoryConfig,
   snapshot_index: SnapshotIndexProcess,
+  blob_index: BlobIndexProcess,
   blob_store: BlobStoreProcess,
   hash_index: HashIndexProcess,
   blob_backend: B,
   hash_backend: key_store::HashStoreBackend,
-  max_blob_size: usize,
 }
 
+/// Key in the key index info of the description of the family's chunker.
+static CHUNKER_INFO: &'static str = "chunker";
+
+/// Key in the key index info of the directory that the family's latest snapshot was taken of.
+static SOURCE_INFO: &'static str = "source";
+
 fn concat_filename(a: &PathBuf, b: String) -> String {
   let mut result = a.clone();
   result.push(&b);
@@ -74,111 +119,801 @@ fn hash_index_name(root: &PathBuf) -> String {
   concat_filename(root, "hash_index.sqlite3".to_string())
 }
 
+/// Name of the host we are running on, or the empty string if it is not known.
+fn hostname() -> String {
+  let mut buf = vec![0u8; 256];
+  let res = unsafe {
+    libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len() as libc::size_t)
+  };
+  if res != 0 {
+    return String::new();
+  }
+  let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+  String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Name of the user we are running as, falling back to the numeric user id.
+fn username() -> String {
+  env::var("USER").or_else(|_| env::var("LOGNAME"))
+    .unwrap_or_else(|_| unsafe { libc::getuid() }.to_string())
+}
+
+/// Whether `path` is the key index of a family (as opposed to a shared index or other file).
+fn is_key_index(root: &PathBuf, path: &PathBuf) -> bool {
+  let path_str = match path.to_str() {
+    Some(p) => p.to_string(),
+    None => return false,
+  };
+  if path_str == snapshot_index_name(root) || path_str == blob_index_name(root) ||
+     path_str == hash_index_name(root) {
+    return false;
+  }
+
+  // All key indexes are SQLite databases:
+  let mut header = [0u8; 16];
+  match fs::File::open(path).and_then(|mut fd| fd.read(&mut header)) {
+    Ok(16) => &header[..] == &b"SQLite format 3\0"[..],
+    _ => false,
+  }
+}
+
+/// Names of the families that have a key index in the repository at `root`.
+fn key_index_families(root: &PathBuf) -> Vec<String> {
+  let mut names = Vec::new();
+  if let Ok(entries) = fs::read_dir(root) {
+    for entry in entries.filter_map(|e| e.ok()) {
+      let path = entry.path();
+      let name_opt = path.file_name().and_then(|n| n.to_str()).map(|n| n.to_string());
+      if let Some(name) = name_opt {
+        if is_key_index(root, &path) {
+          names.push(name);
+        }
+      }
+    }
+  }
+  names
+}
+
+/// Bring the indexes of the repository at `root` up to the current format, and record that in its
+/// manifest. Opening an index adds the columns that older formats lack.
+fn upgrade_repository(root: &PathBuf) -> HatResult<()> {
+  drop(try!(SnapshotIndex::new(snapshot_index_name(root))));
+  for family_name in key_index_families(root).into_iter() {
+    drop(try!(KeyIndex::new(concat_filename(root, family_name))));
+  }
+  repository::finish_layout(root)
+}
+
+/// Create a new, empty repository at `repository_root`.
+///
+/// This creates the repository directory, the blob backend root, the configuration and the
+/// snapshot, blob and hash indexes, and finally the format manifest that marks the repository as
+/// initialised.
+pub fn init_repository(repository_root: &PathBuf, config: &RepositoryConfig)
+                       -> HatResult<()> {
+  try!(repository::create_layout(repository_root, config).map_err(HatError::Repository));
+
+  // Creating the indexes creates their tables:
+  drop(try!(SnapshotIndex::new(snapshot_index_name(repository_root))));
+  drop(try!(BlobIndex::new(blob_index_name(repository_root))));
+  drop(try!(HashIndex::new(hash_index_name(repository_root))));
+
+  repository::finish_layout(repository_root)
+}
+
 impl <B: 'static + BlobStoreBackend + Clone + Send> Hat<B> {
-  pub fn open_repository(repository_root: &PathBuf, backend: B, max_blob_size: usize)
-                        -> Hat<B> {
+  pub fn open_repository(repository_root: &PathBuf, backend: B) -> HatResult<Hat<B>> {
+    let version = try!(repository::check(repository_root).map_err(HatError::Repository));
+    if version < repository::FORMAT_VERSION {
+      try!(upgrade_repository(repository_root));
+    }
+    let config = try!(RepositoryConfig::load(repository_root).map_err(HatError::Repository));
+    let max_blob_size = config.max_blob_size;
+
     let snapshot_index_path = snapshot_index_name(repository_root);
     let blob_index_path = blob_index_name(repository_root);
     let hash_index_path = hash_index_name(repository_root);
-    let si_p = Process::new(Box::new(move|| { SnapshotIndex::new(snapshot_index_path) }));
-    let bi_p = Process::new(Box::new(move|| { BlobIndex::new(blob_index_path) }));
-    let hi_p = Process::new(Box::new(move|| { HashIndex::new(hash_index_path) }));
+    let si_p = try!(Process::new_or_fail(Box::new(move|| {
+      SnapshotIndex::new(snapshot_index_path) })));
+    let bi_p = try!(Process::new_or_fail(Box::new(move|| { BlobIndex::new(blob_index_path) })));
+    let hi_p = try!(Process::new_or_fail(Box::new(move|| { HashIndex::new(hash_index_path) })));
 
     let local_blob_index = bi_p.clone();
     let local_backend = backend.clone();
     let bs_p = Process::new(Box::new(move|| {
       BlobStore::new(local_blob_index, local_backend, max_blob_size) }));
 
-    Hat{repository_root: repository_root.clone(),
-        snapshot_index: si_p,
-        hash_index: hi_p.clone(),
-        blob_store: bs_p.clone(),
-        blob_backend: backend.clone(),
-        hash_backend: key_store::HashStoreBackend::new(hi_p, bs_p),
-        max_blob_size: max_blob_size,
+    Ok(Hat{repository_root: repository_root.clone(),
+           config: config,
+           snapshot_index: si_p,
+           blob_index: bi_p,
+           hash_index: hi_p.clone(),
+           blob_store: bs_p.clone(),
+           blob_backend: backend.clone(),
+           hash_backend: key_store::HashStoreBackend::new(hi_p, bs_p),
+    })
+  }
+
+  pub fn config(&self) -> &RepositoryConfig {
+    &self.config
+  }
+
+  /// Open the family `name`, cutting file data as configured for the repository.
+  pub fn open_family(&self, name: String) -> HatResult<Family> {
+    self.open_configured_family(name, true)
+  }
+
+  /// Open the existing family `name` to read its snapshots or commit its snapshot in progress.
+  /// Unlike `open_family`, this neither creates the family nor records or checks its chunker.
+  fn find_family(&self, family_name: &String) -> HatResult<Family> {
+    self.open_configured_family(family_name.clone(), false)
+  }
+
+  fn open_configured_family(&self, name: String, create: bool) -> HatResult<Family> {
+    let size = self.config.chunk_size;
+    match self.config.chunking() {
+      Chunking::Fixed => self.open_family_in_mode(name, FixedSize::new(size), create),
+      Chunking::ContentDefined => self.open_family_in_mode(name, ContentDefined::new(size), create),
+    }
+  }
+
+  /// Check that `name` can name a family. The key index of a family is stored under its name in
+  /// the repository root, so the name must stay in the root and not be that of another file there.
+  fn check_family_name(&self, name: &String) -> HatResult<()> {
+    if name.len() == 0 || &name[..] == "." || &name[..] == ".." || name.contains('/') ||
+       name.contains('\0') {
+      return Err(HatError::Repository(format!("Invalid family name '{}'", name)));
     }
+    let path = PathBuf::from(concat_filename(&self.repository_root, name.clone()));
+    let reserved = vec![PathBuf::from(snapshot_index_name(&self.repository_root)),
+                        PathBuf::from(blob_index_name(&self.repository_root)),
+                        PathBuf::from(hash_index_name(&self.repository_root)),
+                        RepositoryConfig::path(&self.repository_root),
+                        repository::manifest_path(&self.repository_root),
+                        self.config.blob_path(&self.repository_root)];
+    if reserved.contains(&path) {
+      return Err(HatError::Repository(format!("Family name '{}' is reserved", name)));
+    }
+    Ok(())
+  }
+
+  /// Open the family `name`, cutting file data with `chunker`.
+  ///
+  /// The chunker is recorded with the family when it is first opened, and opening it with a
+  /// different chunker fails: data cut differently does not deduplicate against its snapshots.
+  pub fn open_family_with_chunker<C: 'static + Chunker + Clone>(&self, name: String, chunker: C)
+                                                                -> HatResult<Family> {
+    self.open_family_in_mode(name, chunker, true)
   }
 
-  pub fn open_family(&self, name: String) -> Option<Family> {
+  /// Open the family `name`. Unless `create` is set, the family must exist already, and its key
+  /// index is left as it is.
+  fn open_family_in_mode<C: 'static + Chunker + Clone>(&self, name: String, chunker: C,
+                                                       create: bool) -> HatResult<Family> {
+    try!(self.check_family_name(&name));
+
     // We setup a standard pipeline of processes:
     // KeyStore -> KeyIndex
     //          -> HashIndex
     //          -> BlobStore -> BlobIndex
 
     let key_index_path = concat_filename(&self.repository_root, name.clone());
-    let ki_p = Process::new(Box::new(move|| { KeyIndex::new(key_index_path) }));
+    if !create && fs::metadata(&key_index_path).is_err() {
+      return Err(HatError::Repository(format!("No such family '{}'", name)));
+    }
+    let ki_p: KeyIndexProcess<FileEntry> =
+      try!(Process::new_or_fail(Box::new(move|| { KeyIndex::new(key_index_path) })));
+
+    // Reading or committing does not cut any data, so only new data must match the chunker:
+    if create {
+      let description = chunker.describe();
+      match ki_p.send_reply(key_index::Msg::GetInfo(CHUNKER_INFO.to_string())) {
+        key_index::Reply::Info(None) => {
+          match ki_p.send_reply(key_index::Msg::SetInfo(CHUNKER_INFO.to_string(),
+                                                        description.clone().into_bytes())) {
+            key_index::Reply::UpdateOK => (),
+            key_index::Reply::Error(e) => return Err(e),
+            _ => return Err(HatError::unexpected_reply("key index")),
+          }
+        },
+        key_index::Reply::Info(Some(ref recorded))
+          if &recorded[..] == description.as_bytes() => (),
+        key_index::Reply::Info(Some(recorded)) => return Err(HatError::Repository(format!(
+          "Family '{}' is chunked with '{}', not '{}'", name,
+          String::from_utf8_lossy(&recorded[..]), description))),
+        key_index::Reply::Error(e) => return Err(e),
+        _ => return Err(HatError::unexpected_reply("key index")),
+      }
+    }
 
-    let local_ks = KeyStore::new(ki_p.clone(), self.hash_index.clone(), self.blob_store.clone());
+    let order = self.config.hash_tree_order;
+    let local_ks = KeyStore::new(ki_p.clone(), self.hash_index.clone(), self.blob_store.clone(),
+                                 order, Box::new(chunker.clone()));
     let ks_p = Process::new(Box::new(move|| { local_ks }));
 
-    let ks = KeyStore::new(ki_p, self.hash_index.clone(), self.blob_store.clone());
-    Some(Family{name: name, key_store: ks, key_store_process: ks_p})
+    let ks = KeyStore::new(ki_p.clone(), self.hash_index.clone(), self.blob_store.clone(), order,
+                           Box::new(chunker));
+    Ok(Family{name: name, config: self.config.clone(),
+              key_index: ki_p, key_store: ks, key_store_process: ks_p})
+  }
+
+  /// Commit the snapshot in progress of `family_name`, recording `tags` and `message` with it.
+  pub fn commit(&self, family_name: String, tags: Vec<String>, message: String)
+                -> HatResult<SnapshotMetadata> {
+    let mut family = try!(self.find_family(&family_name));
+    self.commit_family(&mut family, tags, message)
   }
 
-  pub fn commit(&self, family_name: String) {
-    let mut family = self.open_family(family_name.clone()).expect(
-      &format!("Could not open family '{}'", family_name));
+  /// Take a snapshot of `dir`, commit it and record it as a new snapshot of `family_name`.
+  ///
+  /// The snapshot is only added to the snapshot index once all of its data is durably stored, so
+  /// on failure the family is left with (at most) a snapshot in progress, which a later `commit` or
+  /// `backup` completes.
+  pub fn backup(&self, family_name: String, dir: PathBuf, tags: Vec<String>, message: String)
+                -> HatResult<SnapshotMetadata> {
+    if !try!(fs::metadata(&dir)).is_dir() {
+      return Err(HatError::Io(io::ErrorKind::InvalidInput,
+                              format!("{} is not a directory", dir.display())));
+    }
+
+    let mut family = try!(self.open_family(family_name));
+    try!(family.snapshot_dir(dir));
 
+    self.commit_family(&mut family, tags, message)
+  }
+
+  fn commit_family(&self, family: &mut Family, tags: Vec<String>, message: String)
+                   -> HatResult<SnapshotMetadata> {
     // Commit snapshot:
-    let (hash, top_ref) = family.commit();
-    family.flush();
+    let mut metadata = SnapshotMetadata{hostname: hostname(), user: username(),
+                                        tags: tags, message: message, ..Default::default()};
+    let (hash, top_ref) = try!(family.commit(&mut metadata));
+    try!(family.flush());
+
+    // The hash index commits hashes in the order they were reserved, and the top of the tree is
+    // reserved last. Once it is committed, so is everything it refers to:
+    let (tx, rx) = sync::mpsc::channel();
+    match self.hash_index.send_reply(hash_index::Msg::CallAfterHashIsComitted(
+      hash.clone(), Box::new(move|| { tx.send(()).unwrap(); }))) {
+      hash_index::Reply::CallbackRegistered => (),
+      hash_index::Reply::HashNotKnown => return Err(HatError::MissingHash(hash)),
+      hash_index::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("hash index")),
+    }
+    try!(self.flush_hash_index());
+    if rx.try_recv().is_err() {
+      return Err(HatError::Repository(format!("Snapshot tree {:?} was not durably stored",
+                                              hash)));
+    }
 
     // Update to snapshot index:
-    self.snapshot_index.send_reply(snapshot_index::Msg::Add(family_name, hash, top_ref));
-    self.snapshot_index.send_reply(snapshot_index::Msg::Flush);
-  }
-
-  pub fn checkout_in_dir(&self, family_name: String, output_dir: PathBuf) {
-    // Extract latest snapshot info:
-    let (dir_hash, dir_ref) =
-      match self.snapshot_index.send_reply(snapshot_index::Msg::Latest(family_name.clone())) {
-        snapshot_index::Reply::Latest(Some((h, r))) => (h, r),
-        snapshot_index::Reply::Latest(None) =>
-          panic!("Tries to checkout family '{}' before first commit", family_name),
-        _ => panic!("Unexpected result from snapshot index"),
+    match self.snapshot_index.send_reply(
+      snapshot_index::Msg::Add(family.name.clone(), hash, top_ref, metadata.clone())) {
+      snapshot_index::Reply::AddOK => (),
+      snapshot_index::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("snapshot index")),
+    }
+    try!(self.flush_snapshot_index());
+
+    Ok(metadata)
+  }
+
+  fn flush_hash_index(&self) -> HatResult<()> {
+    match self.hash_index.send_reply(hash_index::Msg::Flush) {
+      hash_index::Reply::CommitOK => Ok(()),
+      hash_index::Reply::Error(e) => Err(e),
+      _ => Err(HatError::unexpected_reply("hash index")),
+    }
+  }
+
+  fn flush_snapshot_index(&self) -> HatResult<()> {
+    match self.snapshot_index.send_reply(snapshot_index::Msg::Flush) {
+      snapshot_index::Reply::FlushOK => Ok(()),
+      snapshot_index::Reply::Error(e) => Err(e),
+      _ => Err(HatError::unexpected_reply("snapshot index")),
+    }
+  }
+
+  /// List the snapshots of `family_name`, or of all families if no family is given.
+  pub fn list_snapshots(&self, family_name: Option<String>) -> HatResult<Vec<SnapshotInfo>> {
+    let msg = match family_name {
+      Some(name) => snapshot_index::Msg::List(name),
+      None => snapshot_index::Msg::ListAll,
+    };
+    match self.snapshot_index.send_reply(msg) {
+      snapshot_index::Reply::Snapshots(snapshots) => Ok(snapshots),
+      snapshot_index::Reply::Error(e) => Err(e),
+      _ => Err(HatError::unexpected_reply("snapshot index")),
+    }
+  }
+
+  /// Check that the snapshots of `family_name` (or of all families) can be restored.
+  ///
+  /// With `check_data`, all data is read back and hashed again to detect corruption.
+  pub fn verify(&self, family_name: Option<String>, check_data: bool)
+                -> HatResult<verify::Report> {
+    let mut verifier = Verifier::new(self.hash_index.clone(), self.blob_backend.clone(),
+                                     check_data);
+    for snapshot in try!(self.list_snapshots(family_name)).iter() {
+      try!(verifier.verify_snapshot(snapshot));
+    }
+    Ok(verifier.report())
+  }
+
+  /// Apply the retention `policy` to the snapshots of `family_name`, removing the snapshots it
+  /// does not keep from the snapshot index (unless `dry_run` is set).
+  ///
+  /// The data of forgotten snapshots stays in the repository until it is removed by `gc`.
+  pub fn forget(&self, family_name: &String, policy: &retention::Policy, dry_run: bool)
+                -> HatResult<retention::Decision> {
+    let snapshots = try!(self.list_snapshots(Some(family_name.clone())));
+    let decision = retention::apply(policy, snapshots);
+    if !dry_run {
+      for snapshot in decision.forget.iter() {
+        match self.snapshot_index.send_reply(snapshot_index::Msg::Delete(snapshot.id)) {
+          snapshot_index::Reply::DeleteOK => (),
+          snapshot_index::Reply::Error(e) => return Err(e),
+          _ => return Err(HatError::unexpected_reply("snapshot index")),
+        }
+      }
+      try!(self.flush_snapshot_index());
+    }
+    Ok(decision)
+  }
+
+  /// Names of all families: those with snapshots, and those with a key index only (i.e. with a
+  /// snapshot in progress that was never committed).
+  fn family_names(&self) -> HatResult<Vec<String>> {
+    let mut names = match self.snapshot_index.send_reply(snapshot_index::Msg::ListFamilies) {
+      snapshot_index::Reply::Families(names) => names,
+      snapshot_index::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("snapshot index")),
+    };
+    for name in key_index_families(&self.repository_root).into_iter() {
+      if !names.contains(&name) {
+        names.push(name);
+      }
+    }
+    names.sort();
+    Ok(names)
+  }
+
+  /// Remove the chunks and blobs that are no longer referenced by any snapshot.
+  ///
+  /// Data of snapshots in progress (taken, but not yet committed) is kept. If any of the retained
+  /// snapshots can not be read completely, nothing is removed. With `dry_run`, nothing is removed
+  /// and the returned plan describes what would be. The repository must not be used by other
+  /// processes while it is collected.
+  pub fn gc(&self, dry_run: bool) -> HatResult<gc::Plan> {
+    // Make sure everything in flight is committed before looking at the indexes:
+    match self.blob_store.send_reply(blob_store::Msg::Flush) {
+      blob_store::Reply::FlushOK => (),
+      blob_store::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("blob store")),
+    }
+    try!(self.flush_hash_index());
+
+    // Mark:
+    let mut marker = Marker::new(self.hash_index.clone(), self.hash_backend.clone());
+    for snapshot in try!(self.list_snapshots(None)).iter() {
+      try!(marker.mark_dir(snapshot.hash.clone()));
+    }
+    for family_name in try!(self.family_names()).into_iter() {
+      let key_index_path = concat_filename(&self.repository_root, family_name);
+      let ki_p: KeyIndexProcess<FileEntry> =
+        try!(Process::new_or_fail(Box::new(move|| { KeyIndex::new(key_index_path) })));
+      let hashes = match ki_p.send_reply(key_index::Msg::ListHashes) {
+        key_index::Reply::Hashes(hashes) => hashes,
+        key_index::Reply::Error(e) => return Err(e),
+        _ => return Err(HatError::unexpected_reply("key index")),
       };
+      for hash in hashes.into_iter() {
+        try!(marker.mark_tree(Hash{bytes: hash}));
+      }
+    }
+    let live = marker.live_hashes();
+
+    // Sweep:
+    let hashes = match self.hash_index.send_reply(hash_index::Msg::List) {
+      hash_index::Reply::Listing(hashes) => hashes,
+      hash_index::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("hash index")),
+    };
+    let blobs = match self.blob_index.send_reply(blob_index::Msg::List) {
+      blob_index::Reply::Listing(blobs) => blobs,
+      blob_index::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("blob index")),
+    };
+    let plan = gc::plan(&live, hashes, blobs);
+    if dry_run {
+      return Ok(plan);
+    }
+
+    // Forget the dead hashes before deleting any blobs, so that a crash cannot leave hashes
+    // pointing into deleted blobs:
+    for hash in plan.dead_hashes.iter() {
+      match self.hash_index.send_reply(hash_index::Msg::Delete(hash.clone())) {
+        hash_index::Reply::DeleteOK => (),
+        // Not committed yet, so its data is still being stored:
+        hash_index::Reply::Retry => (),
+        hash_index::Reply::Error(e) => return Err(e),
+        _ => return Err(HatError::unexpected_reply("hash index")),
+      }
+    }
+    try!(self.flush_hash_index());
+
+    // A blob is only forgotten once it is gone, so that a failed delete is retried by the next
+    // collection:
+    let mut backend = self.blob_backend.clone();
+    for blob in plan.dead_blobs.iter() {
+      try!(backend.delete(&blob.name[..]));
+      match self.blob_index.send_reply(blob_index::Msg::Delete(blob.clone())) {
+        blob_index::Reply::CommitOK => (),
+        blob_index::Reply::Error(e) => return Err(e),
+        _ => return Err(HatError::unexpected_reply("blob index")),
+      }
+    }
+
+    Ok(plan)
+  }
+
+  /// Resolve `selector` to the top hash and persistent reference of a snapshot of `family_name`.
+  pub fn resolve_snapshot(&self, family_name: &String, selector: &SnapshotSelector)
+                          -> HatResult<Option<(Hash, Vec<u8>)>> {
+    let msg = match *selector {
+      SnapshotSelector::Latest => snapshot_index::Msg::Latest(family_name.clone()),
+      SnapshotSelector::Id(id) => snapshot_index::Msg::Lookup(family_name.clone(), id),
+      SnapshotSelector::AsOf(time) => snapshot_index::Msg::LatestAsOf(family_name.clone(), time),
+    };
+    match self.snapshot_index.send_reply(msg) {
+      snapshot_index::Reply::Latest(res_opt) => Ok(res_opt),
+      snapshot_index::Reply::Snapshot(res_opt) => Ok(res_opt),
+      snapshot_index::Reply::Error(e) => Err(e),
+      _ => Err(HatError::unexpected_reply("snapshot index")),
+    }
+  }
+
+  fn find_snapshot(&self, family_name: &String, selector: &SnapshotSelector)
+                   -> HatResult<(Hash, Vec<u8>)> {
+    match try!(self.resolve_snapshot(family_name, selector)) {
+      Some(root) => Ok(root),
+      None => Err(HatError::Repository(format!("Found no snapshot {:?} of family '{}'",
+                                               selector, family_name))),
+    }
+  }
 
-    let family = self.open_family(family_name.clone()).expect(
-      &format!("Could not open family '{}'", family_name));
+  /// Restore a snapshot of `family_name` into `output_dir`.
+  ///
+  /// If `only` is given, just the file or directory at that path inside the snapshot is restored
+  /// (to the same relative path inside `output_dir`), and only the directory listings along that
+  /// path are read. Restored files and directories get their recorded permissions and timestamps,
+  /// and with `restore_owner` also their owner and group (which usually requires running as root).
+  /// Files that were hard links to each other are restored as hard links again. Device nodes are
+  /// skipped with a warning when we lack the privileges to create them.
+  pub fn checkout_in_dir(&self, family_name: String, output_dir: PathBuf,
+                         selector: SnapshotSelector, only: Option<PathBuf>, restore_owner: bool)
+                         -> HatResult<()> {
+    // Extract snapshot info:
+    let (dir_hash, dir_ref) = try!(self.find_snapshot(&family_name, &selector));
+    let family = try!(self.find_family(&family_name));
 
     let mut output_dir = output_dir;
-    self.checkout_dir_ref(&family, &mut output_dir, dir_hash, dir_ref);
-  }
-
-  fn checkout_dir_ref(&self, family: &Family, output: &mut PathBuf, dir_hash: Hash, dir_ref: Vec<u8>) {
-    fs::create_dir_all(&output).unwrap();
-    for o in family.fetch_dir_data(dir_hash, dir_ref, self.hash_backend.clone()) {
-      for d in o.as_array().unwrap().iter() {
-        if let Some(ref m) = d.as_object() {
-          let name: Vec<u8> = m.get("name").unwrap().as_array()
-            .unwrap().iter().map(|x| x.as_i64().unwrap() as u8).collect();
-          output.push(str::from_utf8(&name[..]).unwrap());
-          println!("{}", output.display());
-
-          // TODO(jos): Replace all uses of JSON with either protocol bufffers or cap'n proto.
-          let bytes = m.get("dir_hash").or(m.get("data_hash"))
-            .and_then(|x| x.as_array()).unwrap().iter().map(|y| y.as_i64().unwrap() as u8);
-          let hash = Hash{bytes: bytes.collect()};
-
-          let pref = m.get("dir_ref").or(m.get("data_ref"))
-            .and_then(|x| x.as_array()).unwrap().iter().map(|x| x.as_i64().unwrap() as u8).collect();
-
-          if m.contains_key("dir_hash") {
-            self.checkout_dir_ref(family, output, hash, pref);
-          } else if m.contains_key("data_hash") {
-            let mut fd = fs::File::create(&output).unwrap();
-            let tree_opt = hash_tree::SimpleHashTreeReader::open(self.hash_backend.clone(), hash, pref);
-            if let Some(tree) = tree_opt {
-              family.write_file_chunks(&mut fd, tree);
-            }
+    let mut links = HashMap::new();
+    match only {
+      None => self.checkout_dir_ref(&family, &mut output_dir, dir_hash, dir_ref, restore_owner,
+                                    &mut links),
+      Some(path) => {
+        let entry = try!(self.lookup_path(&family, dir_hash, dir_ref, &path));
+        // The path is relative to the snapshot root even if it starts with '/', and must not
+        // replace the output directory:
+        for name in try!(path_names(&path)).iter() {
+          output_dir.push(OsStr::from_bytes(&name[..]));
+        }
+        if let Some(parent) = output_dir.parent() {
+          try!(fs::create_dir_all(parent));
+        }
+        println!("{}", output_dir.display());
+        self.checkout_entry(&family, &mut output_dir, &entry, restore_owner, &mut links)
+      },
+    }
+  }
+
+  /// Read the complete listing of a committed directory.
+  fn read_dir_entries(&self, family: &Family, dir_hash: Hash, dir_ref: Vec<u8>)
+                      -> HatResult<Vec<TreeEntry>> {
+    let mut entries = Vec::new();
+    for o in try!(family.fetch_dir_data(dir_hash, dir_ref, self.hash_backend.clone())) {
+      entries.extend(try!(TreeEntry::list_from_json(&o)).into_iter());
+    }
+    Ok(entries)
+  }
+
+  /// List the entries at `path` inside a snapshot of `family_name`.
+  ///
+  /// If `path` names a directory, its content is listed, otherwise the entry itself is. With
+  /// `recursive`, the content of all sub-directories is listed as well. Every entry is returned
+  /// with its path relative to the listed directory.
+  pub fn list_path(&self, family_name: &String, selector: &SnapshotSelector, path: &Path,
+                   recursive: bool) -> HatResult<Vec<(PathBuf, TreeEntry)>> {
+    let (dir_hash, dir_ref) = try!(self.find_snapshot(family_name, selector));
+    let family = try!(self.find_family(family_name));
+
+    let mut out = Vec::new();
+    if try!(path_names(path)).len() == 0 {
+      try!(self.collect_entries(&family, dir_hash, dir_ref, &mut PathBuf::new(), recursive,
+                                &mut out));
+      return Ok(out);
+    }
+
+    let entry = try!(self.lookup_path(&family, dir_hash, dir_ref, path));
+    if entry.is_directory() {
+      try!(self.collect_entries(&family, entry.hash, entry.persistent_ref, &mut PathBuf::new(),
+                                recursive, &mut out));
+    } else {
+      out.push((PathBuf::from(OsStr::from_bytes(&entry.name[..])), entry));
+    }
+    Ok(out)
+  }
+
+  /// Write the content of the file at `path` inside a snapshot of `family_name` to `out`.
+  pub fn cat_file<W: Write>(&self, family_name: &String, selector: &SnapshotSelector, path: &Path,
+                            out: &mut W) -> HatResult<()> {
+    let (dir_hash, dir_ref) = try!(self.find_snapshot(family_name, selector));
+    let family = try!(self.find_family(family_name));
+
+    let entry = try!(self.lookup_path(&family, dir_hash, dir_ref, path));
+    if entry.kind != EntryKind::File {
+      return Err(HatError::Repository(format!("Not a file: '{}'", path.display())));
+    }
+
+    let tree_opt = hash_tree::SimpleHashTreeReader::open(
+      self.hash_backend.clone(), entry.hash, entry.persistent_ref);
+    if let Some(tree) = tree_opt {
+      for chunk in tree {
+        try!(out.write_all(&try!(chunk)[..]));
+      }
+    }
+    Ok(try!(out.flush()))
+  }
+
+  /// Compare two snapshots of `family_name`, listing the paths that changed from `old` to `new`.
+  ///
+  /// Sub-directories with the same hash in both snapshots are skipped without being read. A
+  /// directory that was added or removed is reported once, without its content.
+  pub fn diff(&self, family_name: &String, old: &SnapshotSelector, new: &SnapshotSelector)
+              -> HatResult<Vec<Difference>> {
+    let (old_hash, old_ref) = try!(self.find_snapshot(family_name, old));
+    let (new_hash, new_ref) = try!(self.find_snapshot(family_name, new));
+    let family = try!(self.find_family(family_name));
+
+    let mut out = Vec::new();
+    if old_hash != new_hash {
+      try!(self.diff_dirs(&family, (old_hash, old_ref), (new_hash, new_ref),
+                          &mut PathBuf::new(), &mut out));
+    }
+    Ok(out)
+  }
+
+  fn diff_dirs(&self, family: &Family, old: (Hash, Vec<u8>), new: (Hash, Vec<u8>),
+               prefix: &mut PathBuf, out: &mut Vec<Difference>) -> HatResult<()> {
+    let old_entries = try!(self.read_dir_entries(family, old.0, old.1));
+    let new_entries = try!(self.read_dir_entries(family, new.0, new.1));
+    let listing_diff = diff::compare_listings(old_entries, new_entries);
+
+    for (name, change) in listing_diff.changes.into_iter() {
+      prefix.push(OsStr::from_bytes(&name[..]));
+      out.push(Difference{path: prefix.clone(), change: change});
+      prefix.pop();
+    }
+
+    for (old_dir, new_dir) in listing_diff.subdirs.into_iter() {
+      prefix.push(OsStr::from_bytes(&new_dir.name[..]));
+      try!(self.diff_dirs(family, (old_dir.hash, old_dir.persistent_ref),
+                          (new_dir.hash, new_dir.persistent_ref), prefix, out));
+      prefix.pop();
+    }
+    Ok(())
+  }
+
+  fn collect_entries(&self, family: &Family, dir_hash: Hash, dir_ref: Vec<u8>,
+                     prefix: &mut PathBuf, recursive: bool, out: &mut Vec<(PathBuf, TreeEntry)>)
+                     -> HatResult<()> {
+    for entry in try!(self.read_dir_entries(family, dir_hash, dir_ref)).into_iter() {
+      prefix.push(OsStr::from_bytes(&entry.name[..]));
+      out.push((prefix.clone(), entry.clone()));
+      if recursive && entry.is_directory() {
+        try!(self.collect_entries(family, entry.hash, entry.persistent_ref, prefix, recursive,
+                                  out));
+      }
+      prefix.pop();
+    }
+    Ok(())
+  }
+
+  /// Locate the entry at `path` in the committed directory tree given by `dir_hash` and `dir_ref`.
+  ///
+  /// Only the listings of the directories along `path` are fetched.
+  fn lookup_path(&self, family: &Family, dir_hash: Hash, dir_ref: Vec<u8>, path: &Path)
+                 -> HatResult<TreeEntry> {
+    let names = try!(path_names(path));
+    if names.len() == 0 {
+      return Err(HatError::Repository("Path does not name an entry".to_string()));
+    }
+
+    let (mut dir_hash, mut dir_ref) = (dir_hash, dir_ref);
+    let last = names.len() - 1;
+    for (i, name) in names.into_iter().enumerate() {
+      let entry_opt = try!(self.read_dir_entries(family, dir_hash, dir_ref)).into_iter()
+        .find(|e| e.name == name);
+      match entry_opt {
+        None => return Err(HatError::Repository(format!("No such file or directory: '{}'",
+                                                        String::from_utf8_lossy(&name[..])))),
+        Some(entry) => {
+          if i == last {
+            return Ok(entry);
+          }
+          if !entry.is_directory() {
+            return Err(HatError::Repository(format!("Not a directory: '{}'",
+                                                    String::from_utf8_lossy(&name[..]))));
+          }
+          dir_hash = entry.hash;
+          dir_ref = entry.persistent_ref;
+        },
+      }
+    }
+    unreachable!();
+  }
+
+  /// Restore a single entry to `output`. `links` maps the hard links seen so far to the path they
+  /// were first restored to; later entries of the same file become hard links to that path.
+  fn checkout_entry(&self, family: &Family, output: &mut PathBuf, entry: &TreeEntry,
+                    restore_owner: bool, links: &mut HashMap<(u64, u64), PathBuf>)
+                    -> HatResult<()> {
+    match entry.kind {
+      EntryKind::Directory => {
+        try!(self.checkout_dir_ref(family, output, entry.hash.clone(),
+                                   entry.persistent_ref.clone(), restore_owner, links));
+        // Restoring the contents changes the directory's times, and a read-only directory can
+        // not be filled, so its metadata is applied last:
+        restore_metadata(output, entry, restore_owner)
+      },
+      EntryKind::File => {
+        if let Some(key) = entry.hard_link {
+          if let Some(first) = links.get(&key) {
+            // The data and metadata are shared with the first link:
+            return Ok(try!(fs::hard_link(first, &output)));
           }
-          output.pop();
+          links.insert(key, output.clone());
         }
+        {
+          let mut fd = try!(fs::File::create(&output));
+          let tree_opt = hash_tree::SimpleHashTreeReader::open(
+            self.hash_backend.clone(), entry.hash.clone(), entry.persistent_ref.clone());
+          if let Some(tree) = tree_opt {
+            try!(family.write_file_chunks(&mut fd, tree));
+          }
+        }
+        restore_metadata(output, entry, restore_owner)
+      },
+      EntryKind::Symlink => {
+        let target = match entry.link_target {
+          Some(ref target) => target,
+          None => return Err(HatError::CorruptData(format!("Symlink '{}' has no target",
+                                                           output.display()))),
+        };
+        try!(fs::soft_link(&PathBuf::from(OsStr::from_bytes(&target[..])), &output));
+        restore_metadata(output, entry, restore_owner)
+      },
+      EntryKind::Fifo | EntryKind::Socket | EntryKind::CharDevice | EntryKind::BlockDevice => {
+        let mode = entry.kind.special_mode().unwrap() | 0o600;
+        let dev = entry.device.map(|(major, minor)| unix::make_device(major, minor)).unwrap_or(0);
+        match unix::make_node(output, mode, dev) {
+          // Only root may create device nodes; everything else can still be restored:
+          Err(ref e) if unix::is_not_permitted(e) && entry.device.is_some() => {
+            println!("Skipping '{}': {}", output.display(), e);
+            Ok(())
+          },
+          res => {
+            try!(res);
+            restore_metadata(output, entry, restore_owner)
+          },
+        }
+      },
+    }
+  }
+
+  fn checkout_dir_ref(&self, family: &Family, output: &mut PathBuf, dir_hash: Hash,
+                      dir_ref: Vec<u8>, restore_owner: bool,
+                      links: &mut HashMap<(u64, u64), PathBuf>) -> HatResult<()> {
+    try!(fs::create_dir_all(&output));
+    for o in try!(family.fetch_dir_data(dir_hash, dir_ref, self.hash_backend.clone())) {
+      for entry in try!(TreeEntry::list_from_json(&o)).iter() {
+        output.push(OsStr::from_bytes(&entry.name[..]));
+        println!("{}", output.display());
+        try!(self.checkout_entry(family, output, entry, restore_owner, links));
+        output.pop();
       }
     }
+    Ok(())
   }
 }
 
+/// Apply the owner, permissions, extended attributes and timestamps recorded in `entry` to the
+/// restored `path`.
+///
+/// The owner is changed first, since doing so can clear the setuid and setgid bits and file
+/// capabilities. Extended attributes are set while the entry is still writable, before the
+/// permissions; only POSIX ACLs come after the permissions, so that a restored ACL is not narrowed
+/// by them. Attributes are skipped if the file system does not support them. Without
+/// `restore_owner`, `security.*` and `trusted.*` attributes we are not allowed to set are skipped
+/// with a warning. Symlinks do not get permissions or times, as changing those would follow the
+/// link.
+fn restore_metadata(path: &Path, entry: &TreeEntry, restore_owner: bool) -> HatResult<()> {
+  if restore_owner {
+    if let (Some(uid), Some(gid)) = (entry.user_id, entry.group_id) {
+      try!(unix::set_owner(path, uid, gid));
+    }
+  }
+  try!(restore_xattrs(path, entry, restore_owner, false));
+  if !entry.is_symlink() {
+    if let Some(mode) = entry.permissions {
+      try!(fs::set_permissions(path, fs::Permissions::from_mode((mode & 0o7777) as u32)));
+    }
+  }
+  try!(restore_xattrs(path, entry, restore_owner, true));
+  if entry.is_symlink() {
+    return Ok(());
+  }
+  if entry.modified > 0 {
+    try!(fs::set_file_times(path, entry.accessed, entry.modified));
+  }
+  Ok(())
+}
+
+/// Set the extended attributes of `entry` on `path`: either only its POSIX ACLs (with `acls`), or
+/// only its other attributes.
+fn restore_xattrs(path: &Path, entry: &TreeEntry, restore_owner: bool, acls: bool)
+                  -> HatResult<()> {
+  for (name, value) in entry.xattrs.iter() {
+    if name.starts_with(b"system.posix_acl_") != acls {
+      continue;
+    }
+    match unix::set_xattr(path, &name[..], &value[..]) {
+      Err(ref e) if unix::is_unsupported(e) => break,
+      Err(ref e) if !restore_owner && unix::is_not_permitted(e) && is_privileged_xattr(name) => {
+        println!("Skipping attribute '{}' of '{}': {}", String::from_utf8_lossy(&name[..]),
+                 path.display(), e);
+      },
+      res => try!(res),
+    }
+  }
+  Ok(())
+}
+
+/// Whether only privileged processes may set the extended attribute `name`.
+fn is_privileged_xattr(name: &[u8]) -> bool {
+  name.starts_with(b"security.") || name.starts_with(b"trusted.")
+}
+
+/// Split a path inside a snapshot into the names of its components.
+fn path_names(path: &Path) -> HatResult<Vec<Vec<u8>>> {
+  let mut names = Vec::new();
+  for component in path.components() {
+    match component {
+      Component::Normal(name) => names.push(name.as_bytes().to_vec()),
+      Component::CurDir | Component::RootDir => (),
+      _ => return Err(HatError::Repository(format!("Unsupported path component in '{}'",
+                                                   path.display()))),
+    }
+  }
+  Ok(names)
+}
+
+/// Combine a Unix time in seconds and nanoseconds; `None` for times before 1970.
+fn nanoseconds(secs: i64, nsecs: i64) -> Option<u64> {
+  if secs < 0 { None } else { Some(secs as u64 * 1000000000 + nsecs as u64) }
+}
+
 struct FileEntry {
   name: Vec<u8>,
   id: Option<u64>,
@@ -186,29 +921,42 @@ struct FileEntry {
   metadata: fs::Metadata,
   full_path: PathBuf,
   link_path: Option<PathBuf>,
+  xattrs: XAttrs,
 }
 
 impl FileEntry {
   fn new(full_path: PathBuf,
          parent: Option<u64>) -> Result<FileEntry, String> {
-    let filename_opt = full_path.file_name().and_then(|n| n.to_str());
+    // Names are kept as raw bytes, whether they are valid UTF-8 or not:
+    let filename_opt = full_path.file_name().map(|n| n.as_bytes().to_vec());
     let link_path = fs::read_link(&full_path).ok();
 
     if filename_opt.is_some() {
+      // Symlinks are stored as such, so look at the link rather than its target:
+      let metadata = match fs::symlink_metadata(&full_path) {
+        Ok(m) => m,
+        Err(e) => return Err(e.to_string()),
+      };
+      let xattrs = match unix::xattrs(&full_path) {
+        Ok(x) => x,
+        Err(e) => return Err(e.to_string()),
+      };
       Ok(FileEntry{
-        name: filename_opt.unwrap().bytes().collect(),
+        name: filename_opt.unwrap(),
         id: None,
         parent_id: parent.clone(),
-        metadata: fs::metadata(&full_path).unwrap(),
+        metadata: metadata,
         full_path: full_path.clone(),
         link_path: link_path,
+        xattrs: xattrs,
       })
     }
-    else { Err("Could not parse filename."[..].to_string()) }
+    else { Err("Path has no file name."[..].to_string()) }
   }
 
-  fn file_iterator(&self) -> io::Result<FileIterator> {
-    FileIterator::new(&self.full_path)
+  fn open_data(&self) -> io::Result<Box<Read + Send>> {
+    let file = try!(fs::File::open(&self.full_path));
+    Ok(Box::new(file))
   }
 
   fn is_directory(&self) -> bool { self.metadata.is_dir() }
@@ -222,9 +970,10 @@ impl Clone for FileEntry {
       name:self.name.clone(),
       id: self.id.clone(),
       parent_id:self.parent_id.clone(),
-      metadata: fs::metadata(&self.full_path).unwrap(),
+      metadata: self.metadata.clone(),
       full_path: self.full_path.clone(),
       link_path: self.link_path.clone(),
+      xattrs: self.xattrs.clone(),
     }
   }
 }
@@ -255,13 +1004,39 @@ impl KeyEntry<FileEntry> for FileEntry {
   }
 
   fn permissions(&self) -> Option<u64> {
-    None
+    Some(self.metadata.mode() as u64)
   }
   fn user_id(&self) -> Option<u64> {
-    None
+    Some(self.metadata.uid() as u64)
   }
   fn group_id(&self) -> Option<u64> {
-    None
+    Some(self.metadata.gid() as u64)
+  }
+  fn symlink_target(&self) -> Option<Vec<u8>> {
+    self.link_path.as_ref().map(|p| p.as_os_str().as_bytes().to_vec())
+  }
+  fn modified_ns(&self) -> Option<u64> {
+    nanoseconds(self.metadata.mtime(), self.metadata.mtime_nsec())
+  }
+  fn changed_ns(&self) -> Option<u64> {
+    nanoseconds(self.metadata.ctime(), self.metadata.ctime_nsec())
+  }
+  fn inode(&self) -> Option<(u64, u64)> {
+    Some((self.metadata.dev() as u64, self.metadata.ino() as u64))
+  }
+  fn link_count(&self) -> Option<u64> {
+    if self.metadata.is_file() { Some(self.metadata.nlink() as u64) } else { None }
+  }
+  fn xattrs(&self) -> XAttrs {
+    self.xattrs.clone()
+  }
+  fn device(&self) -> Option<u64> {
+    match EntryKind::special_from_mode(self.metadata.mode() as u64) {
+      Some(EntryKind::CharDevice) | Some(EntryKind::BlockDevice) => {
+        Some(self.metadata.rdev() as u64)
+      },
+      _ => None,
+    }
   }
   fn with_id(&self, id: u64) -> FileEntry {
     let mut x = self.clone();
@@ -270,49 +1045,29 @@ impl KeyEntry<FileEntry> for FileEntry {
   }
 }
 
-struct FileIterator {
-  file: fs::File
-}
-
-impl FileIterator {
-  fn new(path: &PathBuf) -> io::Result<FileIterator> {
-    match fs::File::open(path) {
-      Ok(f) => Ok(FileIterator{file: f}),
-      Err(e) => Err(e),
-    }
-  }
-}
-
-impl Iterator for FileIterator {
-  type Item = Vec<u8>;
-
-  fn next(&mut self) -> Option<Vec<u8>> {
-    let mut buf = vec![0u8; 128*1024];
-    match self.file.read(&mut buf[..]) {
-      Err(_) => None,
-      Ok(size) if size == 0 => None,
-      Ok(size) => Some(buf[..size].to_vec()),
-    }
-  }
-}
-
-
 #[derive(Clone)]
 struct InsertPathHandler {
   count: sync::Arc<sync::atomic::AtomicIsize>,
   last_print: sync::Arc<sync::Mutex<time::Timespec>>,
-  key_store: KeyStoreProcess<FileEntry, FileIterator>,
+  key_store: KeyStoreProcess<FileEntry>,
+
+  /// Number of paths that were skipped because they could not be read or stored.
+  failures: sync::Arc<sync::atomic::AtomicUsize>,
 }
 
 impl InsertPathHandler {
-  pub fn new(key_store: KeyStoreProcess<FileEntry, FileIterator>)
-             -> InsertPathHandler {
+  pub fn new(key_store: KeyStoreProcess<FileEntry>) -> InsertPathHandler {
     InsertPathHandler{
       count: sync::Arc::new(sync::atomic::AtomicIsize::new(0)),
       last_print: sync::Arc::new(sync::Mutex::new(time::now().to_timespec())),
       key_store: key_store,
+      failures: sync::Arc::new(sync::atomic::AtomicUsize::new(0)),
     }
   }
+
+  pub fn failures(&self) -> usize {
+    self.failures.load(atomic::Ordering::SeqCst)
+  }
 }
 
 impl listdir::PathHandler<Option<u64>> for InsertPathHandler
@@ -332,21 +1087,24 @@ impl listdir::PathHandler<Option<u64>> for InsertPathHandler
     match FileEntry::new(path.clone(), parent) {
       Err(e) => {
         println!("Skipping '{}': {}", path.display(), e.to_string());
+        self.failures.fetch_add(1, atomic::Ordering::SeqCst);
       },
       Ok(file_entry) => {
-        if file_entry.is_symlink() {
-          return None;
-        }
         let is_directory = file_entry.is_directory();
-        let local_root = path;
+        // Only regular files have data: symlinks are never followed, and FIFOs, sockets and device
+        // nodes are never read (which could block forever):
+        let has_data = file_entry.is_file();
+        let local_root = path.clone();
         let local_file_entry = file_entry.clone();
+        let local_failures = self.failures.clone();
 
         match self.key_store.send_reply(key_store::Msg::Insert(
           file_entry,
-          if is_directory { None }
+          if !has_data { None }
           else { Some(Box::new(move|| {
-            match local_file_entry.file_iterator() {
+            match local_file_entry.open_data() {
               Err(e) => {println!("Skipping '{}': {}", local_root.display(), e.to_string());
+                         local_failures.fetch_add(1, atomic::Ordering::SeqCst);
                          None},
               Ok(it) => { Some(it) }
             }
@@ -356,7 +1114,14 @@ impl listdir::PathHandler<Option<u64>> for InsertPathHandler
           key_store::Reply::Id(id) => {
             if is_directory { return Some(Some(id)) }
           },
-          _ => panic!("Unexpected reply from key store."),
+          key_store::Reply::Error(e) => {
+            println!("Skipping '{}': {}", path.display(), e);
+            self.failures.fetch_add(1, atomic::Ordering::SeqCst);
+          },
+          _ => {
+            println!("Skipping '{}': {}", path.display(), HatError::unexpected_reply("key store"));
+            self.failures.fetch_add(1, atomic::Ordering::SeqCst);
+          },
         }
       }
     }
@@ -366,134 +1131,1038 @@ impl listdir::PathHandler<Option<u64>> for InsertPathHandler
 }
 
 
-fn try_a_few_times_then_panic<F>(f: F, msg: &str) where F: FnMut() -> bool {
+/// Run `f` up to four times, returning the result of the last attempt if all of them fail.
+fn try_a_few_times<F>(f: F) -> io::Result<()> where F: FnMut() -> io::Result<()> {
   let mut f = f;
-  for _ in (1 as i32..5) {
-    if f() { return }
+  for _ in (1 as i32..4) {
+    if f().is_ok() { return Ok(()) }
   }
-  panic!(msg.to_string());
+  f()
 }
 
 
 struct Family {
   name: String,
+  config: RepositoryConfig,
+  key_index: KeyIndexProcess<FileEntry>,
   key_store: KeyStore<FileEntry>,
-  key_store_process: KeyStoreProcess<FileEntry, FileIterator>,
+  key_store_process: KeyStoreProcess<FileEntry>,
 }
 
 impl Family
 {
-  pub fn snapshot_dir(&self, dir: PathBuf) {
+  /// Take a snapshot of `dir`, storing its files in the key store and the data of the files in
+  /// the repository. The snapshot is only marked as finished once everything is flushed, and not at
+  /// all if anything could not be read: committing it would drop the unread files.
+  pub fn snapshot_dir(&self, dir: PathBuf) -> HatResult<()> {
+    // Files that this run does not find have been removed, and must not be committed again:
+    try!(self.update_key_index(key_index::Msg::NewGeneration));
+
+    // Remember where the snapshot is taken from, for when it is committed:
+    let source = env::current_dir().map(|cwd| cwd.join(&dir)).unwrap_or(dir.clone());
+    try!(self.update_key_index(key_index::Msg::SetInfo(
+      SOURCE_INFO.to_string(), source.as_os_str().as_bytes().to_vec())));
+
     let mut handler = InsertPathHandler::new(self.key_store_process.clone());
-    listdir::iterate_recursively((PathBuf::from(&dir), None), &mut handler);
+    let unread_dirs = listdir::iterate_recursively((PathBuf::from(&dir), None), &mut handler,
+                                                   self.config.traversal_threads);
+    try!(self.flush());
+
+    // Only a run that went through the whole tree can be committed:
+    let failures = unread_dirs + handler.failures();
+    if failures > 0 {
+      return Err(HatError::Repository(format!(
+        "Could not read {} entries under '{}'; the snapshot is not finished", failures,
+        dir.display())));
+    }
+    self.update_key_index(key_index::Msg::FinishGeneration)
+  }
+
+  fn update_key_index(&self, msg: key_index::Msg<FileEntry>) -> HatResult<()> {
+    match self.key_index.send_reply(msg) {
+      key_index::Reply::UpdateOK => Ok(()),
+      key_index::Reply::Error(e) => Err(e),
+      _ => Err(HatError::unexpected_reply("key index")),
+    }
   }
 
-  pub fn flush(&self) {
-    self.key_store_process.send_reply(key_store::Msg::Flush);
+  pub fn flush(&self) -> HatResult<()> {
+    match self.key_store_process.send_reply(key_store::Msg::Flush) {
+      key_store::Reply::FlushOK => Ok(()),
+      key_store::Reply::Error(e) => Err(e),
+      _ => Err(HatError::unexpected_reply("key store")),
+    }
   }
 
   fn write_file_chunks<HTB: hash_tree::HashTreeBackend + Clone>(
-    &self, fd: &mut fs::File, tree: hash_tree::ReaderResult<HTB>)
+    &self, fd: &mut fs::File, tree: hash_tree::ReaderResult<HTB>) -> HatResult<()>
   {
     for chunk in tree {
-      try_a_few_times_then_panic(|| fd.write_all(&chunk[..]).is_ok(), "Could not write chunk.");
+      let chunk = try!(chunk);
+      try!(try_a_few_times(|| fd.write_all(&chunk[..])));
     }
-    try_a_few_times_then_panic(|| fd.flush().is_ok(), "Could not flush file.");
+    Ok(try!(try_a_few_times(|| fd.flush())))
   }
 
-  pub fn checkout_in_dir(&self, output_dir: PathBuf, dir_id: Option<u64>) {
-    let mut path = output_dir;
-    for (id, name, _, _, _, hash, _, data_res_opt) in self.list_from_key_store(dir_id).into_iter() {
-      // Extend directory with filename:
-      path.push(str::from_utf8(&name[..]).unwrap());
-
-      if hash.len() == 0 {
-        // This is a directory, recurse!
-        fs::create_dir_all(&path).unwrap();
-        self.checkout_in_dir(path.clone(), Some(id));
-      } else {
-        // This is a file, write it
-        let mut fd = fs::File::create(&path).unwrap();
-        if let Some(data_res) = data_res_opt {
-          self.write_file_chunks(&mut fd, data_res);
-        }
-      }
-      // Prepare for next filename:
-      path.pop();
+  pub fn list_from_key_store(&self, dir_id: Option<u64>) -> HatResult<Vec<key_store::DirElem>> {
+    match self.key_store_process.send_reply(key_store::Msg::ListDir(dir_id)) {
+      key_store::Reply::ListResult(ls) => Ok(ls),
+      key_store::Reply::Error(e) => Err(e),
+      _ => Err(HatError::unexpected_reply("key store")),
     }
   }
 
-  pub fn list_from_key_store(&self, dir_id: Option<u64>) -> Vec<key_store::DirElem> {
-    let listing = match self.key_store_process.send_reply(key_store::Msg::ListDir(dir_id)) {
-      key_store::Reply::ListResult(ls) => ls,
-      _ => panic!("Unexpected result from key store."),
-    };
-
-    return listing;
-  }
-
   pub fn fetch_dir_data<HTB: hash_tree::HashTreeBackend + Clone>(
     &self, dir_hash: Hash, dir_ref: Vec<u8>, backend: HTB
-    ) -> Vec<json::Json> {
+    ) -> HatResult<Vec<json::Json>> {
     let mut out = Vec::new();
-    let it = hash_tree::SimpleHashTreeReader::open(backend, dir_hash, dir_ref)
-      .expect("unable to open dir");
+    let it = match hash_tree::SimpleHashTreeReader::open(backend, dir_hash.clone(), dir_ref) {
+      Some(it) => it,
+      None => return Err(HatError::CorruptData("Directory without hash".to_string())),
+    };
     for chunk in it {
+      let chunk = try!(chunk);
       if chunk.len() == 0 {
         continue;
       }
-      let m = json::Json::from_str(str::from_utf8(&chunk[..]).unwrap()).unwrap();
-      out.push(m);
+      match str::from_utf8(&chunk[..]).ok().and_then(|s| json::Json::from_str(s).ok()) {
+        Some(m) => out.push(m),
+        None => return Err(HatError::CorruptData(
+          format!("Could not decode directory listing {:?}", dir_hash))),
+      }
     }
-    return out;
+    return Ok(out);
   }
 
-  pub fn commit(&mut self) -> (Hash, Vec<u8>) {
+  /// Commit the snapshot in progress to a directory tree, filling in its source and statistics in
+  /// `metadata`.
+  pub fn commit(&mut self, metadata: &mut SnapshotMetadata) -> HatResult<(Hash, Vec<u8>)> {
+    // An interrupted snapshot run has left out the files it did not get to:
+    match self.key_index.send_reply(key_index::Msg::IsFinished) {
+      key_index::Reply::Finished(true) => (),
+      key_index::Reply::Finished(false) => return Err(HatError::Repository(format!(
+        "The last snapshot of family '{}' did not finish; snapshot it again before committing",
+        self.name))),
+      key_index::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("key index")),
+    }
+
+    match self.key_index.send_reply(key_index::Msg::GetInfo(SOURCE_INFO.to_string())) {
+      key_index::Reply::Info(source_opt) => metadata.source = source_opt.unwrap_or(vec![]),
+      key_index::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("key index")),
+    }
+
     let mut top_tree = self.key_store.hash_tree_writer();
-    self.commit_to_tree(&mut top_tree, None);
-    return top_tree.hash();
+    try!(self.commit_to_tree(&mut top_tree, None, metadata));
+    top_tree.hash()
   }
 
   pub fn commit_to_tree(&mut self,
                         tree: &mut hash_tree::SimpleHashTreeWriter<key_store::HashStoreBackend>,
-                        dir_id: Option<u64>) {
+                        dir_id: Option<u64>, metadata: &mut SnapshotMetadata) -> HatResult<()> {
     let mut keys = Vec::new();
 
-    for (id, name, ctime, _mtime, atime, hash, data_ref, _) in self.list_from_key_store(dir_id).into_iter() {
-      let mut m = BTreeMap::new();
-      m.insert("id".to_string(), id.to_json());
-      m.insert("name".to_string(), name.to_json());
-      m.insert("ct".to_string(), ctime.to_json());
-      m.insert("at".to_string(), atime.to_json());
-
-      if hash.len() > 0 {
-        // This is a file, store its data hash:
-        m.insert("data_hash".to_string(), hash.to_json());
-        m.insert("data_ref".to_string(), data_ref.to_json());
-      } else if hash.len() == 0 {
-        drop(hash);
-        drop(data_ref);
+    for (key, _) in try!(self.list_from_key_store(dir_id)).into_iter() {
+      let special_kind = key.permissions.and_then(EntryKind::special_from_mode);
+      let is_directory = EntryKind::is_directory_mode(key.permissions, key.hash.len() > 0);
+
+      // Every kind of entry has the same metadata; only files and directories have data:
+      let mut entry = TreeEntry{id: key.id, name: key.name, kind: EntryKind::File, size: None,
+                                created: key.created, modified: key.modified,
+                                accessed: key.accessed, permissions: key.permissions,
+                                user_id: key.user_id, group_id: key.group_id, link_target: None,
+                                hard_link: None, xattrs: key.xattrs, device: None,
+                                hash: Hash{bytes: vec![]}, persistent_ref: vec![]};
+      if key.symlink_target.is_some() {
+        entry.kind = EntryKind::Symlink;
+        entry.link_target = key.symlink_target;
+      } else if let Some(kind) = special_kind {
+        // FIFOs, sockets and device nodes have no data:
+        entry.kind = kind;
+        entry.device = key.device.map(unix::device_numbers);
+      } else if !is_directory {
+        // This is a file, store its data hash (which is empty if it could not be read):
+        metadata.file_count += 1;
+        metadata.bytes += key.size;
+        entry.size = Some(key.size);
+        entry.hard_link = key.hard_link;
+        entry.hash = Hash{bytes: key.hash};
+        entry.persistent_ref = key.persistent_ref;
+      } else {
         // This is a directory, recurse!
         let mut inner_tree = self.key_store.hash_tree_writer();
-        self.commit_to_tree(&mut inner_tree, Some(id));
+        try!(self.commit_to_tree(&mut inner_tree, Some(key.id), metadata));
         // Store a reference for the sub-tree in our tree:
-        let (dir_hash, dir_ref) = inner_tree.hash();
-        m.insert("dir_hash".to_string(), dir_hash.bytes.to_json());
-        m.insert("dir_ref".to_string(), dir_ref.to_json());
+        let (dir_hash, dir_ref) = try!(inner_tree.hash());
+        entry.kind = EntryKind::Directory;
+        entry.hash = dir_hash;
+        entry.persistent_ref = dir_ref;
       }
 
-      keys.push(json::Json::Object(m).to_json());
+      keys.push(entry.to_json());
 
       // Flush to our own tree when we have a decent amount.
       // The tree prevents large directories from clogging ram.
       if keys.len() >= 1000 {
-        tree.append(keys.to_json().to_string().as_bytes().to_vec());
+        try!(tree.append(keys.to_json().to_string().as_bytes().to_vec()));
         keys.clear();
       }
     }
     if keys.len() > 0 {
-      tree.append(keys.to_json().to_string().as_bytes().to_vec());
+      try!(tree.append(keys.to_json().to_string().as_bytes().to_vec()));
+    }
+    Ok(())
+  }
+
+}
+
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+
+  use rand;
+  use rand::{Rng};
+  use std::collections::{BTreeMap};
+  use std::default::{Default};
+  use std::env;
+  use std::ffi::{OsStr};
+  use std::fs;
+  use std::io;
+  use std::io::{Read, Write};
+  use std::ops::{Deref};
+  use std::os::unix::ffi::{OsStrExt};
+  use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+  use std::path::{Path, PathBuf};
+  use std::sync::{Arc, Mutex};
+
+  use super::{concat_filename};
+
+  use blob_index;
+  use blob_store::{BlobStoreBackend};
+  use blob_store::tests::{MemoryBackend};
+  use chunker::{ContentDefined, FixedSize};
+  use config::{RepositoryConfig};
+  use diff::{Change, Difference};
+  use errors::{HatError, HatResult};
+  use key_index;
+  use libc;
+  use repository;
+  use hash_index::{Hash};
+  use snapshot_index::{SnapshotMetadata};
+  use sqlite3;
+  use tree_entry::{TreeEntry, EntryKind};
+  use unix;
+  use verify::{Damage};
+
+  /// A directory for the files of a test, removed with everything in it when dropped.
+  pub struct ScratchDir {
+    path: PathBuf,
+  }
+
+  impl Deref for ScratchDir {
+    type Target = PathBuf;
+    fn deref(&self) -> &PathBuf {
+      &self.path
+    }
+  }
+
+  impl Drop for ScratchDir {
+    fn drop(&mut self) {
+      // Tests leave read-only and unreadable directories behind, which can not be emptied as is:
+      make_removable(&self.path);
+      let _ = fs::remove_dir_all(&self.path);
+    }
+  }
+
+  fn make_removable(path: &Path) {
+    match fs::symlink_metadata(path) {
+      Ok(ref metadata) if metadata.is_dir() => (),
+      _ => return,
+    }
+    let _ = fs::set_permissions(path, fs::Permissions::from_mode(0o700));
+    if let Ok(entries) = fs::read_dir(path) {
+      for entry in entries {
+        if let Ok(entry) = entry {
+          make_removable(&entry.path());
+        }
+      }
+    }
+  }
+
+  /// A new, empty directory for the files of a test.
+  pub fn scratch_dir(name: &str) -> ScratchDir {
+    let mut path = env::temp_dir();
+    path.push(&format!("hat-test-{}-{}", name, rand::random::<u32>()));
+    fs::create_dir_all(&path).unwrap();
+    ScratchDir{path: path}
+  }
+
+  /// A new repository in a scratch directory named after `name`, and the path of a `data`
+  /// directory next to it for the files to back up (created by the first `write_file`).
+  ///
+  /// The scratch directory is returned first so that it outlives the repository.
+  pub fn test_repository(name: &str) -> (ScratchDir, Hat<MemoryBackend>, PathBuf) {
+    let root = scratch_dir(name);
+    let hat = new_repository(&root);
+    let mut data = root.clone();
+    data.push("data");
+    (root, hat, data)
+  }
+
+  /// A new repository in `root`, with small chunks and blobs so that tests use several of both.
+  pub fn new_repository_with<B: 'static + BlobStoreBackend + Clone + Send>(root: &Path,
+                                                                           backend: B) -> Hat<B> {
+    let mut repository_root = root.to_path_buf();
+    repository_root.push("repo");
+    let config = RepositoryConfig{max_blob_size: 4096, traversal_threads: 2, chunk_size: 1024,
+                                  ..Default::default()};
+    init_repository(&repository_root, &config).unwrap();
+    Hat::open_repository(&repository_root, backend).unwrap()
+  }
+
+  pub fn new_repository(root: &Path) -> Hat<MemoryBackend> {
+    new_repository_with(root, MemoryBackend::new())
+  }
+
+  pub fn random_data(len: usize) -> Vec<u8> {
+    rand::thread_rng().gen_iter::<u8>().take(len).collect()
+  }
+
+  /// Write `data` to the file at `path`, creating its parent directories as needed.
+  pub fn write_file(path: &Path, data: &[u8]) {
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent).unwrap();
+    }
+    fs::File::create(path).and_then(|mut fd| fd.write_all(data)).unwrap();
+  }
+
+  pub fn read_file(path: &Path) -> Vec<u8> {
+    let mut data = Vec::new();
+    fs::File::open(path).and_then(|mut fd| fd.read_to_end(&mut data)).unwrap();
+    data
+  }
+
+  /// Back up `data` as family `name`, and check the snapshot out to a new directory in `root`.
+  fn backup_and_checkout<B: 'static + BlobStoreBackend + Clone + Send>(
+    hat: &Hat<B>, root: &Path, name: &str, data: &Path) -> PathBuf {
+    hat.backup(name.to_string(), data.to_path_buf(), vec![], String::new()).unwrap();
+    let mut out = root.to_path_buf();
+    out.push("out");
+    hat.checkout_in_dir(name.to_string(), out.clone(), SnapshotSelector::Latest, None, false)
+      .unwrap();
+    out
+  }
+
+  fn committed_blobs<B: 'static + BlobStoreBackend + Clone + Send>(hat: &Hat<B>)
+                                                                   -> Vec<blob_index::BlobDesc> {
+    match hat.blob_index.send_reply(blob_index::Msg::List) {
+      blob_index::Reply::Listing(blobs) => blobs,
+      _ => panic!("Unexpected reply from blob index."),
+    }
+  }
+
+  /// Write a blob that no chunk refers to, reporting it to the blob index as in air and then (if
+  /// `commit` is set) as committed.
+  fn write_orphan_blob<B: 'static + BlobStoreBackend + Clone + Send>(hat: &Hat<B>, commit: bool)
+                                                                     -> blob_index::BlobDesc {
+    let blob = match hat.blob_index.send_reply(blob_index::Msg::Reserve) {
+      blob_index::Reply::Reserved(blob) => blob,
+      _ => panic!("Unexpected reply from blob index."),
+    };
+    hat.blob_index.send_reply(blob_index::Msg::InAir(blob.clone()));
+    hat.blob_backend.clone().store(&blob.name[..], b"orphan").unwrap();
+    if commit {
+      hat.blob_index.send_reply(blob_index::Msg::CommitDone(blob.clone()));
     }
+    blob
+  }
+
+  #[test]
+  fn gc_keeps_blobs_in_air() {
+    let root = scratch_dir("gc-in-air");
+    let hat = new_repository(&root);
+    let blob = write_orphan_blob(&hat, false);
+
+    let plan = hat.gc(false).unwrap();
+    assert!(plan.dead_blobs.is_empty());
+    assert_eq!(hat.blob_backend.clone().retrieve(&blob.name[..]), Ok(b"orphan".to_vec()));
   }
 
+  /// Fails to delete anything while `undeletable` is set.
+  #[derive(Clone)]
+  struct UndeletableBackend {
+    undeletable: Arc<Mutex<bool>>,
+    backend: MemoryBackend,
+  }
+
+  impl BlobStoreBackend for UndeletableBackend {
+    fn store(&mut self, name: &[u8], data: &[u8]) -> HatResult<()> {
+      self.backend.store(name, data)
+    }
+    fn retrieve(&mut self, name: &[u8]) -> HatResult<Vec<u8>> {
+      self.backend.retrieve(name)
+    }
+    fn delete(&mut self, name: &[u8]) -> HatResult<()> {
+      if *self.undeletable.lock().unwrap() {
+        return Err(HatError::Backend("Permission denied".to_string()));
+      }
+      self.backend.delete(name)
+    }
+  }
+
+  #[test]
+  fn gc_forgets_blobs_once_deleted() {
+    let root = scratch_dir("gc-delete");
+    let backend = UndeletableBackend{undeletable: Arc::new(Mutex::new(true)),
+                                     backend: MemoryBackend::new()};
+    let hat = new_repository_with(&root, backend.clone());
+    let blob = write_orphan_blob(&hat, true);
+
+    match hat.gc(false) {
+      Err(HatError::Backend(_)) => (),
+      r => panic!("Unexpected result from gc: {:?}", r),
+    }
+    assert_eq!(committed_blobs(&hat), vec![blob.clone()]);
+
+    *backend.undeletable.lock().unwrap() = false;
+    assert_eq!(hat.gc(false).unwrap().dead_blobs, vec![blob.clone()]);
+    assert!(committed_blobs(&hat).is_empty());
+    assert!(backend.clone().retrieve(&blob.name[..]).is_err());
+  }
+
+  #[test]
+  fn open_upgrades_old_repositories() {
+    let root = scratch_dir("upgrade");
+    let mut repository_root = root.clone();
+    repository_root.push("repo");
+    repository::create_layout(&repository_root, &Default::default()).unwrap();
+
+    // The indexes and manifest as written by the first format version:
+    let old_index = |name: &str, sql: &str| {
+      let mut dbh = sqlite3::open(&concat_filename(&repository_root, name.to_string())).unwrap();
+      assert!(dbh.exec(sql).unwrap());
+    };
+    old_index("snapshot_index.sqlite3",
+              "CREATE TABLE snapshot_index (id INTEGER PRIMARY KEY, family BLOB, hash BLOB,
+                                            tree_ref BLOB);
+               INSERT INTO snapshot_index (family, hash, tree_ref)
+               VALUES (x'6f6c64', x'00', x'00')");
+    old_index("old",
+              "CREATE TABLE key_index (rowid INTEGER PRIMARY KEY, parent INTEGER, name BLOB,
+                                       created UINT8, modified UINT8, accessed UINT8, hash BLOB,
+                                       persistent_ref BLOB);
+               INSERT INTO key_index (parent, name) VALUES (0, x'6f6c64')");
+    let mut manifest = repository_root.clone();
+    manifest.push("format.json");
+    write_file(&manifest, br#"{"format": "hat-backup", "version": 1}"#);
+
+    let hat = Hat::open_repository(&repository_root, MemoryBackend::new()).unwrap();
+    assert_eq!(repository::check(&repository_root), Ok(repository::FORMAT_VERSION));
+
+    let snapshots = hat.list_snapshots(None).unwrap();
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].family, "old".to_string());
+    assert_eq!(snapshots[0].metadata, SnapshotMetadata{..Default::default()});
+    assert_eq!(snapshots[0].created, 0);
+
+    // The family's key index takes new snapshots:
+    let mut data = root.clone();
+    data.push("data");
+    fs::create_dir_all(&data).unwrap();
+    let content = random_data(3000);
+    data.push("file");
+    write_file(&data, &content[..]);
+    data.pop();
+    hat.backup("old".to_string(), data, vec![], String::new()).unwrap();
+    assert_eq!(hat.list_snapshots(None).unwrap().len(), 2);
+
+    // Snapshots from before creation times were recorded are older than any other:
+    assert_eq!(hat.resolve_snapshot(&"old".to_string(), &SnapshotSelector::AsOf(0)).unwrap(),
+               Some((Hash{bytes: vec![0]}, vec![0])));
+
+    let mut out = root.clone();
+    out.push("out");
+    hat.checkout_in_dir("old".to_string(), out.clone(), SnapshotSelector::Latest, None, false)
+      .unwrap();
+    out.push("file");
+    assert_eq!(read_file(&out), content);
+  }
+
+  #[test]
+  fn commit_refuses_unfinished_snapshot() {
+    let (_root, hat, mut data) = test_repository("unfinished");
+    fs::create_dir_all(&data).unwrap();
+    data.push("file");
+    write_file(&data, b"contents");
+    data.pop();
+    hat.backup("fam".to_string(), data, vec![], String::new()).unwrap();
+
+    // A snapshot run that is interrupted before going through the whole tree:
+    {
+      let family = hat.open_family("fam".to_string()).unwrap();
+      match family.key_index.send_reply(key_index::Msg::NewGeneration) {
+        key_index::Reply::UpdateOK => (),
+        _ => panic!("Unexpected result from key index."),
+      }
+      family.flush().unwrap();
+    }
+
+    match hat.commit("fam".to_string(), vec![], String::new()) {
+      Err(HatError::Repository(_)) => (),
+      r => panic!("Unfinished snapshot was committed: {:?}", r),
+    }
+    assert_eq!(hat.list_snapshots(None).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn empty_snapshot_can_be_committed_later() {
+    let (_root, hat, data) = test_repository("empty-run");
+    let mut file = data.clone();
+    file.push("file");
+    write_file(&file, b"contents");
+    hat.backup("fam".to_string(), data.clone(), vec![], String::new()).unwrap();
+
+    // A run that finds nothing leaves no entries of its own:
+    fs::remove_file(&file).unwrap();
+    {
+      let family = hat.open_family("fam".to_string()).unwrap();
+      family.snapshot_dir(data.clone()).unwrap();
+      family.flush().unwrap();
+    }
+
+    // Committing opens the family's key index again:
+    let metadata = hat.commit("fam".to_string(), vec![], String::new()).unwrap();
+    assert_eq!(metadata.file_count, 0);
+    assert_eq!(hat.list_snapshots(None).unwrap().len(), 2);
+  }
+
+  #[test]
+  fn checkout_restores_xattrs() {
+    let (root, hat, data) = test_repository("xattrs");
+    let mut file = data.clone();
+    file.push("file");
+    write_file(&file, b"contents");
+    match unix::set_xattr(&file, b"user.hat-test", b"value") {
+      // Nothing to test on this file system:
+      Err(ref e) if unix::is_unsupported(e) => return,
+      res => res.unwrap(),
+    }
+
+    let mut out = backup_and_checkout(&hat, &root, "fam", &data);
+    out.push("file");
+    let xattrs = unix::xattrs(&out).unwrap();
+    assert_eq!(xattrs.get(&b"user.hat-test".to_vec()), Some(&b"value".to_vec()));
+  }
+
+  #[test]
+  fn checkout_restores_xattrs_of_read_only_files() {
+    let (root, hat, data) = test_repository("read-only-xattrs");
+    let mut file = data.clone();
+    file.push("file");
+    write_file(&file, b"contents");
+    match unix::set_xattr(&file, b"user.hat-test", b"value") {
+      Err(ref e) if unix::is_unsupported(e) => return,
+      res => res.unwrap(),
+    }
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o444)).unwrap();
+
+    let mut out = backup_and_checkout(&hat, &root, "fam", &data);
+    out.push("file");
+    let xattrs = unix::xattrs(&out).unwrap();
+    assert_eq!(xattrs.get(&b"user.hat-test".to_vec()), Some(&b"value".to_vec()));
+    assert_eq!(fs::metadata(&out).unwrap().mode() & 0o7777, 0o444);
+  }
+
+  #[test]
+  fn restore_tolerates_privileged_xattrs() {
+    let root = scratch_dir("privileged-xattrs");
+    let mut path = root.clone();
+    path.push("file");
+    write_file(&path, b"contents");
+
+    let mut xattrs = BTreeMap::new();
+    xattrs.insert(b"trusted.hat-test".to_vec(), b"value".to_vec());
+    let entry = TreeEntry{id: 1, name: b"file".to_vec(), kind: EntryKind::File, size: Some(8),
+                          created: 0, modified: 0, accessed: 0,
+                          permissions: None, user_id: None, group_id: None, link_target: None,
+                          hard_link: None, xattrs: xattrs, device: None,
+                          hash: Hash{bytes: vec![]}, persistent_ref: vec![]};
+
+    // Set when running as root, and skipped otherwise:
+    super::restore_metadata(&path, &entry, false).unwrap();
+  }
+
+  #[test]
+  fn non_utf8_names_are_kept() {
+    let root = scratch_dir("non-utf8");
+    let hat = new_repository(&root);
+    let mut data = root.clone();
+    data.push(OsStr::from_bytes(b"source-\xff"));
+    let mut file = data.clone();
+    file.push(OsStr::from_bytes(b"caf\xe9"));
+    write_file(&file, b"contents");
+
+    let mut out = backup_and_checkout(&hat, &root, "fam", &data);
+    let snapshots = hat.list_snapshots(Some("fam".to_string())).unwrap();
+    assert_eq!(snapshots[0].metadata.source, data.as_os_str().as_bytes().to_vec());
+    out.push(OsStr::from_bytes(b"caf\xe9"));
+    assert_eq!(read_file(&out), b"contents".to_vec());
+  }
+
+  #[test]
+  fn checkout_restores_hard_links() {
+    let (root, hat, data) = test_repository("hard-links");
+    fs::create_dir_all(&data).unwrap();
+    let in_data = |name: &str| { let mut p = data.clone(); p.push(name); p };
+    write_file(&in_data("first"), b"shared");
+    fs::hard_link(&in_data("first"), &in_data("second")).unwrap();
+
+    // A link whose other name is outside the snapshot:
+    let mut outside = root.clone();
+    outside.push("outside");
+    write_file(&outside, b"lone");
+    fs::hard_link(&outside, &in_data("lone")).unwrap();
+
+    let out = backup_and_checkout(&hat, &root, "fam", &data);
+    let in_out = |name: &str| { let mut p = out.clone(); p.push(name); p };
+    let first = fs::metadata(&in_out("first")).unwrap();
+    let second = fs::metadata(&in_out("second")).unwrap();
+    assert_eq!(first.ino(), second.ino());
+    assert_eq!(first.nlink(), 2);
+    assert_eq!(read_file(&in_out("second")), b"shared".to_vec());
+
+    let lone = fs::metadata(&in_out("lone")).unwrap();
+    assert_eq!(lone.nlink(), 1);
+    assert_eq!(read_file(&in_out("lone")), b"lone".to_vec());
+  }
+
+  #[test]
+  fn checkout_restores_mode_and_times() {
+    let (root, hat, data) = test_repository("mode-times");
+    fs::create_dir_all(&data).unwrap();
+    let mut file = data.clone();
+    file.push("file");
+    write_file(&file, b"contents");
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+    fs::set_file_times(&file, 1000000000000, 1234567890000).unwrap();
+
+    let mut out = backup_and_checkout(&hat, &root, "fam", &data);
+    out.push("file");
+    let metadata = fs::metadata(&out).unwrap();
+    assert_eq!(metadata.mode() & 0o7777, 0o640);
+    assert_eq!(metadata.mtime(), 1234567890);
+    assert_eq!(metadata.atime(), 1000000000);
+  }
+
+  #[test]
+  fn unreadable_file_fails_snapshot() {
+    // Root can read the file regardless of its mode:
+    if unsafe { libc::geteuid() } == 0 {
+      return;
+    }
+    let (root, hat, data) = test_repository("unreadable");
+    fs::create_dir_all(&data).unwrap();
+    let mut file = data.clone();
+    file.push("file");
+    write_file(&file, b"contents");
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o000)).unwrap();
+
+    // Committing the snapshot would lose the file's data:
+    match hat.backup("fam".to_string(), data.clone(), vec![], String::new()) {
+      Err(HatError::Repository(_)) => (),
+      r => panic!("Snapshot with an unreadable file was committed: {:?}", r),
+    }
+    assert_eq!(hat.list_snapshots(None).unwrap().len(), 0);
+
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o400)).unwrap();
+    let mut out = backup_and_checkout(&hat, &root, "fam", &data);
+    out.push("file");
+    let metadata = fs::symlink_metadata(&out).unwrap();
+    assert!(metadata.is_file());
+    assert_eq!(metadata.mode() & 0o7777, 0o400);
+    assert_eq!(read_file(&out), b"contents".to_vec());
+  }
+
+  #[test]
+  fn unreadable_directory_fails_snapshot() {
+    if unsafe { libc::geteuid() } == 0 {
+      return;
+    }
+    let (_root, hat, data) = test_repository("unreadable-dir");
+    let mut dir = data.clone();
+    dir.push("dir");
+    let mut file = dir.clone();
+    file.push("file");
+    write_file(&file, b"contents");
+    hat.backup("fam".to_string(), data.clone(), vec![], String::new()).unwrap();
+
+    // The directory's entries must not be dropped from the next snapshot:
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o000)).unwrap();
+    let res = hat.backup("fam".to_string(), data.clone(), vec![], String::new());
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+    match res {
+      Err(HatError::Repository(_)) => (),
+      r => panic!("Snapshot with an unreadable directory was committed: {:?}", r),
+    }
+    assert_eq!(hat.list_snapshots(None).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn checkout_restores_symlinks() {
+    let (root, hat, data) = test_repository("symlinks");
+    fs::create_dir_all(&data).unwrap();
+    let mut link = data.clone();
+    link.push("link");
+    // The target does not exist, so following the link would fail:
+    fs::soft_link(&PathBuf::from("../missing"), &link).unwrap();
+
+    let mut out = backup_and_checkout(&hat, &root, "fam", &data);
+    out.push("link");
+    assert_eq!(fs::symlink_metadata(&out).unwrap().mode() & 0o170000, 0o120000);
+    assert_eq!(fs::read_link(&out).unwrap(), PathBuf::from("../missing"));
+  }
+
+  #[test]
+  fn checkout_restores_fifos() {
+    let (root, hat, data) = test_repository("fifos");
+    fs::create_dir_all(&data).unwrap();
+    let mut fifo = data.clone();
+    fifo.push("fifo");
+    unix::make_node(&fifo, EntryKind::Fifo.special_mode().unwrap() | 0o644, 0).unwrap();
+
+    // Nothing writes to the FIFO, so reading it during the backup would block:
+    let mut out = backup_and_checkout(&hat, &root, "fam", &data);
+    out.push("fifo");
+    assert!(fs::symlink_metadata(&out).unwrap().file_type().is_fifo());
+  }
+
+  #[test]
+  fn checkout_restores_read_only_directories() {
+    let (root, hat, data) = test_repository("read-only");
+    let mut dir = data.clone();
+    dir.push("dir");
+    fs::create_dir_all(&dir).unwrap();
+    let mut file = dir.clone();
+    file.push("file");
+    write_file(&file, b"contents");
+    fs::set_file_times(&dir, 1000000000000, 1234567890000).unwrap();
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+
+    let mut out = backup_and_checkout(&hat, &root, "fam", &data);
+    out.push("dir");
+    let metadata = fs::metadata(&out).unwrap();
+    assert_eq!(metadata.mode() & 0o7777, 0o555);
+    assert_eq!(metadata.mtime(), 1234567890);
+    out.push("file");
+    assert_eq!(read_file(&out), b"contents".to_vec());
+  }
+
+  #[test]
+  fn init_completes_interrupted_init() {
+    let root = scratch_dir("reinit");
+    let mut repository_root = root.clone();
+    repository_root.push("repo");
+    // Initialisation that stopped before the manifest was written:
+    repository::create_layout(&repository_root, &Default::default()).unwrap();
+    assert!(repository::check(&repository_root).is_err());
+
+    init_repository(&repository_root, &Default::default()).unwrap();
+    assert_eq!(repository::check(&repository_root), Ok(repository::FORMAT_VERSION));
+    match init_repository(&repository_root, &Default::default()) {
+      Err(HatError::Repository(_)) => (),
+      r => panic!("Initialised repository was initialised again: {:?}", r),
+    }
+  }
+
+  #[test]
+  fn family_keeps_its_chunker() {
+    let root = scratch_dir("chunker");
+    let hat = new_repository(&root);
+    {
+      let family = hat.open_family_with_chunker("fam".to_string(), FixedSize::new(1024)).unwrap();
+      family.flush().unwrap();
+    }
+    match hat.open_family_with_chunker("fam".to_string(), ContentDefined::new(1024)) {
+      Err(HatError::Repository(_)) => (),
+      r => panic!("Family was opened with another chunker: {:?}", r.is_ok()),
+    }
+    hat.open_family_with_chunker("fam".to_string(), FixedSize::new(1024)).unwrap();
+  }
+
+  #[test]
+  fn family_names_stay_in_repository() {
+    let root = scratch_dir("family-names");
+    let hat = new_repository(&root);
+    for name in vec!["", ".", "..", "../x", "a/b", "config.json", "format.json",
+                     "hash_index.sqlite3", "blobs"].into_iter() {
+      match hat.open_family(name.to_string()) {
+        Err(HatError::Repository(_)) => (),
+        r => panic!("Family '{}' was opened: {:?}", name, r.is_ok()),
+      }
+    }
+    let mut data = root.clone();
+    data.push("data");
+    fs::create_dir_all(&data).unwrap();
+    assert!(hat.backup("../x".to_string(), data, vec![], String::new()).is_err());
+    assert!(fs::metadata(&concat_filename(&root, "x".to_string())).is_err());
+    hat.open_family("fam".to_string()).unwrap();
+  }
+
+  #[test]
+  fn checkout_only_absolute_path_stays_in_output() {
+    let (root, hat, data) = test_repository("only-absolute");
+    let mut sub = data.clone();
+    sub.push("sub");
+    fs::create_dir_all(&sub).unwrap();
+    sub.push("file");
+    write_file(&sub, b"contents");
+    hat.backup("fam".to_string(), data, vec![], String::new()).unwrap();
+
+    let mut out = root.clone();
+    out.push("out");
+    hat.checkout_in_dir("fam".to_string(), out.clone(), SnapshotSelector::Latest,
+                        Some(PathBuf::from("/sub/file")), false).unwrap();
+    out.push("sub");
+    out.push("file");
+    assert_eq!(read_file(&out), b"contents".to_vec());
+    assert!(fs::metadata("/sub/file").is_err());
+  }
+
+  #[test]
+  fn reading_leaves_families_alone() {
+    let root = scratch_dir("reading-families");
+    let hat = new_repository(&root);
+    let missing = "missing".to_string();
+    assert!(hat.list_path(&missing, &SnapshotSelector::Latest, Path::new(""), false).is_err());
+    match hat.commit(missing.clone(), vec![], String::new()) {
+      Err(HatError::Repository(_)) => (),
+      r => panic!("Missing family was committed: {:?}", r),
+    }
+    assert!(fs::metadata(&concat_filename(&hat.repository_root, missing)).is_err());
+
+    // Snapshots are read and committed whatever chunker they were taken with:
+    let mut data = root.clone();
+    data.push("data");
+    let mut file = data.clone();
+    file.push("file");
+    write_file(&file, b"contents");
+    {
+      let family = hat.open_family_with_chunker("fam".to_string(), FixedSize::new(4096)).unwrap();
+      family.snapshot_dir(data).unwrap();
+      family.flush().unwrap();
+    }
+    let fam = "fam".to_string();
+    hat.commit(fam.clone(), vec![], String::new()).unwrap();
+    let listing = hat.list_path(&fam, &SnapshotSelector::Latest, Path::new(""), false).unwrap();
+    assert_eq!(listing.len(), 1);
+  }
+
+  #[test]
+  fn snapshots_are_listed_per_family() {
+    let (_root, hat, data) = test_repository("list-snapshots");
+    let mut file = data.clone();
+    file.push("file");
+    write_file(&file, b"first");
+    hat.backup("fam".to_string(), data.clone(), vec![], String::new()).unwrap();
+    hat.backup("other".to_string(), data.clone(), vec![], String::new()).unwrap();
+    write_file(&file, b"second");
+    hat.backup("fam".to_string(), data, vec![], String::new()).unwrap();
+
+    let fam = hat.list_snapshots(Some("fam".to_string())).unwrap();
+    assert_eq!(fam.len(), 2);
+    assert!(fam.iter().all(|s| s.family == "fam".to_string() && s.created > 0));
+    assert!(fam[0].id < fam[1].id);
+    assert!(fam[0].hash != fam[1].hash);
+
+    let all = hat.list_snapshots(None).unwrap();
+    assert_eq!(all.iter().map(|s| s.family.clone()).collect::<Vec<String>>(),
+               vec!["fam".to_string(), "fam".to_string(), "other".to_string()]);
+    assert_eq!(hat.list_snapshots(Some("none".to_string())).unwrap().len(), 0);
+  }
+
+  #[test]
+  fn checkout_selects_snapshot() {
+    let (root, hat, data) = test_repository("checkout-selects");
+    let mut file = data.clone();
+    file.push("file");
+    write_file(&file, b"first");
+    hat.backup("fam".to_string(), data.clone(), vec![], String::new()).unwrap();
+    write_file(&file, b"second version");
+    hat.backup("fam".to_string(), data, vec![], String::new()).unwrap();
+    let snapshots = hat.list_snapshots(Some("fam".to_string())).unwrap();
+
+    let checkout = |name: &str, selector: SnapshotSelector| {
+      let mut out = root.clone();
+      out.push(name);
+      hat.checkout_in_dir("fam".to_string(), out.clone(), selector, None, false).map(|()| {
+        out.push("file");
+        read_file(&out)
+      })
+    };
+    assert_eq!(checkout("by-id", SnapshotSelector::Id(snapshots[0].id)).unwrap(),
+               b"first".to_vec());
+    assert_eq!(checkout("latest", SnapshotSelector::Latest).unwrap(), b"second version".to_vec());
+    assert_eq!(checkout("as-of", SnapshotSelector::AsOf(snapshots[1].created)).unwrap(),
+               b"second version".to_vec());
+    assert!(checkout("too-early", SnapshotSelector::AsOf(snapshots[0].created - 1)).is_err());
+    assert!(checkout("unknown-id", SnapshotSelector::Id(snapshots[1].id + 1)).is_err());
+  }
+
+  #[test]
+  fn ls_lists_paths_in_snapshot() {
+    let (_root, hat, data) = test_repository("ls");
+    let mut file = data.clone();
+    file.push("a/b/file");
+    write_file(&file, b"contents");
+    let mut top = data.clone();
+    top.push("top");
+    write_file(&top, b"top");
+    hat.backup("fam".to_string(), data, vec![], String::new()).unwrap();
+
+    let fam = "fam".to_string();
+    let ls = |path: &str, recursive: bool| {
+      hat.list_path(&fam, &SnapshotSelector::Latest, Path::new(path), recursive).map(|entries| {
+        let mut paths: Vec<PathBuf> = entries.into_iter().map(|(path, _)| path).collect();
+        paths.sort();
+        paths
+      })
+    };
+    assert_eq!(ls("", false).unwrap(), vec![PathBuf::from("a"), PathBuf::from("top")]);
+    assert_eq!(ls("", true).unwrap(), vec![PathBuf::from("a"), PathBuf::from("a/b"),
+                                           PathBuf::from("a/b/file"), PathBuf::from("top")]);
+    assert_eq!(ls("a", true).unwrap(), vec![PathBuf::from("b"), PathBuf::from("b/file")]);
+    assert!(ls("missing", false).is_err());
+
+    let entries = hat.list_path(&fam, &SnapshotSelector::Latest, Path::new("a/b/file"), false)
+      .unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].0, PathBuf::from("file"));
+    assert_eq!(entries[0].1.kind, EntryKind::File);
+    assert_eq!(entries[0].1.size, Some(8));
+  }
+
+  #[test]
+  fn cat_writes_file_contents() {
+    let (_root, hat, data) = test_repository("cat");
+    let contents = random_data(10000);
+    let mut big = data.clone();
+    big.push("dir/big");
+    write_file(&big, &contents[..]);
+    let mut empty = data.clone();
+    empty.push("empty");
+    write_file(&empty, b"");
+    hat.backup("fam".to_string(), data, vec![], String::new()).unwrap();
+
+    let fam = "fam".to_string();
+    let cat = |path: &str| {
+      let mut out = Vec::new();
+      hat.cat_file(&fam, &SnapshotSelector::Latest, Path::new(path), &mut out).map(|()| out)
+    };
+    assert_eq!(cat("dir/big").unwrap(), contents);
+    assert_eq!(cat("empty").unwrap(), Vec::<u8>::new());
+    match cat("dir") {
+      Err(HatError::Repository(_)) => (),
+      other => panic!("Expected a repository error, got {:?}", other),
+    }
+    assert!(cat("missing").is_err());
+  }
+
+  #[test]
+  fn backup_records_snapshot_metadata() {
+    let (_root, hat, data) = test_repository("backup");
+    let mut first = data.clone();
+    first.push("first");
+    write_file(&first, b"12345");
+    let mut second = data.clone();
+    second.push("sub/second");
+    write_file(&second, b"123");
+
+    let metadata = hat.backup("fam".to_string(), data.clone(),
+                              vec!["daily".to_string(), "home".to_string()],
+                              "first backup".to_string()).unwrap();
+    assert_eq!(metadata.file_count, 2);
+    assert_eq!(metadata.bytes, 8);
+    assert_eq!(metadata.tags, vec!["daily".to_string(), "home".to_string()]);
+    assert_eq!(metadata.message, "first backup".to_string());
+
+    let snapshots = hat.list_snapshots(Some("fam".to_string())).unwrap();
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].metadata, metadata);
+
+    // Backing up a file instead of a directory fails without adding a snapshot:
+    match hat.backup("fam".to_string(), first, vec![], String::new()) {
+      Err(HatError::Io(kind, _)) => assert_eq!(kind, io::ErrorKind::InvalidInput),
+      other => panic!("Expected an I/O error, got {:?}", other),
+    }
+    assert_eq!(hat.list_snapshots(None).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn diff_reports_changes_between_snapshots() {
+    let (_root, hat, data) = test_repository("diff");
+    let path = |name: &str| {
+      let mut path = data.clone();
+      path.push(name);
+      path
+    };
+    write_file(&path("changed"), b"old");
+    write_file(&path("chmod"), b"same");
+    write_file(&path("removed"), b"removed");
+    write_file(&path("same"), b"same");
+    write_file(&path("sub/x"), b"x");
+    hat.backup("fam".to_string(), data.clone(), vec![], String::new()).unwrap();
+
+    write_file(&path("changed"), b"new contents");
+    fs::set_permissions(&path("chmod"), fs::Permissions::from_mode(0o600)).unwrap();
+    write_file(&path("new"), b"new");
+    fs::remove_file(&path("removed")).unwrap();
+    hat.backup("fam".to_string(), data.clone(), vec![], String::new()).unwrap();
+
+    let snapshots = hat.list_snapshots(Some("fam".to_string())).unwrap();
+    let fam = "fam".to_string();
+    let differences = hat.diff(&fam, &SnapshotSelector::Id(snapshots[0].id),
+                               &SnapshotSelector::Latest).unwrap();
+    let difference = |path: &str, change: Change| {
+      Difference{path: PathBuf::from(path), change: change}
+    };
+    assert_eq!(differences, vec![difference("changed", Change::Modified),
+                                 difference("chmod", Change::MetadataChanged),
+                                 difference("new", Change::Added),
+                                 difference("removed", Change::Removed)]);
+
+    // The reverse direction swaps additions and removals:
+    let reverse = hat.diff(&fam, &SnapshotSelector::Latest,
+                           &SnapshotSelector::Id(snapshots[0].id)).unwrap();
+    assert_eq!(reverse.iter().map(|d| d.change).collect::<Vec<_>>(),
+               vec![Change::Modified, Change::MetadataChanged, Change::Removed, Change::Added]);
+
+    assert_eq!(hat.diff(&fam, &SnapshotSelector::Latest, &SnapshotSelector::Latest).unwrap(),
+               vec![]);
+  }
+
+  #[test]
+  fn verify_reports_missing_blobs() {
+    let (_root, hat, data) = test_repository("verify");
+    let mut file = data.clone();
+    file.push("file");
+    write_file(&file, &random_data(5000)[..]);
+    hat.backup("fam".to_string(), data, vec![], String::new()).unwrap();
+
+    let report = hat.verify(None, true).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.snapshots, 1);
+    assert!(report.chunks > 0);
+
+    for blob in committed_blobs(&hat).into_iter() {
+      hat.blob_backend.clone().delete(&blob.name[..]).unwrap();
+    }
+    let report = hat.verify(Some("fam".to_string()), false).unwrap();
+    assert!(!report.is_ok());
+    assert!(report.damage.iter().any(|entry| match entry.damage {
+      Damage::MissingBlob{..} => true,
+      _ => false,
+    }));
+  }
 }
@@ -21,10 +21,16 @@ use process::{Process, MsgHandler};
 use sqlite3::database::{Database};
 
 use sqlite3::cursor::{Cursor};
-use sqlite3::types::ResultCode::{SQLITE_ROW, SQLITE_DONE};
+use sqlite3::types::ResultCode::{SQLITE_ROW};
 use sqlite3::{open};
 
 use rustc_serialize::hex::{ToHex};
+use rustc_serialize::json;
+use std::collections::{BTreeMap};
+use std::str;
+
+use errors::{HatError, HatResult};
+use tree_entry::{EntryKind, XAttrs, xattrs_from_json, xattrs_to_json};
 
 
 pub trait KeyEntry<KE> {
@@ -42,30 +48,134 @@ pub trait KeyEntry<KE> {
   fn user_id(&self) -> Option<u64>;
   fn group_id(&self) -> Option<u64>;
 
+  /// Target of a symlink; `None` for anything else.
+  fn symlink_target(&self) -> Option<Vec<u8>>;
+
+  /// Modification and status change times in nanoseconds. Together with the size and inode,
+  /// these decide whether an entry has changed since it was inserted (see `LookupExact`).
+  fn modified_ns(&self) -> Option<u64>;
+  fn changed_ns(&self) -> Option<u64>;
+
+  /// Device and inode number.
+  fn inode(&self) -> Option<(u64, u64)>;
+
+  /// Number of hard links to a regular file; `None` for anything else.
+  fn link_count(&self) -> Option<u64>;
+
+  /// Extended attributes, including POSIX ACLs.
+  fn xattrs(&self) -> XAttrs;
+
+  /// Device number of a character or block device; `None` for anything else.
+  fn device(&self) -> Option<u64>;
+
   fn with_id(&self, u64) -> KE;
 }
 
 pub type KeyIndexProcess<KE> = Process<Msg<KE>, Reply>;
 
+
+/// Columns added to the key index after its first version, with their types. Entries of key
+/// indexes created before belong to the current snapshot run, and are read again by the next one
+/// (as their sizes and change times are unknown).
+static ADDED_COLUMNS: [(&'static str, &'static str); 13] = [
+  ("size", "UINT8"), ("permissions", "UINT8"), ("user_id", "UINT8"), ("group_id", "UINT8"),
+  ("symlink_target", "BLOB"), ("device", "UINT8"), ("inode", "UINT8"), ("link_count", "UINT8"),
+  ("modified_ns", "UINT8"), ("changed_ns", "UINT8"), ("xattrs", "BLOB"),
+  ("device_number", "UINT8"), ("generation", "UINT8 DEFAULT 0")];
+
+/// Info key holding the last snapshot run that went through its whole directory tree.
+static FINISHED_GENERATION: &'static str = "complete_generation";
+
+/// Info key holding the snapshot run in progress. A run need not leave any entries behind (e.g.
+/// when its directory is empty), so the run can not be told from the entries alone.
+static CURRENT_GENERATION: &'static str = "current_generation";
+
+/// An entry as stored in the key index.
+#[derive(Clone, Debug)]
+pub struct IndexEntry {
+  pub id: u64,
+  pub name: Vec<u8>,
+  pub size: u64,
+
+  pub created: u64,
+  pub modified: u64,
+  pub accessed: u64,
+
+  pub permissions: Option<u64>,
+  pub user_id: Option<u64>,
+  pub group_id: Option<u64>,
+
+  pub symlink_target: Option<Vec<u8>>,
+
+  /// Device and inode of a file with more than one hard link.
+  pub hard_link: Option<(u64, u64)>,
+
+  pub xattrs: XAttrs,
+  pub device: Option<u64>,
+
+  /// Top hash and persistent reference of the entry's data; empty if there is no data.
+  pub hash: Vec<u8>,
+  pub persistent_ref: Vec<u8>,
+}
+
+/// Any message is answered with `Error` instead when the index cannot be read or written.
 pub enum Msg<KeyEntryT> {
 
   /// Insert an entry in the key index.
   /// Returns `Id` with the new entry ID.
   Insert(KeyEntryT),
 
-  /// Lookup an entry in the key index, to see if it exists.
+  /// Lookup an entry in the key index, to see if it exists unchanged. An entry is unchanged if its
+  /// size, modification time, status change time, device and inode are the same; the access time
+  /// is ignored, as reading a file (e.g. to back it up) changes it. Any change to the permissions,
+  /// owner, extended attributes or links of a file updates its status change time. An entry that
+  /// has data (the flag) is only found once its data hash is stored, so that a file is read again
+  /// if the run that inserted it was interrupted before its data was stored.
+  ///
+  /// A directory is found by its name and inode instead, as adding or removing its entries changes
+  /// its times. Its metadata is updated in place, and it keeps its ID so that the unchanged entries
+  /// under it are found again.
   /// Returns either `Id` with the found entry ID or `Notfound`.
-  LookupExact(KeyEntryT),
+  LookupExact(KeyEntryT, bool),
 
   /// Update the `payload` and `persistent_ref` of an entry.
   /// Returns `UpdateOK`.
   UpdateDataHash(KeyEntryT, Option<Vec<u8>>, Option<Vec<u8>>),
 
   /// List a directory (aka. `level`) in the index.
-  /// Returns `ListResult` with all the entries under the given parent.
+  /// Returns `ListResult` with the entries under the given parent that were inserted or found by
+  /// `LookupExact` since the last `NewGeneration` (i.e. that still exist).
   ListDir(Option<u64>),
 
+  /// Start a new snapshot run. Entries that are not inserted or found again are left out of
+  /// listings from now on, and entries that were not seen in the previous run are removed.
+  /// Returns `UpdateOK`.
+  NewGeneration,
+
+  /// Record that the snapshot run in progress has inserted or found every entry, so that its
+  /// listings are complete.
+  /// Returns `UpdateOK`.
+  FinishGeneration,
+
+  /// Check whether the snapshot run in progress was finished with `FinishGeneration`.
+  /// Returns `Finished`.
+  IsFinished,
+
+  /// Store a named value describing the snapshot in progress (e.g. its source directory).
+  /// Values are raw bytes, as paths need not be valid UTF-8.
+  /// Returns `UpdateOK`.
+  SetInfo(String, Vec<u8>),
+
+  /// Look up a value stored with `SetInfo`.
+  /// Returns `Info`.
+  GetInfo(String),
+
+  /// List the top hashes of all entries with data.
+  /// Returns `Hashes`.
+  ListHashes,
+
   /// Flush this key index.
+  /// Returns `FlushOK`, or `Error` if this or an earlier unanswered `UpdateDataHash` failed.
   Flush,
 }
 
@@ -73,8 +183,12 @@ pub enum Reply {
   Id(u64),
   NotFound,
   UpdateOK,
-  ListResult(Vec<(u64, Vec<u8>, u64, u64, u64, Vec<u8>, Vec<u8>)>),
+  ListResult(Vec<IndexEntry>),
+  Hashes(Vec<Vec<u8>>),
+  Info(Option<Vec<u8>>),
+  Finished(bool),
   FlushOK,
+  Error(HatError),
 }
 
 
@@ -82,88 +196,412 @@ pub struct KeyIndex {
   path: String,
   dbh: Database,
   flush_timer: PeriodicTimer,
+
+  /// The snapshot run in progress; entries are marked with the run that last saw them.
+  generation: u64,
+
+  /// An error that could not be replied to when it happened (e.g. from an `UpdateDataHash` sent
+  /// by a hash index callback); it is reported by the next `Flush`.
+  error: Option<HatError>,
 }
 
 
-fn i64_to_u64_or_panic(x: i64) -> u64 {
+fn u64_from_i64(x: i64) -> HatResult<u64> {
   if x < 0 {
-    panic!("expected u64, got {:?}", x);
+    return Err(HatError::CorruptData(format!("Key index: expected an unsigned number, got {}",
+                                             x)));
+  }
+  Ok(x as u64)
+}
+
+/// Format an optional value for use in SQL, with `None` as `NULL`.
+fn sql_opt(x: Option<u64>) -> String {
+  x.map(|v| v.to_string()).unwrap_or("NULL".to_string())
+}
+
+/// Format optional bytes for use in SQL, with `None` as `NULL`.
+fn sql_opt_blob(x: Option<Vec<u8>>) -> String {
+  x.map(|v| format!("x'{}'", v.to_hex())).unwrap_or("NULL".to_string())
+}
+
+/// Format extended attributes for use in SQL, with no attributes as `NULL`.
+fn sql_xattrs(xattrs: &XAttrs) -> String {
+  if xattrs.is_empty() {
+    "NULL".to_string()
+  } else {
+    sql_opt_blob(Some(xattrs_to_json(xattrs).to_string().into_bytes()))
   }
-  return x as u64
+}
+
+/// Read back extended attributes stored by `sql_xattrs`.
+fn xattrs_from_blob(blob: Option<&[u8]>) -> XAttrs {
+  blob.and_then(|b| str::from_utf8(b).ok())
+    .and_then(|s| json::Json::from_str(s).ok())
+    .and_then(|j| xattrs_from_json(&j))
+    .unwrap_or(BTreeMap::new())
+}
+
+/// The metadata columns of `entry` (everything but its parent, name, run and data), with their
+/// values formatted for use in SQL.
+fn metadata_columns<A: KeyEntry<A>>(entry: &A) -> Vec<(&'static str, String)> {
+  vec![("size", entry.size().unwrap_or(0).to_string()),
+       ("created", entry.created().unwrap_or(0).to_string()),
+       ("modified", entry.modified().unwrap_or(0).to_string()),
+       ("accessed", entry.accessed().unwrap_or(0).to_string()),
+       ("permissions", sql_opt(entry.permissions())),
+       ("user_id", sql_opt(entry.user_id())),
+       ("group_id", sql_opt(entry.group_id())),
+       ("symlink_target", sql_opt_blob(entry.symlink_target())),
+       ("device", sql_opt(entry.inode().map(|(dev, _)| dev))),
+       ("inode", sql_opt(entry.inode().map(|(_, ino)| ino))),
+       ("link_count", sql_opt(entry.link_count())),
+       ("modified_ns", sql_opt(entry.modified_ns())),
+       ("changed_ns", sql_opt(entry.changed_ns())),
+       ("xattrs", sql_xattrs(&entry.xattrs())),
+       ("device_number", sql_opt(entry.device()))]
+}
+
+/// Read back an optional value selected as `IFNULL(column, -1)`.
+fn opt_from_i64(x: i64) -> Option<u64> {
+  if x < 0 { None } else { Some(x as u64) }
 }
 
 
 impl KeyIndex {
-  pub fn new(path: String) -> KeyIndex {
+  pub fn new(path: String) -> HatResult<KeyIndex> {
     let mut ki = match open(&path) {
       Ok(dbh) => {
         KeyIndex{path: path,
                  dbh: dbh,
-                 flush_timer: PeriodicTimer::new(Duration::seconds(5))}
+                 flush_timer: PeriodicTimer::new(Duration::seconds(5)),
+                 generation: 0,
+                 error: None}
       },
-      Err(err) => panic!("{:?}", err),
+      Err(err) => return Err(HatError::index(format!("Could not open key index {}: {:?}",
+                                                  path, err))),
     };
-    ki.exec_or_die("CREATE TABLE IF NOT EXISTS
-                    key_index (rowid          INTEGER PRIMARY KEY,
-                               parent         INTEGER,
-                               name           BLOB,
-                               created        UINT8,
-                               modified       UINT8,
-                               accessed       UINT8,
-                               hash           BLOB,
-                               persistent_ref BLOB
-                            );");
+    try!(ki.exec("CREATE TABLE IF NOT EXISTS
+                  key_index (rowid          INTEGER PRIMARY KEY,
+                             parent         INTEGER,
+                             name           BLOB,
+                             size           UINT8,
+                             created        UINT8,
+                             modified       UINT8,
+                             accessed       UINT8,
+                             permissions    UINT8,
+                             user_id        UINT8,
+                             group_id       UINT8,
+                             symlink_target BLOB,
+                             device         UINT8,
+                             inode          UINT8,
+                             link_count     UINT8,
+                             modified_ns    UINT8,
+                             changed_ns     UINT8,
+                             xattrs         BLOB,
+                             device_number  UINT8,
+                             generation     UINT8,
+                             hash           BLOB,
+                             persistent_ref BLOB
+                          );"));
+    try!(ki.add_missing_columns());
+
+    try!(ki.exec("CREATE TABLE IF NOT EXISTS
+                  key_index_info (key   BLOB PRIMARY KEY,
+                                  value BLOB);"));
 
     if cfg!(test) {
-      ki.exec_or_die("CREATE UNIQUE INDEX IF NOT EXISTS
-                      KeyIndex_UniqueParentName
-                      ON key_index(parent, name)");
+      try!(ki.exec("CREATE UNIQUE INDEX IF NOT EXISTS
+                    KeyIndex_UniqueParentName
+                    ON key_index(parent, name)"));
     }
 
-    ki.exec_or_die("BEGIN");
-    ki
+    // Key indexes written before the run was recorded are at the run of their newest entries:
+    let current = {
+      let mut cursor = try!(ki.prepare(&format!(
+        "SELECT IFNULL((SELECT value FROM key_index_info WHERE key=x'{}'),
+                       (SELECT IFNULL(MAX(generation), 0) FROM key_index))",
+        CURRENT_GENERATION.as_bytes().to_hex())));
+      if cursor.step() == SQLITE_ROW { Some(cursor.get_i64(0)) } else { None }
+    };
+    ki.generation = match current {
+      Some(generation) => try!(u64_from_i64(generation)),
+      None => return Err(HatError::index(format!("Key index {}: {}",
+                                                 ki.path, ki.dbh.get_errmsg()))),
+    };
+
+    try!(ki.exec("BEGIN"));
+    Ok(ki)
   }
 
   #[cfg(test)]
   pub fn new_for_testing() -> KeyIndex {
-    KeyIndex::new(":memory:".to_string())
+    KeyIndex::new(":memory:".to_string()).unwrap()
   }
 
-  fn exec_or_die(&mut self, sql: &str) {
+  fn exec(&mut self, sql: &str) -> HatResult<()> {
     match self.dbh.exec(sql) {
-      Ok(true) => (),
-      Ok(false) => panic!("exec: {:?}, {}", self.dbh.get_errmsg(), sql),
-      Err(msg) => panic!("exec: {:?}, {:?}, {}", msg, self.dbh.get_errmsg(), sql),
+      Ok(true) => Ok(()),
+      Ok(false) => Err(HatError::index(format!("Key index {}: {}, in '{}'",
+                                            self.path, self.dbh.get_errmsg(), sql))),
+      Err(msg) => Err(HatError::index(format!("Key index {}: {} ({:?}), in '{}'",
+                                           self.path, self.dbh.get_errmsg(), msg, sql))),
     }
   }
 
-  fn prepare_or_die<'a>(&'a mut self, sql: &str) -> Cursor<'a> {
+  fn prepare<'a>(&'a mut self, sql: &str) -> HatResult<Cursor<'a>> {
     match self.dbh.prepare(sql, &None) {
-      Ok(s)  => s,
-      Err(x) => panic!("sqlite error: {} ({:?})", self.dbh.get_errmsg(), x),
+      Ok(s)  => Ok(s),
+      Err(x) => Err(HatError::index(format!("Key index {}: {} ({:?})",
+                                         self.path, self.dbh.get_errmsg(), x))),
     }
   }
 
-  pub fn maybe_flush(&mut self) {
+  /// Add the columns that key indexes created by older versions lack.
+  fn add_missing_columns(&mut self) -> HatResult<()> {
+    let existing = {
+      let mut cursor = try!(self.prepare("PRAGMA table_info(key_index)"));
+      let mut names = Vec::new();
+      while cursor.step() == SQLITE_ROW {
+        names.push(String::from_utf8_lossy(cursor.get_blob(1).unwrap_or(&[])).into_owned());
+      }
+      names
+    };
+    for &(name, column_type) in ADDED_COLUMNS.iter() {
+      if !existing.iter().any(|c| &c[..] == name) {
+        try!(self.exec(&format!("ALTER TABLE key_index ADD COLUMN {} {}", name, column_type)));
+      }
+    }
+    Ok(())
+  }
+
+  pub fn maybe_flush(&mut self) -> HatResult<()> {
     if self.flush_timer.did_fire() {
-      self.flush();
+      try!(self.flush());
     }
+    Ok(())
   }
 
-  pub fn flush(&mut self) {
-    self.exec_or_die("COMMIT; BEGIN");
+  pub fn flush(&mut self) -> HatResult<()> {
+    self.exec("COMMIT; BEGIN")
+  }
+
+  fn insert<A: KeyEntry<A>>(&mut self, entry: A) -> HatResult<u64> {
+    let parent = entry.parent_id().unwrap_or(0);
+    let (names, values): (Vec<&str>, Vec<String>) = metadata_columns(&entry).into_iter().unzip();
+
+    try!(self.exec(&format!(
+      "INSERT OR REPLACE INTO key_index (parent, name, {}, generation)
+       VALUES ({:?}, x'{}', {}, {})",
+      names.connect(", "), parent, entry.name().to_hex(), values.connect(", "),
+      self.generation)));
+
+    u64_from_i64(self.dbh.get_last_insert_rowid())
+  }
+
+  /// Find the directory `entry` by its parent, name and inode, and update its metadata.
+  fn lookup_directory<A: KeyEntry<A>>(&mut self, entry: A) -> HatResult<Option<u64>> {
+    let parent = entry.parent_id().unwrap_or(0);
+    // Directories have no data, and their mode has the file type 0o040000 (under mask 0o170000):
+    let found = {
+      let mut cursor = try!(self.prepare(&format!(
+        "SELECT rowid FROM key_index
+         WHERE parent={:?} AND name=x'{}'
+         AND IFNULL(device, -1)={} AND IFNULL(inode, -1)={}
+         AND ((permissions IS NULL AND hash IS NULL) OR (permissions & 61440)=16384)
+         LIMIT 1",
+        parent, entry.name().to_hex(),
+        entry.inode().map(|(dev, _)| dev as i64).unwrap_or(-1),
+        entry.inode().map(|(_, ino)| ino as i64).unwrap_or(-1))));
+      if cursor.step() == SQLITE_ROW {
+        Some(try!(u64_from_i64(cursor.get_i64(0))))
+      } else {
+        None
+      }
+    };
+    if let Some(id) = found {
+      let assignments: Vec<String> = metadata_columns(&entry).into_iter()
+        .map(|(name, value)| format!("{}={}", name, value)).collect();
+      try!(self.exec(&format!("UPDATE key_index SET {}, generation={} WHERE rowid={}",
+                              assignments.connect(", "), self.generation, id)));
+    }
+    Ok(found)
+  }
+
+  fn lookup_exact<A: KeyEntry<A>>(&mut self, entry: A, has_data: bool)
+                                  -> HatResult<Option<u64>> {
+    if EntryKind::is_directory_mode(entry.permissions(), has_data) {
+      return self.lookup_directory(entry);
+    }
+    let parent = entry.parent_id().unwrap_or(0);
+    let found = {
+      let mut cursor = try!(self.prepare(&format!(
+        "SELECT rowid FROM key_index
+         WHERE parent={:?} AND name=x'{}' AND size={}
+         AND IFNULL(modified_ns, -1)={} AND IFNULL(changed_ns, -1)={}
+         AND IFNULL(device, -1)={} AND IFNULL(inode, -1)={} {}
+         LIMIT 1",
+        parent, entry.name().to_hex(),
+        entry.size().unwrap_or(0),
+        entry.modified_ns().map(|x| x as i64).unwrap_or(-1),
+        entry.changed_ns().map(|x| x as i64).unwrap_or(-1),
+        entry.inode().map(|(dev, _)| dev as i64).unwrap_or(-1),
+        entry.inode().map(|(_, ino)| ino as i64).unwrap_or(-1),
+        if has_data { "AND hash IS NOT NULL" } else { "" })));
+      if cursor.step() == SQLITE_ROW {
+        Some(try!(u64_from_i64(cursor.get_i64(0))))
+      } else {
+        None
+      }
+    };
+    if let Some(id) = found {
+      // The entry still exists, so it belongs to this run:
+      try!(self.exec(&format!("UPDATE key_index SET generation={} WHERE rowid={}",
+                              self.generation, id)));
+    }
+    Ok(found)
+  }
+
+  fn update_data_hash<A: KeyEntry<A>>(&mut self, entry: A, hash_opt: Option<Vec<u8>>,
+                                      persistent_ref_opt: Option<Vec<u8>>) -> HatResult<()> {
+    let parent = entry.parent_id().unwrap_or(0);
+    let id = match entry.id() {
+      Some(id) => id,
+      None => return Err(HatError::CorruptData(format!(
+        "Data hash for '{}', which is not in the key index",
+        String::from_utf8_lossy(&entry.name()[..])))),
+    };
+    let (hash, persistent_ref) = match (hash_opt, persistent_ref_opt) {
+      (Some(hash), Some(persistent_ref)) =>
+        (format!("x'{}'", hash.to_hex()), format!("x'{}'", persistent_ref.to_hex())),
+      (None, None) => ("NULL".to_string(), "NULL".to_string()),
+      _ => return Err(HatError::CorruptData(format!(
+        "Data hash of '{}' without its reference, or the other way around",
+        String::from_utf8_lossy(&entry.name()[..])))),
+    };
+
+    match entry.modified() {
+      Some(modified) => {
+        try!(self.exec(&format!(
+          "UPDATE key_index SET hash={}, persistent_ref={}, modified={}
+           WHERE parent={:?} AND rowid={:?} AND IFNULL(modified, 0)<={}",
+          hash, persistent_ref, modified, parent, id, modified)));
+      },
+      None => {
+        try!(self.exec(&format!(
+          "UPDATE key_index SET hash={}, persistent_ref={}
+           WHERE parent={:?} AND rowid={:?}",
+          hash, persistent_ref, parent, id)));
+      }
+    }
+
+    self.maybe_flush()
+  }
+
+  fn new_generation(&mut self) -> HatResult<()> {
+    // Entries that the last run did not see belong to removed files; the entries of the last
+    // run are kept for detecting unchanged files in the new one:
+    try!(self.exec(&format!("DELETE FROM key_index WHERE IFNULL(generation, 0)<{}",
+                            self.generation)));
+    self.generation += 1;
+    let generation = self.generation;
+    self.exec(&format!(
+      "INSERT OR REPLACE INTO key_index_info (key, value) VALUES (x'{}', {})",
+      CURRENT_GENERATION.as_bytes().to_hex(), generation))
+  }
+
+  fn finish_generation(&mut self) -> HatResult<()> {
+    let generation = self.generation;
+    self.exec(&format!(
+      "INSERT OR REPLACE INTO key_index_info (key, value) VALUES (x'{}', {})",
+      FINISHED_GENERATION.as_bytes().to_hex(), generation))
+  }
+
+  fn is_finished(&mut self) -> HatResult<bool> {
+    let generation = self.generation;
+    let mut cursor = try!(self.prepare(&format!(
+      "SELECT value FROM key_index_info WHERE key=x'{}'",
+      FINISHED_GENERATION.as_bytes().to_hex())));
+    Ok(cursor.step() == SQLITE_ROW && cursor.get_i64(0) == generation as i64)
+  }
+
+  fn list_dir(&mut self, parent_opt: Option<u64>) -> HatResult<Vec<IndexEntry>> {
+    let mut listing = Vec::new();
+    let parent = parent_opt.unwrap_or(0);
+    let generation = self.generation;
+
+    let mut cursor = try!(self.prepare(&format!(
+       "SELECT rowid, name, size, created, modified, accessed,
+               IFNULL(permissions, -1), IFNULL(user_id, -1), IFNULL(group_id, -1),
+               symlink_target, IFNULL(device, -1), IFNULL(inode, -1), IFNULL(link_count, 0),
+               xattrs,
+               IFNULL(device_number, -1), hash, persistent_ref
+        FROM key_index
+        WHERE parent={:?} AND generation={}", parent, generation)));
+
+    while cursor.step() == SQLITE_ROW {
+      let id = try!(u64_from_i64(cursor.get_i64(0)));
+      let name = match cursor.get_blob(1) {
+        Some(name) => name.to_vec(),
+        None => return Err(HatError::CorruptData(format!("Key index entry {} has no name", id))),
+      };
+      listing.push(IndexEntry{
+        id: id,
+        name: name,
+        size: cursor.get_i64(2) as u64,
+        created: cursor.get_i64(3) as u64,
+        modified: cursor.get_i64(4) as u64,
+        accessed: cursor.get_i64(5) as u64,
+        permissions: opt_from_i64(cursor.get_i64(6)),
+        user_id: opt_from_i64(cursor.get_i64(7)),
+        group_id: opt_from_i64(cursor.get_i64(8)),
+        symlink_target: cursor.get_blob(9).map(|b| b.to_vec()),
+        hard_link: if cursor.get_i64(12) > 1 {
+          opt_from_i64(cursor.get_i64(10)).and_then(|dev| {
+            opt_from_i64(cursor.get_i64(11)).map(|ino| (dev, ino))
+          })
+        } else { None },
+        xattrs: xattrs_from_blob(cursor.get_blob(13)),
+        device: opt_from_i64(cursor.get_i64(14)),
+        hash: cursor.get_blob(15).unwrap_or(&[]).iter().map(|&x| x).collect(),
+        persistent_ref: cursor.get_blob(16).unwrap_or(&[]).iter().map(|&x| x).collect(),
+      });
+    }
+
+    Ok(listing)
+  }
+
+  fn set_info(&mut self, key: String, value: Vec<u8>) -> HatResult<()> {
+    self.exec(&format!(
+      "INSERT OR REPLACE INTO key_index_info (key, value) VALUES (x'{}', x'{}')",
+      key.as_bytes().to_hex(), value.to_hex()))
   }
-}
 
-impl Clone for KeyIndex {
-  fn clone(&self) -> KeyIndex {
-    KeyIndex::new(self.path.clone())
+  fn get_info(&mut self, key: String) -> HatResult<Option<Vec<u8>>> {
+    let mut cursor = try!(self.prepare(&format!(
+      "SELECT value FROM key_index_info WHERE key=x'{}'", key.as_bytes().to_hex())));
+    if cursor.step() == SQLITE_ROW {
+      Ok(Some(cursor.get_blob(0).unwrap_or(&[]).to_vec()))
+    } else {
+      Ok(None)
+    }
+  }
+
+  fn list_hashes(&mut self) -> HatResult<Vec<Vec<u8>>> {
+    let mut hashes = Vec::new();
+    let mut cursor = try!(self.prepare(
+      "SELECT DISTINCT hash FROM key_index WHERE hash IS NOT NULL"));
+    while cursor.step() == SQLITE_ROW {
+      hashes.push(cursor.get_blob(0).unwrap_or(&[]).to_vec());
+    }
+    Ok(hashes)
   }
 }
 
 impl Drop for KeyIndex {
   fn drop(&mut self) {
-    self.exec_or_die("COMMIT");
+    // Nothing can be reported from here; callers flush first to learn whether their changes were
+    // stored.
+    self.exec("COMMIT").ok();
   }
 }
 
@@ -172,117 +610,88 @@ impl <A: KeyEntry<A>> MsgHandler<Msg<A>, Reply> for KeyIndex {
     match msg {
 
       Msg::Insert(entry) => {
-        let parent = entry.parent_id().unwrap_or(0);
-
-        self.exec_or_die(&format!(
-          "INSERT OR REPLACE INTO key_index (parent, name, created, modified, accessed)
-           VALUES ({:?}, x'{}', {}, {}, {})",
-          parent, entry.name().to_hex(),
-          entry.created().unwrap_or(0),
-          entry.modified().unwrap_or(0),
-          entry.accessed().unwrap_or(0)));
-
-        let id = self.dbh.get_last_insert_rowid();
-        return reply(Reply::Id(i64_to_u64_or_panic(id)));
+        match self.insert(entry) {
+          Ok(id) => return reply(Reply::Id(id)),
+          Err(e) => return reply(Reply::Error(e)),
+        }
       },
 
-      Msg::LookupExact(entry) => {
-        let parent = entry.parent_id().unwrap_or(0);
-        let mut cursor = self.prepare_or_die(&format!(
-          "SELECT rowid FROM key_index
-           WHERE parent={:?} AND name=x'{}'
-           AND created={} AND modified={} AND accessed={}
-           LIMIT 1",
-          parent, entry.name().to_hex(),
-          entry.created().unwrap_or(0),
-          entry.modified().unwrap_or(0),
-          entry.accessed().unwrap_or(0)));
-        if cursor.step() == SQLITE_ROW {
-          assert!(cursor.step() == SQLITE_DONE);
-          return reply(Reply::Id((i64_to_u64_or_panic(cursor.get_i64(0)))));
-        } else {
-          return reply(Reply::NotFound);
+      Msg::LookupExact(entry, has_data) => {
+        match self.lookup_exact(entry, has_data) {
+          Ok(Some(id)) => return reply(Reply::Id(id)),
+          Ok(None) => return reply(Reply::NotFound),
+          Err(e) => return reply(Reply::Error(e)),
         }
       },
 
       Msg::UpdateDataHash(entry, hash_opt, persistent_ref_opt) => {
-        let parent = entry.parent_id().unwrap_or(0);
-
-        assert!(hash_opt.is_some() == persistent_ref_opt.is_some());
-
-        if hash_opt.is_some() && persistent_ref_opt.is_some() {
-          match entry.modified() {
-            Some(modified) => {
-              self.exec_or_die(&format!(
-                "UPDATE key_index SET hash=x'{}', persistent_ref=x'{}'
-                                                  , modified={}
-                  WHERE parent={:?} AND rowid={:?} AND IFNULL(modified,0)<={}",
-                hash_opt.unwrap().to_hex(),
-                persistent_ref_opt.unwrap().to_hex(),
-                modified, parent, entry.id().expect("UpdateDataHash"),
-                modified));
-            },
-            None => {
-              self.exec_or_die(&format!(
-                "UPDATE key_index SET hash=x'{}', persistent_ref=x'{}'
-                 WHERE parent={:?} AND rowid={:?}",
-                hash_opt.unwrap().to_hex(),
-                persistent_ref_opt.unwrap().to_hex(),
-                parent, entry.id().expect("UpdateDataHash, None")));
-            }
-          }
-        } else {
-          match entry.modified() {
-            Some(modified) => {
-              self.exec_or_die(&format!(
-                "UPDATE key_index SET hash=NULL, persistent_ref=NULL
-                                               , modified={}
-                 WHERE parent={:?} AND rowid={:?} AND IFNULL(modified, 0)<={}",
-                modified, parent, entry.id().expect("UpdateDataHash2"), modified));
-            },
-            None => {
-              self.exec_or_die(&format!(
-                "UPDATE key_index SET hash=NULL, persistent_ref=NULL
-                 WHERE parent={:?} AND rowid={}",
-                parent, entry.id().expect("UpdateDataHash2, None")));
-            }
-          }
+        match self.update_data_hash(entry, hash_opt, persistent_ref_opt) {
+          Ok(()) => return reply(Reply::UpdateOK),
+          Err(e) => {
+            // Data hashes are updated from hash index callbacks, which do not look at the reply:
+            self.error = Some(e.clone());
+            return reply(Reply::Error(e));
+          },
         }
-
-        self.maybe_flush();
-        return reply(Reply::UpdateOK);
       },
 
       Msg::Flush => {
-        self.flush();
-        return reply(Reply::FlushOK);
+        if let Err(e) = self.flush() {
+          return reply(Reply::Error(e));
+        }
+        match self.error.take() {
+          None => return reply(Reply::FlushOK),
+          Some(e) => return reply(Reply::Error(e)),
+        }
+      },
+
+      Msg::NewGeneration => {
+        match self.new_generation() {
+          Ok(()) => return reply(Reply::UpdateOK),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
+
+      Msg::FinishGeneration => {
+        match self.finish_generation() {
+          Ok(()) => return reply(Reply::UpdateOK),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
+
+      Msg::IsFinished => {
+        match self.is_finished() {
+          Ok(finished) => return reply(Reply::Finished(finished)),
+          Err(e) => return reply(Reply::Error(e)),
+        }
       },
 
       Msg::ListDir(parent_opt) => {
-        let mut listing = Vec::new();
-        let parent = parent_opt.unwrap_or(0);
-
-        let mut cursor = self.prepare_or_die(&format!(
-           "SELECT rowid, name, created, modified, accessed, hash, persistent_ref
-            FROM key_index
-            WHERE parent={:?}", parent));
-
-        // TODO(jos): replace get_int with something that understands usize64
-        while cursor.step() == SQLITE_ROW {
-          let id = i64_to_u64_or_panic(cursor.get_i64(0));
-          let name = cursor.get_blob(1).expect("name").iter().map(|&x| x).collect();
-          let created = cursor.get_int(2);
-          let modified = cursor.get_int(3);
-          let accessed = cursor.get_int(4);
-          let hash = cursor.get_blob(5).unwrap_or(&[]).iter().map(|&x| x).collect();
-          let persistent_ref = cursor.get_blob(6).unwrap_or(&[]).iter().map(|&x| x).collect();
-
-          listing.push((id, name,
-                        created as u64, modified as u64, accessed as u64,
-                        hash, persistent_ref));
+        match self.list_dir(parent_opt) {
+          Ok(listing) => return reply(Reply::ListResult(listing)),
+          Err(e) => return reply(Reply::Error(e)),
         }
+      },
 
-        return reply(Reply::ListResult(listing));
+      Msg::SetInfo(key, value) => {
+        match self.set_info(key, value) {
+          Ok(()) => return reply(Reply::UpdateOK),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
+
+      Msg::GetInfo(key) => {
+        match self.get_info(key) {
+          Ok(value) => return reply(Reply::Info(value)),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
+
+      Msg::ListHashes => {
+        match self.list_hashes() {
+          Ok(hashes) => return reply(Reply::Hashes(hashes)),
+          Err(e) => return reply(Reply::Error(e)),
+        }
       },
     }
   }
@@ -293,14 +702,22 @@ impl <A: KeyEntry<A>> MsgHandler<Msg<A>, Reply> for KeyIndex {
 mod tests {
   use super::*;
 
+  use process::{Process};
+  use std::collections::{BTreeMap};
+  use tree_entry::{XAttrs};
+
+  #[derive(Clone)]
   struct TestEntry {
     id: Option<u64>,
     parent: Option<u64>,
     name: Vec<u8>,
+    mode: Option<u64>,
+    accessed: Option<u64>,
+    changed_ns: Option<u64>,
   }
   impl KeyEntry<TestEntry> for TestEntry {
     fn id(&self) -> Option<u64> {
-      None
+      self.id
     }
     fn parent_id(&self) -> Option<u64>{
       self.parent.clone()
@@ -320,11 +737,11 @@ mod tests {
       None
     }
     fn accessed(&self) -> Option<u64> {
-      None
+      self.accessed
     }
 
     fn permissions(&self) -> Option<u64> {
-      None
+      self.mode
     }
     fn user_id(&self) -> Option<u64> {
       None
@@ -332,11 +749,180 @@ mod tests {
     fn group_id(&self) -> Option<u64> {
       None
     }
+    fn symlink_target(&self) -> Option<Vec<u8>> {
+      None
+    }
+    fn modified_ns(&self) -> Option<u64> {
+      None
+    }
+    fn changed_ns(&self) -> Option<u64> {
+      self.changed_ns
+    }
+    fn inode(&self) -> Option<(u64, u64)> {
+      None
+    }
+    fn link_count(&self) -> Option<u64> {
+      None
+    }
+    fn xattrs(&self) -> XAttrs {
+      BTreeMap::new()
+    }
+    fn device(&self) -> Option<u64> {
+      None
+    }
     fn with_id(&self, id: u64) -> TestEntry {
-      TestEntry{id:Some(id),
-                parent: self.parent_id(),
-                name: self.name()}
+      TestEntry{id: Some(id), ..self.clone()}
+    }
+  }
+
+  #[test]
+  fn lookup_ignores_access_time() {
+    let ki: KeyIndexProcess<TestEntry> =
+      Process::new(Box::new(move|| { KeyIndex::new_for_testing() }));
+    let entry = TestEntry{id: None, parent: None, name: b"foo".to_vec(), mode: Some(0o100644),
+                          accessed: Some(1000), changed_ns: Some(5000)};
+
+    let id = match ki.send_reply(Msg::Insert(entry.clone())) {
+      Reply::Id(id) => id,
+      _ => panic!("Unexpected reply from key index."),
+    };
+
+    // Reading the file only changes its access time:
+    match ki.send_reply(Msg::LookupExact(TestEntry{accessed: Some(2000), ..entry.clone()},
+                                         false)) {
+      Reply::Id(found) => assert_eq!(found, id),
+      _ => panic!("Unchanged entry was not found."),
+    }
+
+    // Changing its metadata changes its status change time:
+    match ki.send_reply(Msg::LookupExact(TestEntry{changed_ns: Some(6000), ..entry.clone()},
+                                         false)) {
+      Reply::NotFound => (),
+      _ => panic!("Changed entry was found."),
+    }
+  }
+
+  #[test]
+  fn lookup_waits_for_stored_data() {
+    let ki: KeyIndexProcess<TestEntry> =
+      Process::new(Box::new(move|| { KeyIndex::new_for_testing() }));
+    let entry = TestEntry{id: None, parent: None, name: b"foo".to_vec(), mode: Some(0o100644),
+                          accessed: None, changed_ns: Some(5000)};
+
+    let id = match ki.send_reply(Msg::Insert(entry.clone())) {
+      Reply::Id(id) => id,
+      _ => panic!("Unexpected reply from key index."),
+    };
+
+    // The run is interrupted before the data is stored, so the next run must read it again:
+    ki.send_reply(Msg::NewGeneration);
+    match ki.send_reply(Msg::LookupExact(entry.clone(), true)) {
+      Reply::NotFound => (),
+      _ => panic!("Entry without stored data was found."),
+    }
+
+    ki.send_reply(Msg::UpdateDataHash(entry.with_id(id), Some(b"hash".to_vec()),
+                                      Some(b"ref".to_vec())));
+    match ki.send_reply(Msg::LookupExact(entry.clone(), true)) {
+      Reply::Id(found) => assert_eq!(found, id),
+      _ => panic!("Entry with stored data was not found."),
+    }
+  }
+
+  #[test]
+  fn changed_directory_keeps_its_entries() {
+    let ki: KeyIndexProcess<TestEntry> =
+      Process::new(Box::new(move|| { KeyIndex::new_for_testing() }));
+    let insert = |entry: &TestEntry| match ki.send_reply(Msg::Insert(entry.clone())) {
+      Reply::Id(id) => id,
+      _ => panic!("Unexpected reply from key index."),
+    };
+    let top = TestEntry{id: None, parent: None, name: b"top".to_vec(), mode: Some(0o040755),
+                        accessed: None, changed_ns: Some(1000)};
+    let top_id = insert(&top);
+    let sub = TestEntry{parent: Some(top_id), name: b"sub".to_vec(), ..top.clone()};
+    let sub_id = insert(&sub);
+    let file = TestEntry{parent: Some(sub_id), name: b"file".to_vec(), mode: Some(0o100644),
+                         ..top.clone()};
+    let file_id = insert(&file);
+    ki.send_reply(Msg::UpdateDataHash(file.with_id(file_id), Some(b"hash".to_vec()),
+                                      Some(b"ref".to_vec())));
+
+    // Adding a file to the top directory changes its times, but not the entries under it:
+    ki.send_reply(Msg::NewGeneration);
+    match ki.send_reply(Msg::LookupExact(TestEntry{changed_ns: Some(2000), ..top.clone()},
+                                         false)) {
+      Reply::Id(found) => assert_eq!(found, top_id),
+      _ => panic!("Changed directory was not found."),
+    }
+    match ki.send_reply(Msg::LookupExact(sub.clone(), false)) {
+      Reply::Id(found) => assert_eq!(found, sub_id),
+      _ => panic!("Unchanged directory was not found."),
+    }
+    match ki.send_reply(Msg::LookupExact(file.clone(), true)) {
+      Reply::Id(found) => assert_eq!(found, file_id),
+      _ => panic!("Unchanged file was not found."),
+    }
+    match ki.send_reply(Msg::ListDir(None)) {
+      Reply::ListResult(entries) => {
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, top_id);
+        assert_eq!(entries[0].permissions, Some(0o040755));
+      },
+      _ => panic!("Unexpected reply from key index."),
+    }
+  }
+
+  #[test]
+  fn listing_leaves_out_removed_entries() {
+    let ki: KeyIndexProcess<TestEntry> =
+      Process::new(Box::new(move|| { KeyIndex::new_for_testing() }));
+    let kept = TestEntry{id: None, parent: None, name: b"kept".to_vec(), mode: Some(0o100644),
+                         accessed: None, changed_ns: None};
+    let removed = TestEntry{name: b"removed".to_vec(), ..kept.clone()};
+
+    ki.send_reply(Msg::Insert(kept.clone()));
+    ki.send_reply(Msg::Insert(removed.clone()));
+
+    let names = |ki: &KeyIndexProcess<TestEntry>| match ki.send_reply(Msg::ListDir(None)) {
+      Reply::ListResult(entries) => entries.into_iter().map(|e| e.name).collect::<Vec<_>>(),
+      _ => panic!("Unexpected reply from key index."),
+    };
+    assert_eq!(names(&ki), vec![b"kept".to_vec(), b"removed".to_vec()]);
+
+    // The next run only finds one of the entries:
+    ki.send_reply(Msg::NewGeneration);
+    match ki.send_reply(Msg::LookupExact(kept.clone(), false)) {
+      Reply::Id(_) => (),
+      _ => panic!("Unchanged entry was not found."),
+    }
+    assert_eq!(names(&ki), vec![b"kept".to_vec()]);
+
+    // Entries missing from the last run are removed in the next:
+    ki.send_reply(Msg::NewGeneration);
+    match ki.send_reply(Msg::LookupExact(removed.clone(), false)) {
+      Reply::NotFound => (),
+      _ => panic!("Removed entry was found."),
     }
   }
 
+  #[test]
+  fn only_finished_runs_are_complete() {
+    let ki: KeyIndexProcess<TestEntry> =
+      Process::new(Box::new(move|| { KeyIndex::new_for_testing() }));
+    let finished = |ki: &KeyIndexProcess<TestEntry>| match ki.send_reply(Msg::IsFinished) {
+      Reply::Finished(finished) => finished,
+      _ => panic!("Unexpected reply from key index."),
+    };
+    assert!(!finished(&ki));
+
+    ki.send_reply(Msg::NewGeneration);
+    assert!(!finished(&ki));
+    ki.send_reply(Msg::FinishGeneration);
+    assert!(finished(&ki));
+
+    // An interrupted run is not complete, even though the one before it was:
+    ki.send_reply(Msg::NewGeneration);
+    assert!(!finished(&ki));
+  }
 }
@@ -14,42 +14,47 @@
 
 //! External API for creating and manipulating snapshots.
 
+use std::io::{Read};
 use std::thunk::Thunk;
 
 use blob_store;
+use chunker::{Chunker};
+use errors::{HatError, HatResult};
 use hash_tree::{SimpleHashTreeWriter, HashTreeBackend,
                 SimpleHashTreeReader, ReaderResult};
 use hash_index;
 
 use process::{Process, MsgHandler};
 
-use key_index::{KeyIndexProcess, KeyEntry};
+use key_index::{KeyIndexProcess, KeyEntry, IndexEntry};
 use key_index;
 
 
+#[cfg(test)]
+use chunker::{FixedSize};
 #[cfg(test)]
 use key_index::{KeyIndex};
 
-pub type KeyStoreProcess<KE, IT> = Process<Msg<KE, IT>, Reply>;
+pub type KeyStoreProcess<KE> = Process<Msg<KE>, Reply>;
 
-pub type DirElem = (u64, Vec<u8>, u64, u64, u64, Vec<u8>, Vec<u8>,
-                    Option<ReaderResult<HashStoreBackend>>);
+pub type DirElem = (IndexEntry, Option<ReaderResult<HashStoreBackend>>);
 
 // Public structs
-pub enum Msg<KE, IT> {
+pub enum Msg<KE> {
 
-  /// Insert a key into the index. If this key has associated data a "chunk-iterator creator" can be
-  /// passed along with it. If the data turns out to be unreadable, this iterator proc can return
-  /// `None`.
-  /// Returns `Id` with the new entry ID.
-  Insert(KE, Option<Thunk<'static, (), Option<IT>>>),
+  /// Insert a key into the index. If this key has associated data a "data source opener" can be
+  /// passed along with it, and the data it reads is cut into chunks by the key store's `Chunker`.
+  /// If the data turns out to be unreadable, the opener can return `None`.
+  /// Returns `Id` with the new entry ID, or `Error`. The data is stored after replying, so errors
+  /// storing it are returned by the next `Flush`.
+  Insert(KE, Option<Thunk<'static, (), Option<Box<Read + Send>>>>),
 
   /// List a "directory" (aka. a `level`) in the index.
-  /// Returns `ListResult` with all the entries under the given parent.
+  /// Returns `ListResult` with all the entries under the given parent, or `Error`.
   ListDir(Option<u64>),
 
   /// Flush this key store and its dependencies.
-  /// Returns `FlushOK`.
+  /// Returns `FlushOK` or `Error`.
   Flush,
 }
 
@@ -57,12 +62,18 @@ pub enum Reply {
   Id(u64),
   ListResult(Vec<DirElem>),
   FlushOK,
+  Error(HatError),
 }
 
 pub struct KeyStore<KE> {
   index: KeyIndexProcess<KE>,
   hash_index: hash_index::HashIndexProcess,
   blob_store: blob_store::BlobStoreProcess,
+  hash_tree_order: usize,
+  chunker: Box<Chunker>,
+
+  /// The first error from storing the data of an inserted entry; reported by the next `Flush`.
+  error: Option<HatError>,
 }
 
 // Implementations
@@ -70,27 +81,116 @@ impl <KE: 'static + KeyEntry<KE> + Send> KeyStore<KE>
 {
   pub fn new(index: KeyIndexProcess<KE>,
              hash_index: hash_index::HashIndexProcess,
-             blob_store: blob_store::BlobStoreProcess) -> KeyStore<KE> {
-    KeyStore{index: index, hash_index: hash_index, blob_store: blob_store}
+             blob_store: blob_store::BlobStoreProcess,
+             hash_tree_order: usize,
+             chunker: Box<Chunker>) -> KeyStore<KE> {
+    KeyStore{index: index, hash_index: hash_index, blob_store: blob_store,
+             hash_tree_order: hash_tree_order, chunker: chunker, error: None}
   }
 
   #[cfg(test)]
   pub fn new_for_testing<B:'static + blob_store::BlobStoreBackend + Send + Clone>(backend: B) -> KeyStore<KE> {
+    KeyStore::new_for_testing_with_chunker(backend, Box::new(FixedSize::new(128 * 1024)))
+  }
+
+  #[cfg(test)]
+  pub fn new_for_testing_with_chunker<B:'static + blob_store::BlobStoreBackend + Send + Clone>(
+    backend: B, chunker: Box<Chunker>) -> KeyStore<KE>
+  {
     let ki_p = Process::new(Box::new(move|| { KeyIndex::new_for_testing() }));
     let hi_p = Process::new(Box::new(move|| { hash_index::HashIndex::new_for_testing() }));
     let bs_p = Process::new(Box::new(move|| { blob_store::BlobStore::new_for_testing(backend, 1024) }));
-    KeyStore{index: ki_p, hash_index: hi_p, blob_store: bs_p}
+    KeyStore{index: ki_p, hash_index: hi_p, blob_store: bs_p, hash_tree_order: 8,
+             chunker: chunker, error: None}
   }
 
-  pub fn flush(&mut self) {
-    self.blob_store.send_reply(blob_store::Msg::Flush);
-    self.hash_index.send_reply(hash_index::Msg::Flush);
-    self.index.send_reply(key_index::Msg::Flush);
+  pub fn flush(&mut self) -> HatResult<()> {
+    match self.blob_store.send_reply(blob_store::Msg::Flush) {
+      blob_store::Reply::FlushOK => (),
+      blob_store::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("blob store")),
+    }
+    match self.hash_index.send_reply(hash_index::Msg::Flush) {
+      hash_index::Reply::CommitOK => (),
+      hash_index::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("hash index")),
+    }
+    match self.index.send_reply(key_index::Msg::Flush) {
+      key_index::Reply::FlushOK => (),
+      key_index::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("key index")),
+    }
+    match self.error.take() {
+      Some(e) => Err(e),
+      None => Ok(()),
+    }
+  }
+
+  /// Store the data of the entry inserted as `id`, and record its hash in the key index once the
+  /// data is committed.
+  fn store_data(&mut self, org_entry: KE, id: u64,
+                source_opt: Option<Thunk<'static, (), Option<Box<Read + Send>>>>)
+                -> HatResult<()> {
+    let new_entry = org_entry.with_id(id);
+    assert!(new_entry.id().is_some());
+
+    // Setup hash tree structure
+    let mut tree = self.hash_tree_writer();
+
+    // Check if we have an data source:
+    let source_opt = source_opt.and_then(|open| open());
+    if source_opt.is_none() {
+      // No data is associated with this entry.
+      match self.index.send_reply(key_index::Msg::UpdateDataHash(new_entry, None, None)) {
+        key_index::Reply::UpdateOK => (),
+        key_index::Reply::Error(e) => return Err(e),
+        _ => return Err(HatError::unexpected_reply("key index")),
+      }
+      // Bail out before storing data that does not exist:
+      return Ok(());
+    }
+
+    // Read, chunk and insert all data:
+    // (see HashStoreBackend::insert_chunk above)
+    let mut bytes_read = 0u64;
+    for chunk in self.chunker.chunks(source_opt.unwrap()) {
+      // The entry is left without a data hash, so the snapshot is not finished and the next run
+      // reads the entry again:
+      let chunk = match chunk {
+        Ok(chunk) => chunk,
+        Err(e) => return Err(HatError::Io(e.kind(), format!(
+          "Could not read '{}': {}", String::from_utf8_lossy(&org_entry.name()[..]), e))),
+      };
+      bytes_read += chunk.len() as u64;
+      try!(tree.append(chunk));
+    }
+
+    // Warn the user if we did not read the expected size:
+    org_entry.size().map(|s| { file_size_warning(org_entry.name(), s, bytes_read); });
+
+    // Get top tree hash:
+    let (hash, persistent_ref) = try!(tree.hash());
+
+    // Install a callback for updating the entry's data hash once the data has been stored (the
+    // key index reports failures to do so on its next flush):
+    let local_index = self.index.clone();
+    let hash_bytes = hash.bytes.clone();
+    let callback = Box::new(move|| {
+      local_index.send_reply(
+        key_index::Msg::UpdateDataHash(new_entry, Some(hash_bytes), Some(persistent_ref)));
+    });
+    match self.hash_index.send_reply(hash_index::Msg::CallAfterHashIsComitted(hash.clone(),
+                                                                               callback)) {
+      hash_index::Reply::CallbackRegistered => Ok(()),
+      hash_index::Reply::HashNotKnown => Err(HatError::MissingHash(hash)),
+      hash_index::Reply::Error(e) => Err(e),
+      _ => Err(HatError::unexpected_reply("hash index")),
+    }
   }
 
   pub fn hash_tree_writer(&mut self) -> SimpleHashTreeWriter<HashStoreBackend> {
     let backend = HashStoreBackend::new(self.hash_index.clone(), self.blob_store.clone());
-    return SimpleHashTreeWriter::new(8, backend);
+    return SimpleHashTreeWriter::new(self.hash_tree_order, backend);
   }
 }
 
@@ -107,54 +207,63 @@ impl HashStoreBackend {
     HashStoreBackend{hash_index: hash_index, blob_store: blob_store}
   }
 
-  fn fetch_chunk_from_hash(&mut self, hash: hash_index::Hash) -> Option<Vec<u8>> {
+  fn fetch_chunk_from_hash(&mut self, hash: hash_index::Hash) -> HatResult<Vec<u8>> {
     assert!(hash.bytes.len() > 0);
-    match self.hash_index.send_reply(hash_index::Msg::FetchPersistentRef(hash)) {
+    match self.hash_index.send_reply(hash_index::Msg::FetchPersistentRef(hash.clone())) {
       hash_index::Reply::PersistentRef(chunk_ref_bytes) => {
-        let chunk_ref = blob_store::BlobID::from_bytes(chunk_ref_bytes);
-        self.fetch_chunk_from_persistent_ref(chunk_ref)
+        match blob_store::BlobID::try_from_bytes(&chunk_ref_bytes[..]) {
+          Some(chunk_ref) => self.fetch_chunk_from_persistent_ref(chunk_ref),
+          None => Err(HatError::CorruptData(format!("Invalid persistent reference for {:?}",
+                                                    hash))),
+        }
       },
-      _ => None  // TODO: Do we need to distinguish `missing` from `unknown ref`?
+      hash_index::Reply::Error(e) => Err(e),
+      // Either unknown, or not yet stored:
+      _ => Err(HatError::MissingHash(hash)),
     }
   }
 
-  fn fetch_chunk_from_persistent_ref(&mut self, chunk_ref: blob_store::BlobID) -> Option<Vec<u8>> {
+  fn fetch_chunk_from_persistent_ref(&mut self, chunk_ref: blob_store::BlobID)
+                                     -> HatResult<Vec<u8>> {
     match self.blob_store.send_reply(blob_store::Msg::Retrieve(chunk_ref)) {
-      blob_store::Reply::RetrieveOK(chunk) => Some(chunk),
-      _ => None
+      blob_store::Reply::RetrieveOK(chunk) => Ok(chunk),
+      blob_store::Reply::Error(e) => Err(e),
+      _ => Err(HatError::unexpected_reply("blob store")),
     }
   }
 }
 
 impl HashTreeBackend for HashStoreBackend
 {
-  fn fetch_chunk(&mut self, hash: hash_index::Hash) -> Option<Vec<u8>> {
+  fn fetch_chunk(&mut self, hash: hash_index::Hash) -> HatResult<Vec<u8>> {
     assert!(hash.bytes.len() > 0);
     return self.fetch_chunk_from_hash(hash);
   }
 
-  fn fetch_persistent_ref(&mut self, hash: hash_index::Hash) -> Option<Vec<u8>> {
+  fn fetch_persistent_ref(&mut self, hash: hash_index::Hash) -> HatResult<Option<Vec<u8>>> {
     assert!(hash.bytes.len() > 0);
     loop {
       match self.hash_index.send_reply(hash_index::Msg::FetchPersistentRef(hash.clone())) {
-        hash_index::Reply::PersistentRef(r) => { return Some(r) }, // done
-        hash_index::Reply::HashNotKnown => { return None }, // done
+        hash_index::Reply::PersistentRef(r) => { return Ok(Some(r)) }, // done
+        hash_index::Reply::HashNotKnown => { return Ok(None) }, // done
         hash_index::Reply::Retry => (),  // continue loop
-        _ => panic!("Unexpected reply from hash index."),
+        hash_index::Reply::Error(e) => { return Err(e) },
+        _ => return Err(HatError::unexpected_reply("hash index")),
       }
     };
   }
 
-  fn fetch_payload(&mut self, hash: hash_index::Hash) -> Option<Vec<u8>> {
+  fn fetch_payload(&mut self, hash: hash_index::Hash) -> HatResult<Option<Vec<u8>>> {
     match self.hash_index.send_reply(hash_index::Msg::FetchPayload(hash)) {
-      hash_index::Reply::Payload(p) => { return p }, // done
-      hash_index::Reply::HashNotKnown => { return None }, // done
-      _ => panic!("Unexpected reply from hash index."),
+      hash_index::Reply::Payload(p) => { return Ok(p) }, // done
+      hash_index::Reply::HashNotKnown => { return Ok(None) }, // done
+      hash_index::Reply::Error(e) => { return Err(e) },
+      _ => return Err(HatError::unexpected_reply("hash index")),
     }
   }
 
   fn insert_chunk(&mut self, hash: hash_index::Hash, level: i64, payload: Option<Vec<u8>>,
-                  chunk: Vec<u8>) -> Vec<u8> {
+                  chunk: Vec<u8>) -> HatResult<Vec<u8>> {
     assert!(hash.bytes.len() > 0);
 
     let mut hash_entry = hash_index::HashEntry{hash:hash.clone(), level:level, payload:payload,
@@ -163,8 +272,11 @@ impl HashTreeBackend for HashStoreBackend
     match self.hash_index.send_reply(hash_index::Msg::Reserve(hash_entry.clone())) {
       hash_index::Reply::HashKnown => {
         // Someone came before us: piggyback on their result.
-        return self.fetch_persistent_ref(hash).expect(
-          "Could not find persistent_ref for known chunk.");
+        match try!(self.fetch_persistent_ref(hash.clone())) {
+          Some(persistent_ref) => return Ok(persistent_ref),
+          // It was removed in the meantime:
+          None => return Err(HatError::MissingHash(hash)),
+        }
       },
       hash_index::Reply::ReserveOK => {
         // We came first: this data-chunk is ours to process.
@@ -176,13 +288,18 @@ impl HashTreeBackend for HashStoreBackend
         match self.blob_store.send_reply(blob_store::Msg::Store(chunk, callback)) {
           blob_store::Reply::StoreOK(blob_ref) => {
             hash_entry.persistent_ref = Some(blob_ref.as_bytes());
-            self.hash_index.send_reply(hash_index::Msg::UpdateReserved(hash_entry));
-            return blob_ref.as_bytes();
+            match self.hash_index.send_reply(hash_index::Msg::UpdateReserved(hash_entry)) {
+              hash_index::Reply::ReserveOK => return Ok(blob_ref.as_bytes()),
+              hash_index::Reply::Error(e) => return Err(e),
+              _ => return Err(HatError::unexpected_reply("hash index")),
+            }
           },
-          _ => panic!("Unexpected reply from BlobStore."),
+          blob_store::Reply::Error(e) => return Err(e),
+          _ => return Err(HatError::unexpected_reply("blob store")),
         };
       },
-      _ => panic!("Unexpected HashIndex reply."),
+      hash_index::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("hash index")),
     };
   }
 }
@@ -197,93 +314,66 @@ fn file_size_warning(name: Vec<u8>, wanted: u64, got: u64) {
 }
 
 impl
-  <KE: 'static + KeyEntry<KE> + Send + Clone, IT: Iterator<Item=Vec<u8>>>
-  MsgHandler<Msg<KE, IT>, Reply> for KeyStore<KE>
+  <KE: 'static + KeyEntry<KE> + Send + Clone>
+  MsgHandler<Msg<KE>, Reply> for KeyStore<KE>
 {
-  fn handle(&mut self, msg: Msg<KE, IT>, reply: Box<Fn(Reply)>) {
+  fn handle(&mut self, msg: Msg<KE>, reply: Box<Fn(Reply)>) {
     match msg {
       Msg::Flush => {
-        self.flush();
-        return reply(Reply::FlushOK);
+        match self.flush() {
+          Ok(()) => return reply(Reply::FlushOK),
+          Err(e) => return reply(Reply::Error(e)),
+        }
       },
 
       Msg::ListDir(parent) => {
         match self.index.send_reply(key_index::Msg::ListDir(parent)) {
           key_index::Reply::ListResult(entries) => {
-            // TODO(jos): Rewrite this tuple hell
             let mut my_entries = Vec::with_capacity(entries.len());
-            for (id, name, created, modified, accessed, hash, persistent_ref) in entries.into_iter()
-            {
-              let local_hash = hash_index::Hash{bytes: hash.clone()};
-              let local_ref = persistent_ref.clone();
+            for entry in entries.into_iter() {
+              let local_hash = hash_index::Hash{bytes: entry.hash.clone()};
+              let local_ref = entry.persistent_ref.clone();
 
               my_entries.push(
-                (id, name, created, modified, accessed, hash, persistent_ref,
-                   SimpleHashTreeReader::open(
-                     HashStoreBackend::new(self.hash_index.clone(), self.blob_store.clone()),
-                     local_hash, local_ref)
+                (entry,
+                 SimpleHashTreeReader::open(
+                   HashStoreBackend::new(self.hash_index.clone(), self.blob_store.clone()),
+                   local_hash, local_ref)
                  ));
             }
             return reply(Reply::ListResult(my_entries));
           },
-          _ => panic!("Unexpected result from key index."),
+          key_index::Reply::Error(e) => return reply(Reply::Error(e)),
+          _ => return reply(Reply::Error(HatError::unexpected_reply("key index"))),
         }
       },
 
-      Msg::Insert(org_entry, chunk_it_opt) => {
-        match self.index.send_reply(key_index::Msg::LookupExact(org_entry.clone())) {
+      Msg::Insert(org_entry, source_opt) => {
+        let has_data = source_opt.is_some();
+        match self.index.send_reply(key_index::Msg::LookupExact(org_entry.clone(), has_data)) {
 
           key_index::Reply::Id(entry_id) => {
             return reply(Reply::Id(entry_id));
           },
 
+          key_index::Reply::Error(e) => return reply(Reply::Error(e)),
+
           _ => {
             let id = match self.index.send_reply(key_index::Msg::Insert(org_entry.clone())) {
               key_index::Reply::Id(entry_id) => entry_id,
-              _ => panic!("No ID returned from key index Insert()."),
+              key_index::Reply::Error(e) => return reply(Reply::Error(e)),
+              _ => return reply(Reply::Error(HatError::unexpected_reply("key index"))),
             };
 
             // Send out the ID early to allow the client to continue its key discovery routine.
             // The bounded input-channel will prevent the client from overflowing us.
             reply(Reply::Id(id.clone()));
 
-            let new_entry = org_entry.with_id(id);
-            assert!(new_entry.id().is_some());
-
-            // Setup hash tree structure
-            let mut tree = self.hash_tree_writer();
-
-            // Check if we have an data source:
-            let it_opt = chunk_it_opt.and_then(|open| open());
-            if it_opt.is_none() {
-              // No data is associated with this entry.
-              self.index.send_reply(key_index::Msg::UpdateDataHash(new_entry, None, None));
-              // Bail out before storing data that does not exist:
-              return;
-            }
-
-            // Read and insert all file chunks:
-            // (see HashStoreBackend::insert_chunk above)
-            let mut bytes_read = 0u64;
-            for chunk in it_opt.unwrap() {
-              bytes_read += chunk.len() as u64;
-              tree.append(chunk);
+            if let Err(e) = self.store_data(org_entry, id, source_opt) {
+              if self.error.is_none() {
+                self.error = Some(e);
+              }
             }
-
-            // Warn the user if we did not read the expected size:
-            org_entry.size().map(|s| { file_size_warning(org_entry.name(), s, bytes_read); });
-
-            // Get top tree hash:
-            let (hash, persistent_ref) = tree.hash();
-
-            // Install a callback for updating the entry's data hash once the data has been stored:
-            let local_index = self.index.clone();
-            let hash_bytes = hash.bytes.clone();
-            let callback = Box::new(move|| {
-              local_index.send_reply(
-                key_index::Msg::UpdateDataHash(new_entry, Some(hash_bytes), Some(persistent_ref)));
-            });
-            self.hash_index.send_reply(hash_index::Msg::CallAfterHashIsComitted(hash, callback));
           }
         }
       }
@@ -299,9 +389,16 @@ mod tests {
   use key_index::{KeyEntry};
   use blob_store::tests::{MemoryBackend, DevNullBackend};
 
+  use chunker::{Chunker};
+  use errors::{HatError};
   use process::{Process};
+  use tree_entry::{XAttrs};
 
   use rand::Rng;
+  use std::collections::{BTreeMap};
+  use std::fs;
+  use std::io;
+  use std::io::{Read};
   use rand::thread_rng;
 
   use test::{Bencher};
@@ -378,6 +475,34 @@ mod tests {
       None
     }
 
+    fn symlink_target(&self) -> Option<Vec<u8>> {
+      None
+    }
+
+    fn modified_ns(&self) -> Option<u64> {
+      self.modified.map(|m| m * 1000000)
+    }
+
+    fn changed_ns(&self) -> Option<u64> {
+      None
+    }
+
+    fn inode(&self) -> Option<(u64, u64)> {
+      None
+    }
+
+    fn link_count(&self) -> Option<u64> {
+      None
+    }
+
+    fn xattrs(&self) -> XAttrs {
+      BTreeMap::new()
+    }
+
+    fn device(&self) -> Option<u64> {
+      None
+    }
+
     fn with_id(&self, id: u64) -> KeyEntryStub {
       let mut x = self.clone();
       x.id = Some(id);
@@ -386,14 +511,16 @@ mod tests {
 
   }
 
-  impl Iterator for KeyEntryStub {
-    type Item = Vec<u8>;
-
-    fn next(&mut self) -> Option<Vec<u8>> {
-      match self.data.as_mut() {
-        Some(x) => if x.len() > 0 { Some(x.remove(0)) } else { None },
-        None => None,
-      }
+  impl KeyEntryStub {
+    /// A data source with the concatenated data chunks.
+    fn reader(&self) -> Option<Box<Read + Send>> {
+      self.data.as_ref().map(|chunks| {
+        let mut bytes = vec![];
+        for chunk in chunks.iter() {
+          bytes.extend(chunk.iter().cloned());
+        }
+        Box::new(io::Cursor::new(bytes)) as Box<Read + Send>
+      })
     }
   }
 
@@ -449,12 +576,12 @@ mod tests {
                filelist: create_files(root_id, size)}
   }
 
-  fn insert_and_update_fs(fs: &mut FileSystem, ks_p: KeyStoreProcess<KeyEntryStub, KeyEntryStub>)
+  fn insert_and_update_fs(fs: &mut FileSystem, ks_p: KeyStoreProcess<KeyEntryStub>)
   {
     let local_file = fs.file.clone();
     let id = match ks_p.send_reply(Msg::Insert(fs.file.clone(),
                                                if fs.file.data.is_some() {
-                                                 Some(Box::new(move|| { Some(local_file) }))
+                                                 Some(Box::new(move|| { local_file.reader() }))
                                                } else { None })
                                    ) {
       Reply::Id(id) => id,
@@ -470,7 +597,7 @@ mod tests {
   }
 
   fn verify_filesystem(fs: &FileSystem,
-                       ks_p: KeyStoreProcess<KeyEntryStub, KeyEntryStub>) -> usize
+                       ks_p: KeyStoreProcess<KeyEntryStub>) -> usize
   {
     let listing = match ks_p.send_reply(Msg::ListDir(fs.file.id())) {
       Reply::ListResult(ls) => ls,
@@ -479,17 +606,17 @@ mod tests {
 
     assert_eq!(fs.filelist.len(), listing.len());
 
-    for (id, name, created, modified, accessed, hash, persistent_ref, tree_data) in listing {
+    for (entry, tree_data) in listing {
       let mut found = false;
 
       for dir in fs.filelist.iter() {
-        if dir.file.name() == name {
+        if dir.file.name() == entry.name {
           found = true;
 
-          assert_eq!(dir.file.id().unwrap(), id);
-          assert_eq!(dir.file.created().unwrap_or(0), created);
-          assert_eq!(dir.file.accessed().unwrap_or(0), accessed);
-          assert_eq!(dir.file.modified().unwrap_or(0), modified);
+          assert_eq!(dir.file.id().unwrap(), entry.id);
+          assert_eq!(dir.file.created().unwrap_or(0), entry.created);
+          assert_eq!(dir.file.accessed().unwrap_or(0), entry.accessed);
+          assert_eq!(dir.file.modified().unwrap_or(0), entry.modified);
 
           match dir.file.data {
             Some(ref original) => {
@@ -497,16 +624,20 @@ mod tests {
                 None => panic!("No data."),
                 Some(it) => it,
               };
-              let mut chunk_count = 0;
-              for (i, chunk) in it.enumerate() {
-                assert_eq!(original.get(i), Some(&chunk));
-                chunk_count += 1;
+              // The key store cuts the data into its own chunks:
+              let mut expected = vec![];
+              for chunk in original.iter() {
+                expected.extend(chunk.iter().cloned());
+              }
+              let mut stored = vec![];
+              for chunk in it {
+                stored.extend(chunk.unwrap().into_iter());
               }
-              assert_eq!(original.len(), chunk_count);
+              assert_eq!(expected, stored);
             },
             None => {
-              assert_eq!(hash, b"".to_vec());
-              assert_eq!(persistent_ref, b"".to_vec());
+              assert_eq!(entry.hash, b"".to_vec());
+              assert_eq!(entry.persistent_ref, b"".to_vec());
             }
           }
 
@@ -544,11 +675,86 @@ mod tests {
     true
   }
 
+  /// Cuts after every newline.
+  struct LineChunker;
+
+  impl Chunker for LineChunker {
+    fn chunks(&self, source: Box<Read + Send>) -> Box<Iterator<Item=io::Result<Vec<u8>>> + Send> {
+      let mut data = vec![];
+      let mut source = source;
+      source.read_to_end(&mut data).unwrap();
+      let lines: Vec<io::Result<Vec<u8>>> = data.split(|&b| b == b'\n')
+        .filter(|l| l.len() > 0)
+        .map(|l| { let mut l = l.to_vec(); l.push(b'\n'); Ok(l) })
+        .collect();
+      Box::new(lines.into_iter())
+    }
+
+    fn describe(&self) -> String {
+      "lines".to_string()
+    }
+  }
+
+  #[test]
+  fn custom_chunker() {
+    let backend = MemoryBackend::new();
+    let ks_p: KeyStoreProcess<KeyEntryStub> = Process::new(Box::new(move|| {
+      KeyStore::new_for_testing_with_chunker(backend, Box::new(LineChunker))
+    }));
+
+    let entry = KeyEntryStub::new(None, b"lines".to_vec(),
+                                  Some(vec![b"first\nsec".to_vec(), b"ond\nthird\n".to_vec()]),
+                                  None);
+    let local_entry = entry.clone();
+    ks_p.send_reply(Msg::Insert(entry, Some(Box::new(move|| { local_entry.reader() }))));
+    match ks_p.send_reply(Msg::Flush) {
+      Reply::FlushOK => (),
+      _ => panic!("Unexpected result from key store."),
+    }
+
+    let listing = match ks_p.send_reply(Msg::ListDir(None)) {
+      Reply::ListResult(ls) => ls,
+      _ => panic!("Unexpected result from key store."),
+    };
+    assert_eq!(listing.len(), 1);
+    let chunks: Vec<Vec<u8>> = match listing.into_iter().next().unwrap().1 {
+      Some(it) => it.map(|c| c.unwrap()).collect(),
+      None => panic!("No data."),
+    };
+    assert_eq!(chunks, vec![b"first\n".to_vec(), b"second\n".to_vec(), b"third\n".to_vec()]);
+  }
+
+  #[test]
+  fn read_error_leaves_entry_without_data() {
+    let backend = MemoryBackend::new();
+    let ks_p: KeyStoreProcess<KeyEntryStub> =
+      Process::new(Box::new(move|| { KeyStore::new_for_testing(backend) }));
+
+    // Reading a directory as a file fails after the first bytes:
+    let entry = KeyEntryStub::new(None, b"broken".to_vec(), None, None);
+    ks_p.send_reply(Msg::Insert(entry, Some(Box::new(move|| {
+      let source: Box<Read + Send> =
+        Box::new(io::Cursor::new(vec![1u8; 100]).chain(fs::File::open("/").unwrap()));
+      Some(source)
+    }))));
+    match ks_p.send_reply(Msg::Flush) {
+      Reply::Error(HatError::Io(..)) => (),
+      _ => panic!("Read error was not reported."),
+    }
+
+    let listing = match ks_p.send_reply(Msg::ListDir(None)) {
+      Reply::ListResult(ls) => ls,
+      _ => panic!("Unexpected result from key store."),
+    };
+    assert_eq!(listing.len(), 1);
+    assert!(listing[0].0.hash.is_empty());
+  }
+
 
   #[bench]
   fn insert_1_key_x_128000_zeros(bench: &mut Bencher) {
     let backend = DevNullBackend;
-    let ks_p : KeyStoreProcess<KeyEntryStub, KeyEntryStub>
+    let ks_p : KeyStoreProcess<KeyEntryStub>
       = Process::new(Box::new(move|| { KeyStore::new_for_testing(backend) }));
 
     let bytes = vec![0u8; 128*1024];
@@ -559,7 +765,7 @@ mod tests {
 
       let entry = KeyEntryStub::new(None, format!("{}", i).as_bytes().to_vec(),
                                     Some(vec![bytes.clone()]), None);
-      ks_p.send_reply(Msg::Insert(entry.clone(), Some(Box::new(move|| { Some(entry) }))));
+      ks_p.send_reply(Msg::Insert(entry.clone(), Some(Box::new(move|| { entry.reader() }))));
 
     });
 
@@ -571,7 +777,7 @@ mod tests {
   #[bench]
   fn insert_1_key_x_128000_unique(bench: &mut Bencher) {
     let backend = DevNullBackend;
-    let ks_p : KeyStoreProcess<KeyEntryStub, KeyEntryStub>
+    let ks_p : KeyStoreProcess<KeyEntryStub>
       = Process::new(Box::new(move|| { KeyStore::new_for_testing(backend) }));
 
     let bytes = vec![0u8; 128*1024];
@@ -591,7 +797,7 @@ mod tests {
 
       let entry = KeyEntryStub::new(None, format!("{}", i).as_bytes().to_vec(),
                                     Some(vec!(my_bytes)), None);
-      ks_p.send_reply(Msg::Insert(entry.clone(), Some(Box::new(move|| { Some(entry) }))));
+      ks_p.send_reply(Msg::Insert(entry.clone(), Some(Box::new(move|| { entry.reader() }))));
 
     });
 
@@ -603,7 +809,7 @@ mod tests {
   #[bench]
   fn insert_1_key_x_16_x_128000_zeros(bench: &mut Bencher) {
     let backend = DevNullBackend;
-    let ks_p : KeyStoreProcess<KeyEntryStub, KeyEntryStub>
+    let ks_p : KeyStoreProcess<KeyEntryStub>
       = Process::new(Box::new(move|| { KeyStore::new_for_testing(backend) }));
 
     bench.iter(|| {
@@ -613,7 +819,7 @@ mod tests {
                                     Some(vec![bytes; 16]),
                                     None);
 
-      ks_p.send_reply(Msg::Insert(entry.clone(), Some(Box::new(move|| { Some(entry) }))));
+      ks_p.send_reply(Msg::Insert(entry.clone(), Some(Box::new(move|| { entry.reader() }))));
 
       match ks_p.send_reply(Msg::Flush) {
         Reply::FlushOK => (),
@@ -629,7 +835,7 @@ mod tests {
   #[bench]
   fn insert_1_key_x_16_x_128000_unique(bench: &mut Bencher) {
     let backend = DevNullBackend;
-    let ks_p : KeyStoreProcess<KeyEntryStub, KeyEntryStub>
+    let ks_p : KeyStoreProcess<KeyEntryStub>
       = Process::new(Box::new(move|| { KeyStore::new_for_testing(backend) }));
 
     let bytes = vec![0u8; 128*1024];
@@ -656,7 +862,7 @@ mod tests {
 
       let entry = KeyEntryStub::new(None, vec![1u8, 2, 3], Some(chunks), None);
 
-      ks_p.send_reply(Msg::Insert(entry.clone(), Some(Box::new(move|| { Some(entry) }))));
+      ks_p.send_reply(Msg::Insert(entry.clone(), Some(Box::new(move|| { entry.reader() }))));
 
       match ks_p.send_reply(Msg::Flush) {
         Reply::FlushOK => (),
@@ -16,7 +16,8 @@
 
 use std::fs;
 use std::path::PathBuf;
-use std::sync::mpsc;
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use threadpool;
 
@@ -26,12 +27,15 @@ pub trait PathHandler<D> {
 }
 
 
+/// Pass every path under `root` to `worker`, descending into the paths it returns a payload for.
+/// Returns the number of directories and directory entries that could not be read (each of which
+/// is reported as it happens).
 pub fn iterate_recursively<P: 'static + Send + Clone, W: 'static + PathHandler<P> + Send + Clone>
-  (root: (PathBuf, P), worker: &mut W)
+  (root: (PathBuf, P), worker: &mut W, threads: usize) -> usize
 {
-  let threads = 10;
   let (push_ch, work_ch) = mpsc::sync_channel(threads);
   let pool = threadpool::ThreadPool::new(threads);
+  let failures = Arc::new(AtomicUsize::new(0));
 
   // Insert the first task into the queue:
   push_ch.send(Some(root)).unwrap();
@@ -54,20 +58,28 @@ pub fn iterate_recursively<P: 'static + Send + Clone, W: 'static + PathHandler<P
         running_workers += 1;
         let _worker = worker.clone();
         let _push_ch = push_ch.clone();
+        let _failures = failures.clone();
         pool.execute(move|| {
-          let res = fs::read_dir(&root);
-          if res.is_ok() {
-            for entry in res.unwrap() {
-              if entry.is_ok() {
-                let entry = entry.unwrap();
-                let file = entry.path();
-                let path = PathBuf::from(file.to_str().unwrap());
-                let dir_opt = _worker.handle_path(payload.clone(), path);
-                if dir_opt.is_some() {
-                  _push_ch.send(Some((file.clone(), dir_opt.unwrap()))).unwrap();
-                }
+          match fs::read_dir(&root) {
+            Err(e) => {
+              println!("Could not read '{}': {}", root.display(), e);
+              _failures.fetch_add(1, Ordering::SeqCst);
+            },
+            Ok(entries) => for entry in entries {
+              match entry {
+                Err(e) => {
+                  println!("Could not read an entry of '{}': {}", root.display(), e);
+                  _failures.fetch_add(1, Ordering::SeqCst);
+                },
+                Ok(entry) => {
+                  let file = entry.path();
+                  let dir_opt = _worker.handle_path(payload.clone(), file.clone());
+                  if dir_opt.is_some() {
+                    _push_ch.send(Some((file.clone(), dir_opt.unwrap()))).unwrap();
+                  }
+                },
               }
-            }
+            },
           }
           // Count this pool thread as idle:
           _push_ch.send(None).unwrap();
@@ -75,6 +87,7 @@ pub fn iterate_recursively<P: 'static + Send + Clone, W: 'static + PathHandler<P
       }
     }
   }
+  failures.load(Ordering::SeqCst)
 }
 
 struct PrintPathHandler;
@@ -31,6 +31,7 @@
 #![plugin(quickcheck_macros)]
 
 // Standard Rust imports
+extern crate libc;
 extern crate rand;
 extern crate test;
 extern crate time;
@@ -47,9 +48,23 @@ extern crate threadpool;
 extern crate quickcheck;
 
 
+use std::default::{Default};
 use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io;
+use std::io::{Write};
 use std::path::PathBuf;
 
+use rustc_serialize::hex::{ToHex};
+
+mod config;
+mod diff;
+mod errors;
+mod gc;
+mod repository;
+mod retention;
+
 mod callback_container;
 mod cumulative_counter;
 mod ordered_collection;
@@ -59,7 +74,9 @@ mod unique_priority_queue;
 mod hat;
 mod listdir;
 mod process;
+mod unix;
 
+mod chunker;
 mod hash_index;
 mod hash_tree;
 
@@ -70,16 +87,33 @@ mod key_index;
 mod key_store;
 
 mod snapshot_index;
+mod tree_entry;
+mod verify;
 
 
-static MAX_BLOB_SIZE: usize = 4 * 1024 * 1024;
-
-fn blob_dir() -> PathBuf { PathBuf::from("blobs") }
+fn default_repository() -> PathBuf { PathBuf::from("repo") }
 
 
 #[cfg(not(test))]
 fn usage() {
-  println!("Usage: {} [snapshot|commit|checkout] name path", env::args().next().unwrap());
+  let name = env::args_os().next().map(|n| n.to_string_lossy().into_owned())
+    .unwrap_or("hat".to_string());
+  println!("Usage: {} [--repo dir] init [--blob-dir dir] [--max-blob-size bytes] \
+            [--threads n] [--chunk-size bytes] [--chunking fixed|content-defined] \
+            [--tree-order n]", name);
+  println!("       {} [--repo dir] snapshot name path", name);
+  println!("       {} [--repo dir] commit [--tag tag]... [-m message] name", name);
+  println!("       {} [--repo dir] backup [--tag tag]... [-m message] name path", name);
+  println!("       {} [--repo dir] checkout [--id id | --as-of date] [--only subpath] [--no-owner] \
+            name path", name);
+  println!("       {} [--repo dir] snapshots [name]", name);
+  println!("       {} [--repo dir] ls [-r] name[@snapshot] [path]", name);
+  println!("       {} [--repo dir] cat name[@snapshot] path", name);
+  println!("       {} [--repo dir] diff name old new", name);
+  println!("       {} [--repo dir] verify [--data] [name]", name);
+  println!("       {} [--repo dir] gc [--dry-run]", name);
+  println!("       {} [--repo dir] forget [--dry-run] [--keep-last n] [--keep-daily n] \
+            [--keep-weekly n] [--keep-monthly n] [--keep-tag tag]... name", name);
 }
 
 
@@ -89,69 +123,482 @@ fn license() {
 }
 
 
+/// The text of the argument `arg`. Only paths may be given as arguments that are not valid UTF-8.
+fn text(arg: &OsStr) -> String {
+  match arg.to_str() {
+    Some(s) => s.to_string(),
+    None => fail("Invalid argument (not UTF-8)".to_string(), arg.to_string_lossy()),
+  }
+}
+
+
+/// Remove `name` and its value from `args`, returning the value. Fails if `name` is the last
+/// argument, and so has no value.
+fn take_option(args: &mut Vec<OsString>, name: &str) -> Option<OsString> {
+  let pos_opt = args.iter().position(|a| &a[..] == name);
+  match pos_opt {
+    Some(pos) if pos + 1 < args.len() => {
+      args.remove(pos);
+      Some(args.remove(pos))
+    },
+    Some(_) => fail(format!("Option {}", name), "expects a value"),
+    None => None,
+  }
+}
+
+
+/// Remove all occurrences of `name` and their values from `args`, returning the values.
+fn take_all_options(args: &mut Vec<OsString>, name: &str) -> Vec<String> {
+  let mut values = Vec::new();
+  while let Some(value) = take_text_option(args, name) {
+    values.push(value);
+  }
+  values
+}
+
+
+/// Remove `name` and its value from `args`, returning the value as text.
+fn take_text_option(args: &mut Vec<OsString>, name: &str) -> Option<String> {
+  take_option(args, name).map(|v| text(&v))
+}
+
+
+/// Remove the flag `name` from `args`, returning whether it was present.
+fn take_flag(args: &mut Vec<OsString>, name: &str) -> bool {
+  let pos_opt = args.iter().position(|a| &a[..] == name);
+  pos_opt.map(|pos| args.remove(pos)).is_some()
+}
+
+
+/// Remove `name` and its value from `args`, parsing the value as a number.
+fn take_number_option(args: &mut Vec<OsString>, name: &str) -> Option<usize> {
+  take_text_option(args, name).map(|v| match v.parse() {
+    Ok(n) => n,
+    Err(e) => fail(format!("Option {} expects a number, got '{}'", name, v), e),
+  })
+}
+
+
+/// Report a fatal error and exit with a non-zero status.
+fn fail<E: fmt::Display>(what: String, e: E) -> ! {
+  writeln!(&mut io::stderr(), "{}: {}", what, e).ok();
+  std::process::exit(1);
+}
+
+
+#[cfg(not(test))]
+fn init_repository(repository_root: &PathBuf, args: &mut Vec<OsString>) {
+  let mut config: config::RepositoryConfig = Default::default();
+  take_text_option(args, "--blob-dir").map(|d| config.blob_dir = d);
+  take_number_option(args, "--max-blob-size").map(|n| config.max_blob_size = n);
+  take_number_option(args, "--threads").map(|n| config.traversal_threads = n);
+  take_number_option(args, "--chunk-size").map(|n| config.chunk_size = n);
+  take_text_option(args, "--chunking").map(|c| match config::Chunking::from_name(&c[..]) {
+    Some(chunking) => config.chunking = Some(chunking),
+    None => fail("Option --chunking expects 'fixed' or 'content-defined'".to_string(), c),
+  });
+  take_number_option(args, "--tree-order").map(|n| config.hash_tree_order = n);
+  if args.len() > 0 {
+    usage();
+    let rest: Vec<String> = args.iter().map(|a| a.to_string_lossy().into_owned()).collect();
+    fail("Unexpected arguments to init".to_string(), rest.connect(" "));
+  }
+
+  match hat::init_repository(repository_root, &config) {
+    Ok(()) => println!("Initialised repository in '{}'", repository_root.display()),
+    Err(e) => fail(format!("Could not initialise repository '{}'", repository_root.display()), e),
+  }
+}
+
+
+#[cfg(not(test))]
+fn open_repository(repository_root: &PathBuf) -> hat::Hat<blob_store::FileBackend> {
+  let config_res = repository::check(repository_root)
+    .and_then(|_| config::RepositoryConfig::load(repository_root));
+  let config = match config_res {
+    Ok(c) => c,
+    Err(e) => fail(format!("Could not open repository '{}'", repository_root.display()), e),
+  };
+  let backend = blob_store::FileBackend::new(config.blob_path(repository_root));
+  match hat::Hat::open_repository(repository_root, backend) {
+    Ok(hat) => hat,
+    Err(e) => fail(format!("Could not open repository '{}'", repository_root.display()), e),
+  }
+}
+
+
+/// Parse a UTC time given as `YYYY-MM-DD[ HH:MM:SS]` into seconds since the epoch.
+///
+/// A date without a time of day means the end of that day.
+fn parse_time(text: &str) -> Option<i64> {
+  for format in vec!["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"].iter() {
+    if let Ok(tm) = time::strptime(text, format) {
+      return Some(tm.to_timespec().sec);
+    }
+  }
+  time::strptime(text, "%Y-%m-%d").ok().map(|tm| tm.to_timespec().sec + 24 * 60 * 60 - 1)
+}
+
+
+/// Parse a snapshot given either by its id or by a date.
+fn parse_snapshot(snapshot: &str) -> hat::SnapshotSelector {
+  match snapshot.parse() {
+    Ok(id) => hat::SnapshotSelector::Id(id),
+    Err(_) => match parse_time(snapshot) {
+      Some(time) => hat::SnapshotSelector::AsOf(time),
+      None => fail("Invalid snapshot (expected an id or a date)".to_string(), snapshot),
+    },
+  }
+}
+
+
+/// Split `name[@snapshot]` into a family name and a snapshot selector.
+fn parse_family_spec(spec: &str) -> (String, hat::SnapshotSelector) {
+  match spec.rfind('@') {
+    None => (spec.to_string(), hat::SnapshotSelector::Latest),
+    Some(pos) => (spec[..pos].to_string(), parse_snapshot(&spec[pos + 1..])),
+  }
+}
+
+
+/// Remove the snapshot selection options from `args`.
+fn take_snapshot_selector(args: &mut Vec<OsString>) -> hat::SnapshotSelector {
+  let id_opt = take_text_option(args, "--id");
+  let as_of_opt = take_text_option(args, "--as-of");
+  match (id_opt, as_of_opt) {
+    (Some(_), Some(_)) => fail("Options --id and --as-of".to_string(), "cannot be combined"),
+    (Some(id), None) => match id.parse() {
+      Ok(id) => hat::SnapshotSelector::Id(id),
+      Err(e) => fail(format!("Invalid snapshot id '{}'", id), e),
+    },
+    (None, Some(date)) => match parse_time(&date) {
+      Some(time) => hat::SnapshotSelector::AsOf(time),
+      None => fail("Invalid date (expected YYYY-MM-DD[ HH:MM:SS])".to_string(), date),
+    },
+    (None, None) => hat::SnapshotSelector::Latest,
+  }
+}
+
+
+fn format_time(seconds: i64) -> String {
+  time::at_utc(time::Timespec::new(seconds, 0)).rfc3339().to_string()
+}
+
+
+#[cfg(not(test))]
+fn print_snapshots(snapshots: Vec<snapshot_index::SnapshotInfo>) {
+  for s in snapshots.iter() {
+    let m = &s.metadata;
+    println!("{}\t{}\t{}\t{}\t{}@{}\t{}\t{} files\t{} bytes\t{}\t{}", s.id, s.family,
+             format_time(s.created), s.hash.bytes.to_hex(), m.user, m.hostname,
+             String::from_utf8_lossy(&m.source[..]),
+             m.file_count, m.bytes, m.tags.connect(","), m.message);
+  }
+}
+
+
+#[cfg(not(test))]
+fn print_entries(entries: Vec<(PathBuf, tree_entry::TreeEntry)>) {
+  for &(ref path, ref entry) in entries.iter() {
+    let kind = match entry.kind {
+      tree_entry::EntryKind::Directory => "d",
+      tree_entry::EntryKind::File => "-",
+      tree_entry::EntryKind::Symlink => "l",
+      tree_entry::EntryKind::Fifo => "p",
+      tree_entry::EntryKind::Socket => "s",
+      tree_entry::EntryKind::CharDevice => "c",
+      tree_entry::EntryKind::BlockDevice => "b",
+    };
+    // Like ls, show the major and minor number of devices instead of their size:
+    let size = match entry.device {
+      Some((major, minor)) => format!("{}, {}", major, minor),
+      None => entry.size.map(|s| s.to_string()).unwrap_or("-".to_string()),
+    };
+    // Entry times are in milliseconds:
+    let modified = format_time((entry.modified / 1000) as i64);
+    let hash = if entry.hash.bytes.len() > 0 { entry.hash.bytes.to_hex()[..16].to_string() }
+               else { format!("{:<16}", "-") };
+    match entry.link_target {
+      Some(ref target) => println!("{} {:>12} {} {} {} -> {}", kind, size, modified, hash,
+                                   path.display(), String::from_utf8_lossy(&target[..])),
+      None => println!("{} {:>12} {} {} {}", kind, size, modified, hash, path.display()),
+    }
+  }
+}
+
+
+#[cfg(not(test))]
+fn print_differences(differences: Vec<diff::Difference>) {
+  for d in differences.iter() {
+    let change = match d.change {
+      diff::Change::Added => "A",
+      diff::Change::Removed => "D",
+      diff::Change::Modified => "M",
+      diff::Change::MetadataChanged => "m",
+    };
+    println!("{} {}", change, d.path.display());
+  }
+}
+
+
+#[cfg(not(test))]
+fn print_verify_report(report: &verify::Report) {
+  for d in report.damage.iter() {
+    let description = match d.damage {
+      verify::Damage::MissingHash(ref h) =>
+        format!("hash {} is missing from the hash index", h.bytes.to_hex()),
+      verify::Damage::UncommittedHash(ref h) =>
+        format!("hash {} was never committed", h.bytes.to_hex()),
+      verify::Damage::InvalidReference(ref h) =>
+        format!("hash {} has an invalid blob reference", h.bytes.to_hex()),
+      verify::Damage::MissingBlob{ref hash, ref blob, ref error} =>
+        format!("blob {} of hash {} could not be read: {}", blob.to_hex(), hash.bytes.to_hex(),
+                error),
+      verify::Damage::TruncatedBlob{ref hash, ref blob, length, expected} =>
+        format!("blob {} of hash {} has {} bytes, expected at least {}", blob.to_hex(),
+                hash.bytes.to_hex(), length, expected),
+      verify::Damage::Corrupt(ref h) =>
+        format!("data of hash {} does not match its hash", h.bytes.to_hex()),
+      verify::Damage::InvalidListing(ref h) =>
+        format!("directory listing {} could not be decoded", h.bytes.to_hex()),
+    };
+    println!("{}@{}: {}: {}", d.family, d.snapshot_id, d.path.display(), description);
+  }
+  println!("Checked {} snapshots, {} directories and {} chunks: {} problems found",
+           report.snapshots, report.directories, report.chunks, report.damage.len());
+}
+
+
 #[cfg(not(test))]
 fn main() {
   // Initialize sodium (must only be called once)
   sodiumoxide::init();
 
-  let mut args = env::args();
+  // Paths are passed on as they are, whether they are valid UTF-8 or not:
+  let mut args: Vec<OsString> = env::args_os().collect();
 
   if args.len() < 2 {
     return usage(); // There's not even a command here.
   }
-  args.next(); // strip called name
+  args.remove(0); // strip called name
+
+  // `--repo` is only an option before the command; after it, it may be e.g. a path to back up:
+  let repository_root = if args.get(0).map_or(false, |a| &a[..] == "--repo") {
+    args.remove(0);
+    if args.len() == 0 {
+      fail("Option --repo".to_string(), "expects a value");
+    }
+    PathBuf::from(&args.remove(0))
+  } else {
+    default_repository()
+  };
 
-  if args.len() == 1 {
-    let ref flag = args.next().unwrap();
-    if flag == &"--license".to_string() {
+  if args.len() == 0 {
+    return usage();
+  }
+
+  if args.len() == 1 && args[0].to_str().map_or(false, |a| a.starts_with("--")) {
+    let ref flag = args[0];
+    if &flag[..] == "--license" {
         license();
     }
-    else if flag == &"--help".to_string() {
+    else if &flag[..] == "--help" {
       usage();
       license();
     }
     return;
   }
 
-  let ref cmd = args.next().unwrap();
-
-  if cmd == &"snapshot".to_string() && args.len() == 2 {
-    let ref name = args.next().unwrap();  // used for naming the key index
-    let ref path = args.next().unwrap();
+  let cmd = text(&args.remove(0));
 
-    {
-      let backend = blob_store::FileBackend::new(blob_dir());
-      let hat = hat::Hat::open_repository(&PathBuf::from("repo"), backend, MAX_BLOB_SIZE);
+  if cmd == "init" {
+    init_repository(&repository_root, &mut args);
+    return;
+  }
+  else if cmd == "snapshots" && args.len() <= 1 {
+    let hat = open_repository(&repository_root);
+    match hat.list_snapshots(args.pop().map(|a| text(&a))) {
+      Ok(snapshots) => print_snapshots(snapshots),
+      Err(e) => fail("Could not list snapshots".to_string(), e),
+    }
+    return;
+  }
+  else if cmd == "ls" {
+    let recursive = take_flag(&mut args, "-r");
+    if args.len() == 1 || args.len() == 2 {
+      let (name, selector) = parse_family_spec(&text(&args[0]));
+      let path = args.get(1).map(|p| PathBuf::from(p)).unwrap_or_else(PathBuf::new);
+
+      let hat = open_repository(&repository_root);
+
+      match hat.list_path(&name, &selector, &path, recursive) {
+        Ok(entries) => print_entries(entries),
+        Err(e) => fail(format!("Could not list '{}'", path.display()), e),
+      }
+      return;
+    }
+  }
+  else if cmd == "cat" && args.len() == 2 {
+    let (name, selector) = parse_family_spec(&text(&args[0]));
+    let path = PathBuf::from(&args[1]);
 
-      let family_opt = hat.open_family(name.clone());
-      let family = family_opt.expect(&format!("Could not open family '{}'", name));
+    let hat = open_repository(&repository_root);
 
-      family.snapshot_dir(PathBuf::from(path));
-      family.flush();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if let Err(e) = hat.cat_file(&name, &selector, &path, &mut out) {
+      fail(format!("Could not read '{}'", path.display()), e);
     }
-
-    println!("Waiting for final flush...");
     return;
   }
-  else if cmd == &"checkout".to_string() && args.len() == 2 {
-    let ref name = args.next().unwrap();  // used for naming the key index
-    let ref path = args.next().unwrap();
+  else if cmd == "diff" && args.len() == 3 {
+    let ref name = text(&args[0]);
+    let old = parse_snapshot(&text(&args[1]));
+    let new = parse_snapshot(&text(&args[2]));
 
-    let backend = blob_store::FileBackend::new(blob_dir());
-    let hat = hat::Hat::open_repository(&PathBuf::from("repo"), backend, MAX_BLOB_SIZE);
+    let hat = open_repository(&repository_root);
 
-    hat.checkout_in_dir(name.clone(), PathBuf::from(path));
+    match hat.diff(name, &old, &new) {
+      Ok(differences) => print_differences(differences),
+      Err(e) => fail("Could not compare snapshots".to_string(), e),
+    }
     return;
   }
-  else if cmd == &"commit".to_string() && args.len() == 1 {
-    let ref name = args.next().unwrap();
+  else if cmd == "verify" {
+    let check_data = take_flag(&mut args, "--data");
+    if args.len() <= 1 {
+      let hat = open_repository(&repository_root);
+
+      let report = match hat.verify(args.pop().map(|a| text(&a)), check_data) {
+        Ok(report) => report,
+        Err(e) => fail(format!("Could not verify '{}'", repository_root.display()), e),
+      };
+      print_verify_report(&report);
+      if !report.is_ok() {
+        fail(format!("Repository '{}'", repository_root.display()), "damaged");
+      }
+      return;
+    }
+  }
+  else if cmd == "gc" {
+    let dry_run = take_flag(&mut args, "--dry-run");
+    if args.len() == 0 {
+      let hat = open_repository(&repository_root);
+
+      let plan = match hat.gc(dry_run) {
+        Ok(plan) => plan,
+        Err(e) => fail("Could not collect garbage".to_string(), e),
+      };
+      let verb = if dry_run { "Would remove" } else { "Removed" };
+      println!("{} {} of {} hashes and {} of {} blobs ({} bytes)", verb,
+               plan.dead_hashes.len(), plan.live_hashes + plan.dead_hashes.len(),
+               plan.dead_blobs.len(), plan.live_blobs + plan.dead_blobs.len(),
+               plan.reclaimed_bytes);
+      if plan.unreclaimed_bytes > 0 {
+        println!("{} bytes of unused data remain in blobs that are still in use",
+                 plan.unreclaimed_bytes);
+      }
+      return;
+    }
+  }
+  else if cmd == "forget" {
+    let dry_run = take_flag(&mut args, "--dry-run");
+    let mut policy: retention::Policy = Default::default();
+    take_number_option(&mut args, "--keep-last").map(|n| policy.last = n);
+    take_number_option(&mut args, "--keep-daily").map(|n| policy.daily = n);
+    take_number_option(&mut args, "--keep-weekly").map(|n| policy.weekly = n);
+    take_number_option(&mut args, "--keep-monthly").map(|n| policy.monthly = n);
+    policy.tags = take_all_options(&mut args, "--keep-tag");
+    if policy.is_empty() {
+      fail("No retention policy given".to_string(), "refusing to forget anything");
+    }
+    if args.len() == 1 {
+      let ref name = text(&args[0]);
+
+      let hat = open_repository(&repository_root);
+
+      let decision = match hat.forget(name, &policy, dry_run) {
+        Ok(decision) => decision,
+        Err(e) => fail(format!("Could not forget snapshots of '{}'", name), e),
+      };
+      for s in decision.keep.iter() {
+        println!("keep\t{}\t{}", s.id, format_time(s.created));
+      }
+      for s in decision.forget.iter() {
+        println!("{}\t{}\t{}", if dry_run { "would forget" } else { "forget" }, s.id,
+                 format_time(s.created));
+      }
+      return;
+    }
+  }
+  else if cmd == "snapshot" && args.len() == 2 {
+    let ref name = text(&args[0]);  // used for naming the key index
+    let ref path = args[1];
 
-    let backend = blob_store::FileBackend::new(blob_dir());
-    let hat = hat::Hat::open_repository(&PathBuf::from("repo"), backend, MAX_BLOB_SIZE);
+    {
+      let hat = open_repository(&repository_root);
+
+      let family = match hat.open_family(name.clone()) {
+        Ok(family) => family,
+        Err(e) => fail(format!("Could not open family '{}'", name), e),
+      };
+
+      if let Err(e) = family.snapshot_dir(PathBuf::from(path)) {
+        fail(format!("Could not snapshot '{}'", name), e);
+      }
+    }
 
-    hat.commit(name.clone());
+    println!("Waiting for final flush...");
     return;
   }
+  else if cmd == "checkout" {
+    let selector = take_snapshot_selector(&mut args);
+    let only = take_option(&mut args, "--only").map(|p| PathBuf::from(&p));
+    let no_owner = take_flag(&mut args, "--no-owner");
+    if args.len() == 2 {
+      let ref name = text(&args[0]);  // used for naming the key index
+      let ref path = args[1];
+
+      let hat = open_repository(&repository_root);
+
+      if let Err(e) = hat.checkout_in_dir(name.clone(), PathBuf::from(path), selector, only,
+                                          !no_owner) {
+        fail(format!("Could not check out '{}'", name), e);
+      }
+      return;
+    }
+  }
+  else if cmd == "backup" {
+    let tags = take_all_options(&mut args, "--tag");
+    let message = take_text_option(&mut args, "-m").unwrap_or(String::new());
+    if args.len() == 2 {
+      let ref name = text(&args[0]);
+      let ref path = args[1];
+
+      let hat = open_repository(&repository_root);
+
+      match hat.backup(name.clone(), PathBuf::from(path), tags, message) {
+        Ok(metadata) => println!("Backed up {} files ({} bytes) from {}", metadata.file_count,
+                                 metadata.bytes, String::from_utf8_lossy(&metadata.source[..])),
+        Err(e) => fail(format!("Backup of '{}' failed", name), e),
+      }
+      return;
+    }
+  }
+  else if cmd == "commit" {
+    let tags = take_all_options(&mut args, "--tag");
+    let message = take_text_option(&mut args, "-m").unwrap_or(String::new());
+    if args.len() == 1 {
+      let ref name = text(&args[0]);
+
+      let hat = open_repository(&repository_root);
+
+      if let Err(e) = hat.commit(name.clone(), tags, message) {
+        fail(format!("Could not commit '{}'", name), e);
+      }
+      return;
+    }
+  }
 
   usage();
 
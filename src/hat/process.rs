@@ -90,6 +90,31 @@ impl <Msg:'static + Send, Reply:'static + Send>
     p
   }
 
+  /// Create and start a new process using the handler returned by `handler_proc`, or return the
+  /// error that creating the handler failed with.
+  pub fn new_or_fail<H, E>(handler_proc: Thunk<'static, (), Result<H, E>>)
+                           -> Result<Process<Msg, Reply>, E>
+    where H: 'static + MsgHandler<Msg, Reply>, E: 'static + Send
+  {
+    let (sender, receiver) = mpsc::sync_channel(10);
+    let (started, start_result) = mpsc::channel();
+
+    thread::spawn(move|| {
+      // fork handler, reporting back whether it could be created
+      match handler_proc() {
+        Ok(my_handler) => {
+          started.send(Ok(())).unwrap();
+          serve(my_handler, receiver);
+        },
+        Err(e) => started.send(Err(e)).unwrap(),
+      }
+    });
+
+    match start_result.recv().unwrap() {
+      Ok(()) => Ok(Process{sender: sender}),
+      Err(e) => Err(e),
+    }
+  }
 
   fn start<H>(&self, receiver: mpsc::Receiver<(Msg, Option<mpsc::Sender<Reply>>)>,
               handler_proc: Thunk<'static, (), H>)
@@ -97,18 +122,7 @@ impl <Msg:'static + Send, Reply:'static + Send>
   {
     thread::spawn(move|| {
       // fork handler
-      let mut my_handler = handler_proc();
-      loop {
-        match receiver.recv() {
-          Ok((msg, None)) => {
-            my_handler.handle(msg, Box::new(|_r: Reply| {}));
-          },
-          Ok((msg, Some(rep))) => {
-            my_handler.handle(msg, Box::new(move|r| { rep.send(r).unwrap(); }));
-          },
-          Err(_recv_error) => break,
-        };
-      };
+      serve(handler_proc(), receiver);
     });
   }
 
@@ -121,3 +135,21 @@ impl <Msg:'static + Send, Reply:'static + Send>
     return receiver.recv().unwrap();
   }
 }
+
+/// Let `my_handler` handle messages from `receiver` until all senders are gone.
+fn serve<Msg, Reply, H>(mut my_handler: H,
+                        receiver: mpsc::Receiver<(Msg, Option<mpsc::Sender<Reply>>)>)
+  where Msg: 'static + Send, Reply: 'static + Send, H: MsgHandler<Msg, Reply>
+{
+  loop {
+    match receiver.recv() {
+      Ok((msg, None)) => {
+        my_handler.handle(msg, Box::new(|_r: Reply| {}));
+      },
+      Ok((msg, Some(rep))) => {
+        my_handler.handle(msg, Box::new(move|r| { rep.send(r).unwrap(); }));
+      },
+      Err(_recv_error) => break,
+    };
+  };
+}
@@ -0,0 +1,130 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! On-disk layout of a repository and the manifest recording its format.
+//!
+//! A repository is a directory containing the manifest, the configuration, the snapshot, blob and
+//! hash indexes and one key index per family. The manifest is written last when a repository is
+//! created, so a directory without a manifest was either never initialised or its creation did not
+//! finish.
+
+use rustc_serialize::json;
+
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use config::{RepositoryConfig};
+use errors::{HatError, HatResult};
+
+
+/// Name recorded in the manifest of every hat repository.
+pub static FORMAT_NAME: &'static str = "hat-backup";
+
+/// The newest repository format understood by this version of hat.
+///
+/// Version 2 added columns to the snapshot index (creation time and snapshot metadata) and to the
+/// key indexes (file metadata, links, extended attributes and snapshot runs). Repositories in an
+/// older format are upgraded when they are opened.
+pub static FORMAT_VERSION: u64 = 2;
+
+static MANIFEST_FILENAME: &'static str = "format.json";
+
+
+#[derive(Clone, Debug, Eq, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Manifest {
+  pub format: String,
+  pub version: u64,
+}
+
+impl Manifest {
+  pub fn current() -> Manifest {
+    Manifest{format: FORMAT_NAME.to_string(), version: FORMAT_VERSION}
+  }
+}
+
+
+/// Location of the format manifest in the repository at `root`.
+pub fn manifest_path(root: &PathBuf) -> PathBuf {
+  let mut path = root.clone();
+  path.push(MANIFEST_FILENAME);
+  path
+}
+
+fn read_manifest(root: &PathBuf) -> Result<Option<Manifest>, String> {
+  let path = manifest_path(root);
+  let mut fd = match fs::File::open(&path) {
+    Ok(fd) => fd,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(format!("Could not open '{}': {}", path.display(), e)),
+  };
+  let mut text = String::new();
+  if let Err(e) = fd.read_to_string(&mut text) {
+    return Err(format!("Could not read '{}': {}", path.display(), e));
+  }
+  match json::decode(&text) {
+    Ok(m) => Ok(Some(m)),
+    Err(e) => Err(format!("Invalid manifest '{}': {:?}", path.display(), e)),
+  }
+}
+
+/// Check that `root` contains a fully initialised repository in a format we understand, returning
+/// its format version. A repository with an older version must be upgraded before it is used.
+pub fn check(root: &PathBuf) -> Result<u64, String> {
+  match try!(read_manifest(root)) {
+    None => Err(format!("'{}' is not an initialised hat repository (see `hat init`)",
+                        root.display())),
+    Some(ref m) if m.format != FORMAT_NAME =>
+      Err(format!("'{}' has unknown repository format '{}'", root.display(), m.format)),
+    Some(ref m) if m.version > FORMAT_VERSION =>
+      Err(format!("'{}' has repository format version {}, but this hat only supports \
+                   versions up to {}", root.display(), m.version, FORMAT_VERSION)),
+    Some(m) => Ok(m.version),
+  }
+}
+
+/// Create the directories and configuration of a new repository at `root`.
+///
+/// The indexes must be created afterwards, followed by a call to `finish_layout` to mark the
+/// repository as initialised. A repository whose initialisation did not finish (i.e. without a
+/// manifest) can be initialised again; its configuration is replaced.
+pub fn create_layout(root: &PathBuf, config: &RepositoryConfig) -> Result<(), String> {
+  if try!(read_manifest(root)).is_some() {
+    return Err(format!("'{}' is already an initialised hat repository", root.display()));
+  }
+
+  for dir in vec![root.clone(), config.blob_path(root)].iter() {
+    if let Err(e) = fs::create_dir_all(dir) {
+      return Err(format!("Could not create '{}': {}", dir.display(), e));
+    }
+  }
+  config.write(root)
+}
+
+/// Mark the repository at `root` as initialised, or as upgraded to the current format, by writing
+/// its manifest.
+pub fn finish_layout(root: &PathBuf) -> HatResult<()> {
+  let path = manifest_path(root);
+  let text = json::as_pretty_json(&Manifest::current()).to_string();
+  let mut fd = match fs::File::create(&path) {
+    Ok(fd) => fd,
+    Err(e) => return Err(HatError::Io(e.kind(),
+                                      format!("Could not create '{}': {}", path.display(), e))),
+  };
+  match fd.write_all(text.as_bytes()) {
+    Ok(()) => Ok(()),
+    Err(e) => Err(HatError::Io(e.kind(), format!("Could not write '{}': {}", path.display(), e))),
+  }
+}
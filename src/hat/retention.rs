@@ -0,0 +1,193 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Retention policies deciding which snapshots of a family to keep.
+//!
+//! A snapshot is kept if any rule of the policy selects it. The periodic rules keep the newest
+//! snapshot of each of the last N days, weeks or months that have snapshots. Periods are in UTC
+//! and weeks start on Monday. Tagged snapshots can be kept regardless of their age.
+
+use std::collections::{HashSet};
+
+use time;
+
+use snapshot_index::{SnapshotInfo};
+
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Policy {
+  /// Number of most recent snapshots to keep.
+  pub last: usize,
+
+  pub daily: usize,
+  pub weekly: usize,
+  pub monthly: usize,
+
+  /// Snapshots with any of these tags are always kept.
+  pub tags: Vec<String>,
+}
+
+impl Policy {
+  /// A policy without any rules. Applying it keeps everything.
+  pub fn is_empty(&self) -> bool {
+    self.last == 0 && self.daily == 0 && self.weekly == 0 && self.monthly == 0 &&
+      self.tags.len() == 0
+  }
+}
+
+
+/// The outcome of applying a policy. Both lists are ordered newest first.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Decision {
+  pub keep: Vec<SnapshotInfo>,
+  pub forget: Vec<SnapshotInfo>,
+}
+
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+fn day(created: i64) -> i64 {
+  // Floor division, so that times before the epoch land on the right day:
+  if created >= 0 { created / SECONDS_PER_DAY }
+  else { (created - SECONDS_PER_DAY + 1) / SECONDS_PER_DAY }
+}
+
+fn week(created: i64) -> i64 {
+  // The epoch was a Thursday; shift by three days to start weeks on Monday:
+  let d = day(created) + 3;
+  if d >= 0 { d / 7 } else { (d - 6) / 7 }
+}
+
+fn month(created: i64) -> i64 {
+  let tm = time::at_utc(time::Timespec::new(created, 0));
+  tm.tm_year as i64 * 12 + tm.tm_mon as i64
+}
+
+
+/// Mark the newest snapshot of each of the first `count` periods (as given by `period`).
+fn keep_periodic<F>(snapshots: &Vec<SnapshotInfo>, count: usize, period: F,
+                    keep: &mut HashSet<u64>) where F: Fn(i64) -> i64 {
+  let mut last_period = None;
+  let mut kept = 0;
+  for s in snapshots.iter() {
+    if kept >= count {
+      break;
+    }
+    let p = period(s.created);
+    if last_period != Some(p) {
+      keep.insert(s.id);
+      last_period = Some(p);
+      kept += 1;
+    }
+  }
+}
+
+
+/// Decide which of `snapshots` (all of the same family) to keep under `policy`.
+pub fn apply(policy: &Policy, snapshots: Vec<SnapshotInfo>) -> Decision {
+  let mut snapshots = snapshots;
+  // Newest first; ids break ties between snapshots created in the same second:
+  snapshots.sort_by(|a, b| (b.created, b.id).cmp(&(a.created, a.id)));
+
+  if policy.is_empty() {
+    return Decision{keep: snapshots, forget: vec![]};
+  }
+
+  let mut keep = HashSet::new();
+  for s in snapshots.iter().take(policy.last) {
+    keep.insert(s.id);
+  }
+  keep_periodic(&snapshots, policy.daily, day, &mut keep);
+  keep_periodic(&snapshots, policy.weekly, week, &mut keep);
+  keep_periodic(&snapshots, policy.monthly, month, &mut keep);
+  for s in snapshots.iter() {
+    if s.metadata.tags.iter().any(|t| policy.tags.contains(t)) {
+      keep.insert(s.id);
+    }
+  }
+
+  let (kept, forgotten): (Vec<SnapshotInfo>, Vec<SnapshotInfo>) =
+    snapshots.into_iter().partition(|s| keep.contains(&s.id));
+  Decision{keep: kept, forget: forgotten}
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use hash_index::{Hash};
+  use snapshot_index::{SnapshotInfo};
+
+  // 2015-01-05 was a Monday.
+  const MONDAY: i64 = 1420416000;
+  const DAY: i64 = 24 * 60 * 60;
+
+  fn snapshots(times: Vec<i64>) -> Vec<SnapshotInfo> {
+    times.into_iter().enumerate().map(|(i, t)| {
+      SnapshotInfo{id: i as u64 + 1, family: "family".to_string(), created: t,
+                   hash: Hash::new(b""), tree_ref: vec![], metadata: Default::default()}
+    }).collect()
+  }
+
+  fn kept_ids(policy: Policy, times: Vec<i64>) -> Vec<u64> {
+    apply(&policy, snapshots(times)).keep.iter().map(|s| s.id).collect()
+  }
+
+  #[test]
+  fn empty_policy_keeps_everything() {
+    let decision = apply(&Default::default(), snapshots(vec![MONDAY, MONDAY + DAY]));
+    assert_eq!(decision.keep.len(), 2);
+    assert_eq!(decision.forget, vec![]);
+  }
+
+  #[test]
+  fn keep_last() {
+    let times = vec![MONDAY, MONDAY + 1, MONDAY + 2, MONDAY + 3];
+    assert_eq!(kept_ids(Policy{last: 2, ..Default::default()}, times), vec![4, 3]);
+  }
+
+  #[test]
+  fn keep_daily() {
+    // Two snapshots on each of three days:
+    let times = vec![MONDAY, MONDAY + 10, MONDAY + DAY, MONDAY + DAY + 10,
+                     MONDAY + 2 * DAY, MONDAY + 2 * DAY + 10];
+    assert_eq!(kept_ids(Policy{daily: 2, ..Default::default()}, times), vec![6, 4]);
+  }
+
+  #[test]
+  fn keep_weekly_and_monthly() {
+    // Sunday, Monday and Tuesday in January, then a snapshot in February:
+    let times = vec![MONDAY - DAY, MONDAY, MONDAY + DAY, MONDAY + 31 * DAY];
+    assert_eq!(kept_ids(Policy{weekly: 2, ..Default::default()}, times.clone()), vec![4, 3]);
+    assert_eq!(kept_ids(Policy{monthly: 3, ..Default::default()}, times), vec![4, 3]);
+  }
+
+  #[test]
+  fn keep_tagged() {
+    let mut all = snapshots(vec![MONDAY, MONDAY + 1, MONDAY + 2]);
+    all[0].metadata.tags = vec!["release".to_string()];
+    let policy = Policy{last: 1, tags: vec!["release".to_string()], ..Default::default()};
+    let decision = apply(&policy, all);
+    assert_eq!(decision.keep.iter().map(|s| s.id).collect::<Vec<u64>>(), vec![3, 1]);
+  }
+
+  #[test]
+  fn rules_combine() {
+    let times = vec![MONDAY - DAY, MONDAY, MONDAY + DAY];
+    let decision = apply(&Policy{last: 1, weekly: 2, ..Default::default()}, snapshots(times));
+    assert_eq!(decision.keep.iter().map(|s| s.id).collect::<Vec<u64>>(), vec![3, 1]);
+    assert_eq!(decision.forget.iter().map(|s| s.id).collect::<Vec<u64>>(), vec![2]);
+  }
+}
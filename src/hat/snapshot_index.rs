@@ -16,23 +16,96 @@
 
 use process;
 
+use rustc_serialize::json;
+
 use sqlite3::database::{Database};
-use sqlite3::BindArg::{Blob};
+use sqlite3::cursor::{Cursor};
+use sqlite3::BindArg;
+use sqlite3::BindArg::{Blob, Integer64};
 use sqlite3::types::ResultCode::{SQLITE_ROW, SQLITE_DONE, SQLITE_OK};
 use sqlite3::{open};
 
+use errors::{HatError, HatResult};
 use hash_index;
 
+use time;
+
 
 pub type SnapshotIndexProcess = process::Process<Msg, Reply>;
 
+
+/// Columns added to the snapshot index after its first version, with their types.
+static ADDED_COLUMNS: [(&'static str, &'static str); 8] = [
+  ("created", "INTEGER"), ("hostname", "BLOB"), ("user", "BLOB"), ("source", "BLOB"),
+  ("tags", "BLOB"), ("message", "BLOB"), ("files", "INTEGER"), ("bytes", "INTEGER")];
+
+
+/// Descriptive information recorded with a snapshot. Empty strings mean unknown.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SnapshotMetadata {
+  pub hostname: String,
+  pub user: String,
+
+  /// The directory that the snapshot was taken of, as raw bytes (it need not be valid UTF-8).
+  pub source: Vec<u8>,
+
+  pub tags: Vec<String>,
+  pub message: String,
+
+  /// Number of files in the snapshot and their total size.
+  pub file_count: u64,
+  pub bytes: u64,
+}
+
+/// A snapshot as registered in the index.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SnapshotInfo {
+  pub id: u64,
+  pub family: String,
+
+  /// Time of registration in seconds since the epoch.
+  pub created: i64,
+
+  pub hash: hash_index::Hash,
+  pub tree_ref: Vec<u8>,
+
+  pub metadata: SnapshotMetadata,
+}
+
+/// Any message is answered with `Error` instead when the index cannot be read or written, or
+/// holds undecodable snapshots.
 pub enum Msg {
   /// Register a new snapshot by its hash and persistent reference.
-  Add(String, hash_index::Hash, Vec<u8>),
+  Add(String, hash_index::Hash, Vec<u8>, SnapshotMetadata),
 
   /// Extract latest snapshot data for family.
   Latest(String),
 
+  /// Extract snapshot data for the snapshot with the given id, if it belongs to the family.
+  /// Returns `Snapshot`.
+  Lookup(String, u64),
+
+  /// Extract snapshot data for the latest snapshot of the family that was created at or before the
+  /// given time (in seconds since the epoch).
+  /// Returns `Snapshot`.
+  LatestAsOf(String, i64),
+
+  /// List all snapshots of a family, oldest first.
+  /// Returns `Snapshots`.
+  List(String),
+
+  /// List all snapshots of all families, ordered by family and then oldest first.
+  /// Returns `Snapshots`.
+  ListAll,
+
+  /// List the names of all families that have at least one snapshot.
+  /// Returns `Families`.
+  ListFamilies,
+
+  /// Remove the snapshot with the given id from the index. Its data is left untouched.
+  /// Returns `DeleteOK`.
+  Delete(u64),
+
   /// Flush the hash index to clear internal buffers and commit the underlying database.
   Flush,
 }
@@ -40,7 +113,12 @@ pub enum Msg {
 pub enum Reply {
   AddOK,
   Latest(Option<(hash_index::Hash, Vec<u8>)>),
+  Snapshot(Option<(hash_index::Hash, Vec<u8>)>),
+  Snapshots(Vec<SnapshotInfo>),
+  Families(Vec<String>),
+  DeleteOK,
   FlushOK,
+  Error(HatError),
 }
 
 
@@ -50,60 +128,241 @@ pub struct SnapshotIndex {
 
 impl SnapshotIndex {
 
-  pub fn new(path: String) -> SnapshotIndex {
+  pub fn new(path: String) -> HatResult<SnapshotIndex> {
     let mut si = match open(&path) {
       Ok(dbh) => { SnapshotIndex{dbh: dbh} },
-      Err(err) => panic!("{:?}", err),
+      Err(err) => return Err(HatError::index(format!("Could not open snapshot index {}: {:?}",
+                                                  path, err))),
     };
-    si.exec_or_die("CREATE TABLE IF NOT EXISTS
-                    snapshot_index (id        INTEGER PRIMARY KEY,
-                                    family    BLOB,
-                                    hash      BLOB,
-                                    tree_ref  BLOB)");
-    si.exec_or_die("BEGIN");
-    si
+    try!(si.exec("CREATE TABLE IF NOT EXISTS
+                  snapshot_index (id        INTEGER PRIMARY KEY,
+                                  family    BLOB,
+                                  created   INTEGER,
+                                  hash      BLOB,
+                                  tree_ref  BLOB,
+                                  hostname  BLOB,
+                                  user      BLOB,
+                                  source    BLOB,
+                                  tags      BLOB,
+                                  message   BLOB,
+                                  files     INTEGER,
+                                  bytes     INTEGER)"));
+    try!(si.add_missing_columns());
+    try!(si.exec("BEGIN"));
+    Ok(si)
   }
 
   #[cfg(test)]
   pub fn new_for_testing() -> SnapshotIndex {
-    SnapshotIndex::new(":memory:".to_string())
+    SnapshotIndex::new(":memory:".to_string()).unwrap()
   }
 
-  fn exec_or_die(&mut self, sql: &str) {
+  fn exec(&mut self, sql: &str) -> HatResult<()> {
     match self.dbh.exec(sql) {
-      Ok(true) => (),
-      Ok(false) => panic!("exec: {:?}", self.dbh.get_errmsg()),
-      Err(msg) => panic!("exec: {:?}, {:?}\nIn sql: '{}'\n", msg, self.dbh.get_errmsg(), sql)
+      Ok(true) => Ok(()),
+      Ok(false) => Err(self.error(&format!("in '{}'", sql))),
+      Err(msg) => Err(self.error(&format!("{:?}, in '{}'", msg, sql))),
+    }
+  }
+
+  fn prepare<'a>(&'a self, sql: &str) -> HatResult<Cursor<'a>> {
+    match self.dbh.prepare(sql, &None) {
+      Ok(s) => Ok(s),
+      Err(x) => Err(self.error(&format!("{:?}", x))),
+    }
+  }
+
+  /// Describe the last failed database call.
+  fn error(&self, context: &str) -> HatError {
+    HatError::index(format!("Snapshot index: {} ({})", self.dbh.get_errmsg(), context))
+  }
+
+  /// Bind `params` to the parameters of `cursor`, in order.
+  fn bind(&self, cursor: &mut Cursor, params: Vec<BindArg>) -> HatResult<()> {
+    for (i, param) in params.into_iter().enumerate() {
+      if cursor.bind_param(i as i32 + 1, &param) != SQLITE_OK {
+        return Err(self.error("binding parameters"));
+      }
+    }
+    Ok(())
+  }
+
+  /// Add the columns that snapshot indexes created by older versions lack. Their snapshots get
+  /// empty metadata, and are taken to be created at the epoch (so that they are older than any
+  /// time they are looked up as of).
+  fn add_missing_columns(&mut self) -> HatResult<()> {
+    let existing = {
+      let mut cursor = try!(self.prepare("PRAGMA table_info(snapshot_index)"));
+      let mut names = Vec::new();
+      while cursor.step() == SQLITE_ROW {
+        names.push(String::from_utf8_lossy(cursor.get_blob(1).unwrap_or(&[])).into_owned());
+      }
+      names
+    };
+    for &(name, column_type) in ADDED_COLUMNS.iter() {
+      if !existing.iter().any(|c| &c[..] == name) {
+        try!(self.exec(&format!("ALTER TABLE snapshot_index ADD COLUMN {} {}",
+                                name, column_type)));
+      }
+    }
+    self.exec("UPDATE snapshot_index SET created=0 WHERE created IS NULL")
+  }
+
+  fn add_snapshot(&mut self, family: String, hash: hash_index::Hash, tree_ref: Vec<u8>,
+                  metadata: SnapshotMetadata) -> HatResult<()> {
+    let created = time::get_time().sec;
+    let tags = json::encode(&metadata.tags).unwrap();
+    let mut insert_stm = try!(self.prepare(
+      "INSERT INTO snapshot_index (family, created, hash, tree_ref, hostname, user, source, tags,
+                                   message, files, bytes)
+       VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"));
+
+    try!(self.bind(&mut insert_stm, vec![Blob(family.into_bytes()),
+                                         Integer64(created),
+                                         Blob(hash.bytes.clone()),
+                                         Blob(tree_ref),
+                                         Blob(metadata.hostname.into_bytes()),
+                                         Blob(metadata.user.into_bytes()),
+                                         Blob(metadata.source),
+                                         Blob(tags.into_bytes()),
+                                         Blob(metadata.message.into_bytes()),
+                                         Integer64(metadata.file_count as i64),
+                                         Integer64(metadata.bytes as i64)]));
+
+    if insert_stm.step() != SQLITE_DONE {
+      return Err(self.error("adding snapshot"));
+    }
+    Ok(())
+  }
+
+  /// Read the hash and tree reference of the first snapshot found by `lookup_stm`, if any.
+  fn read_snapshot_ref(lookup_stm: &mut Cursor) -> HatResult<Option<(hash_index::Hash, Vec<u8>)>> {
+    if lookup_stm.step() != SQLITE_ROW {
+      return Ok(None);
+    }
+    match (lookup_stm.get_blob(0), lookup_stm.get_blob(1)) {
+      (Some(hash), Some(tree_ref)) =>
+        Ok(Some((hash_index::Hash{bytes: hash.to_vec()}, tree_ref.to_vec()))),
+      _ => Err(HatError::CorruptData("Snapshot without hash or tree reference".to_string())),
+    }
+  }
+
+  fn latest_snapshot(&mut self, family: String)
+                     -> HatResult<Option<(hash_index::Hash, Vec<u8>)>> {
+    let mut lookup_stm = try!(self.prepare(
+      "SELECT hash, tree_ref FROM snapshot_index WHERE family=? ORDER BY id DESC"));
+
+    try!(self.bind(&mut lookup_stm, vec![Blob(family.into_bytes())]));
+
+    SnapshotIndex::read_snapshot_ref(&mut lookup_stm)
+  }
+
+  fn lookup_snapshot(&mut self, family: String, id: u64)
+                     -> HatResult<Option<(hash_index::Hash, Vec<u8>)>> {
+    let mut lookup_stm = try!(self.prepare(
+      "SELECT hash, tree_ref FROM snapshot_index WHERE family=? AND id=?"));
+
+    try!(self.bind(&mut lookup_stm, vec![Blob(family.into_bytes()), Integer64(id as i64)]));
+
+    SnapshotIndex::read_snapshot_ref(&mut lookup_stm)
+  }
+
+  fn latest_snapshot_as_of(&mut self, family: String, time: i64)
+                           -> HatResult<Option<(hash_index::Hash, Vec<u8>)>> {
+    let mut lookup_stm = try!(self.prepare(
+      "SELECT hash, tree_ref FROM snapshot_index WHERE family=? AND created<=?
+       ORDER BY id DESC"));
+
+    try!(self.bind(&mut lookup_stm, vec![Blob(family.into_bytes()), Integer64(time)]));
+
+    SnapshotIndex::read_snapshot_ref(&mut lookup_stm)
+  }
+
+  /// Decode a family name; they are stored as given, so they must be valid UTF-8.
+  fn family_name(bytes: &[u8]) -> HatResult<String> {
+    match String::from_utf8(bytes.to_vec()) {
+      Ok(name) => Ok(name),
+      Err(_) => Err(HatError::CorruptData(format!("Invalid family name {:?}", bytes))),
+    }
+  }
+
+  fn read_snapshots(cursor: &mut Cursor) -> HatResult<Vec<SnapshotInfo>> {
+    let mut snapshots = Vec::new();
+    while cursor.step() == SQLITE_ROW {
+      let text = |cursor: &mut Cursor, i| {
+        String::from_utf8_lossy(cursor.get_blob(i).unwrap_or(&[])).into_owned()
+      };
+      let family = try!(SnapshotIndex::family_name(cursor.get_blob(1).unwrap_or(&[])));
+      let tags = json::decode(&text(cursor, 8)).unwrap_or(vec![]);
+      let (hash, tree_ref) = match (cursor.get_blob(3), cursor.get_blob(4)) {
+        (Some(hash), Some(tree_ref)) => (hash.to_vec(), tree_ref.to_vec()),
+        _ => return Err(HatError::CorruptData(format!(
+          "Snapshot {} of family '{}' has no hash or tree reference", cursor.get_i64(0), family))),
+      };
+      snapshots.push(SnapshotInfo{
+        id: cursor.get_i64(0) as u64,
+        family: family,
+        created: cursor.get_i64(2),
+        hash: hash_index::Hash{bytes: hash},
+        tree_ref: tree_ref,
+        metadata: SnapshotMetadata{
+          hostname: text(cursor, 5),
+          user: text(cursor, 6),
+          source: cursor.get_blob(7).unwrap_or(&[]).to_vec(),
+          tags: tags,
+          message: text(cursor, 9),
+          file_count: cursor.get_i64(10) as u64,
+          bytes: cursor.get_i64(11) as u64,
+        },
+      });
     }
+    Ok(snapshots)
   }
 
-  fn add_snapshot(&mut self, family: String, hash: hash_index::Hash, tree_ref: Vec<u8>) {
-    let mut insert_stm = self.dbh.prepare(
-      "INSERT INTO snapshot_index (family, hash, tree_ref) VALUES (?, ?, ?)", &None).unwrap();
+  fn list_snapshots(&mut self, family: String) -> HatResult<Vec<SnapshotInfo>> {
+    let mut list_stm = try!(self.prepare(
+      "SELECT id, family, created, hash, tree_ref, hostname, user, source, tags, message,
+              files, bytes
+       FROM snapshot_index WHERE family=? ORDER BY id"));
+
+    try!(self.bind(&mut list_stm, vec![Blob(family.into_bytes())]));
+
+    SnapshotIndex::read_snapshots(&mut list_stm)
+  }
 
-    assert_eq!(SQLITE_OK, insert_stm.bind_param(1, &Blob(family.as_bytes().iter().map(|&x| x).collect())));
-    assert_eq!(SQLITE_OK, insert_stm.bind_param(2, &Blob(hash.bytes.clone())));
-    assert_eq!(SQLITE_OK, insert_stm.bind_param(3, &Blob(tree_ref)));
+  fn list_all_snapshots(&mut self) -> HatResult<Vec<SnapshotInfo>> {
+    let mut list_stm = try!(self.prepare(
+      "SELECT id, family, created, hash, tree_ref, hostname, user, source, tags, message,
+              files, bytes
+       FROM snapshot_index ORDER BY family, id"));
 
-    assert_eq!(SQLITE_DONE, insert_stm.step());
+    SnapshotIndex::read_snapshots(&mut list_stm)
   }
 
-  fn latest_snapshot(&mut self, family: String) -> Option<(hash_index::Hash, Vec<u8>)> {
-    let mut lookup_stm = self.dbh.prepare(
-      "SELECT hash, tree_ref FROM snapshot_index WHERE family=? ORDER BY id DESC", &None).unwrap();
+  fn list_families(&mut self) -> HatResult<Vec<String>> {
+    let mut list_stm = try!(self.prepare(
+      "SELECT DISTINCT family FROM snapshot_index ORDER BY family"));
+
+    let mut families = Vec::new();
+    while list_stm.step() == SQLITE_ROW {
+      families.push(try!(SnapshotIndex::family_name(list_stm.get_blob(0).unwrap_or(&[]))));
+    }
+    Ok(families)
+  }
 
-    assert_eq!(SQLITE_OK, lookup_stm.bind_param(1, &Blob(family.as_bytes().to_vec())));
+  fn delete_snapshot(&mut self, id: u64) -> HatResult<()> {
+    let mut delete_stm = try!(self.prepare("DELETE FROM snapshot_index WHERE id=?"));
 
-    if lookup_stm.step() == SQLITE_ROW {
-      return Some((hash_index::Hash{bytes: lookup_stm.get_blob(0).unwrap().to_vec()},
-                   lookup_stm.get_blob(1).unwrap().to_vec()));
+    try!(self.bind(&mut delete_stm, vec![Integer64(id as i64)]));
+    if delete_stm.step() != SQLITE_DONE {
+      return Err(self.error("deleting snapshot"));
     }
-    return None;
+    Ok(())
   }
 
-  fn flush(&mut self) {
+  fn flush(&mut self) -> HatResult<()> {
     // Callbacks assume their data is safe, so commit before calling them
-    self.exec_or_die("COMMIT; BEGIN");
+    self.exec("COMMIT; BEGIN")
   }
 }
 
@@ -112,20 +371,172 @@ impl process::MsgHandler<Msg, Reply> for SnapshotIndex {
   fn handle(&mut self, msg: Msg, reply: Box<Fn(Reply)>) {
     match msg {
 
-      Msg::Add(name, hash, tree_ref) => {
-        self.add_snapshot(name, hash, tree_ref);
-        return reply(Reply::AddOK);
+      Msg::Add(name, hash, tree_ref, metadata) => {
+        match self.add_snapshot(name, hash, tree_ref, metadata) {
+          Ok(()) => return reply(Reply::AddOK),
+          Err(e) => return reply(Reply::Error(e)),
+        }
       },
 
       Msg::Latest(name) => {
-        let res_opt = self.latest_snapshot(name);
-        return reply(Reply::Latest(res_opt));
-      }
+        match self.latest_snapshot(name) {
+          Ok(res_opt) => return reply(Reply::Latest(res_opt)),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
+
+      Msg::Lookup(name, id) => {
+        match self.lookup_snapshot(name, id) {
+          Ok(res_opt) => return reply(Reply::Snapshot(res_opt)),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
+
+      Msg::LatestAsOf(name, time) => {
+        match self.latest_snapshot_as_of(name, time) {
+          Ok(res_opt) => return reply(Reply::Snapshot(res_opt)),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
+
+      Msg::List(name) => {
+        match self.list_snapshots(name) {
+          Ok(snapshots) => return reply(Reply::Snapshots(snapshots)),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
+
+      Msg::ListAll => {
+        match self.list_all_snapshots() {
+          Ok(snapshots) => return reply(Reply::Snapshots(snapshots)),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
+
+      Msg::ListFamilies => {
+        match self.list_families() {
+          Ok(families) => return reply(Reply::Families(families)),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
+
+      Msg::Delete(id) => {
+        match self.delete_snapshot(id) {
+          Ok(()) => return reply(Reply::DeleteOK),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+      },
 
       Msg::Flush => {
-        self.flush();
-        return reply(Reply::FlushOK);
+        match self.flush() {
+          Ok(()) => return reply(Reply::FlushOK),
+          Err(e) => return reply(Reply::Error(e)),
+        }
       }
     }
   }
 }
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use errors::{HatError};
+  use hash_index::{Hash};
+
+  #[test]
+  fn list_per_family_and_all() {
+    let mut si = SnapshotIndex::new_for_testing();
+    si.add_snapshot("foo".to_string(), Hash::new(b"1"), b"ref1".to_vec(), Default::default())
+      .unwrap();
+    si.add_snapshot("bar".to_string(), Hash::new(b"2"), b"ref2".to_vec(), Default::default())
+      .unwrap();
+    si.add_snapshot("foo".to_string(), Hash::new(b"3"), b"ref3".to_vec(), Default::default())
+      .unwrap();
+
+    let foo = si.list_snapshots("foo".to_string()).unwrap();
+    assert_eq!(foo.iter().map(|s| s.hash.clone()).collect::<Vec<Hash>>(),
+               vec![Hash::new(b"1"), Hash::new(b"3")]);
+    assert!(foo[0].id < foo[1].id);
+    assert!(foo.iter().all(|s| s.family == "foo".to_string()));
+
+    assert_eq!(si.list_snapshots("baz".to_string()).unwrap(), vec![]);
+
+    let all = si.list_all_snapshots().unwrap();
+    assert_eq!(all.iter().map(|s| s.tree_ref.clone()).collect::<Vec<Vec<u8>>>(),
+               vec![b"ref2".to_vec(), b"ref1".to_vec(), b"ref3".to_vec()]);
+
+    assert_eq!(si.list_families().unwrap(), vec!["bar".to_string(), "foo".to_string()]);
+    assert_eq!(si.latest_snapshot("foo".to_string()).unwrap(),
+               Some((Hash::new(b"3"), b"ref3".to_vec())));
+  }
+
+  #[test]
+  fn lookup_by_id_and_time() {
+    let mut si = SnapshotIndex::new_for_testing();
+    si.add_snapshot("foo".to_string(), Hash::new(b"1"), b"ref1".to_vec(), Default::default())
+      .unwrap();
+    si.add_snapshot("bar".to_string(), Hash::new(b"2"), b"ref2".to_vec(), Default::default())
+      .unwrap();
+
+    let foo = si.list_snapshots("foo".to_string()).unwrap().pop().unwrap();
+    let bar = si.list_snapshots("bar".to_string()).unwrap().pop().unwrap();
+
+    assert_eq!(si.lookup_snapshot("foo".to_string(), foo.id).unwrap(),
+               Some((Hash::new(b"1"), b"ref1".to_vec())));
+    // Ids of other families do not resolve:
+    assert_eq!(si.lookup_snapshot("foo".to_string(), bar.id).unwrap(), None);
+
+    assert_eq!(si.latest_snapshot_as_of("foo".to_string(), foo.created).unwrap(),
+               Some((Hash::new(b"1"), b"ref1".to_vec())));
+    assert_eq!(si.latest_snapshot_as_of("foo".to_string(), foo.created - 1).unwrap(), None);
+  }
+
+  #[test]
+  fn delete() {
+    let mut si = SnapshotIndex::new_for_testing();
+    si.add_snapshot("foo".to_string(), Hash::new(b"1"), b"ref1".to_vec(), Default::default())
+      .unwrap();
+    si.add_snapshot("foo".to_string(), Hash::new(b"2"), b"ref2".to_vec(), Default::default())
+      .unwrap();
+
+    let latest = si.list_snapshots("foo".to_string()).unwrap().pop().unwrap();
+    si.delete_snapshot(latest.id).unwrap();
+
+    assert_eq!(si.list_snapshots("foo".to_string()).unwrap().len(), 1);
+    assert_eq!(si.latest_snapshot("foo".to_string()).unwrap(),
+               Some((Hash::new(b"1"), b"ref1".to_vec())));
+  }
+
+  #[test]
+  fn metadata() {
+    let mut si = SnapshotIndex::new_for_testing();
+    let metadata = SnapshotMetadata{hostname: "host".to_string(), user: "user".to_string(),
+                                    source: b"/home/user".to_vec(),
+                                    tags: vec!["weekly".to_string(), "pre-upgrade".to_string()],
+                                    message: "Before the upgrade".to_string(),
+                                    file_count: 12, bytes: 3456};
+    si.add_snapshot("foo".to_string(), Hash::new(b"1"), b"ref1".to_vec(), metadata.clone())
+      .unwrap();
+
+    let snapshot = si.list_snapshots("foo".to_string()).unwrap().pop().unwrap();
+    assert_eq!(snapshot.metadata, metadata);
+  }
+
+  #[test]
+  fn invalid_family_name() {
+    let mut si = SnapshotIndex::new_for_testing();
+    si.exec("INSERT INTO snapshot_index (family, hash, tree_ref) VALUES (x'ff', x'01', x'02')")
+      .unwrap();
+
+    match si.list_families() {
+      Err(HatError::CorruptData(_)) => (),
+      r => panic!("Invalid family name was listed: {:?}", r),
+    }
+    match si.list_all_snapshots() {
+      Err(HatError::CorruptData(_)) => (),
+      r => panic!("Invalid family name was listed: {:?}", r),
+    }
+  }
+}
@@ -0,0 +1,437 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Entries of the directory listings in committed snapshots.
+//!
+//! A committed directory is a hash tree whose chunks are JSON lists of entries. Files refer to the
+//! hash tree of their data through `data_hash` and `data_ref`, and sub-directories refer to the
+//! hash tree of their own listing through `dir_hash` and `dir_ref`. Symlinks have no data; their
+//! target is stored as `link`. Files with several hard links record their device and inode as
+//! `dev` and `ino`, so that entries sharing them can be restored as links to a single file.
+//! Extended attributes (which include POSIX ACLs) are stored as a list of name and value pairs in
+//! `xattr`. FIFOs, sockets and device nodes have no data either; their kind is stored as `type`,
+//! and devices also record their `major` and `minor` numbers.
+
+use rustc_serialize::json;
+use rustc_serialize::json::{ToJson};
+use std::collections::{BTreeMap};
+
+use errors::{HatError, HatResult};
+use hash_index::{Hash};
+
+
+/// Extended attributes of an entry, by name.
+pub type XAttrs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EntryKind {
+  Directory,
+  File,
+  Symlink,
+  Fifo,
+  Socket,
+  CharDevice,
+  BlockDevice,
+}
+
+const S_IFMT: u64 = 0o170000;
+const S_IFDIR: u64 = 0o040000;
+const S_IFIFO: u64 = 0o010000;
+const S_IFCHR: u64 = 0o020000;
+const S_IFBLK: u64 = 0o060000;
+const S_IFSOCK: u64 = 0o140000;
+
+impl EntryKind {
+  /// Whether an entry with Unix mode `mode` is a directory. Without a recorded mode, directories
+  /// are the entries without data.
+  pub fn is_directory_mode(mode: Option<u64>, has_data: bool) -> bool {
+    match mode {
+      Some(mode) => mode & S_IFMT == S_IFDIR,
+      None => !has_data,
+    }
+  }
+
+  /// The kind of a special file (FIFO, socket or device node) with Unix mode `mode`; `None` for
+  /// anything else.
+  pub fn special_from_mode(mode: u64) -> Option<EntryKind> {
+    match mode & S_IFMT {
+      S_IFIFO => Some(EntryKind::Fifo),
+      S_IFSOCK => Some(EntryKind::Socket),
+      S_IFCHR => Some(EntryKind::CharDevice),
+      S_IFBLK => Some(EntryKind::BlockDevice),
+      _ => None,
+    }
+  }
+
+  /// The file type bits of the Unix mode of a special file.
+  pub fn special_mode(&self) -> Option<u64> {
+    match *self {
+      EntryKind::Fifo => Some(S_IFIFO),
+      EntryKind::Socket => Some(S_IFSOCK),
+      EntryKind::CharDevice => Some(S_IFCHR),
+      EntryKind::BlockDevice => Some(S_IFBLK),
+      _ => None,
+    }
+  }
+
+  fn special_name(&self) -> Option<&'static str> {
+    match *self {
+      EntryKind::Fifo => Some("fifo"),
+      EntryKind::Socket => Some("sock"),
+      EntryKind::CharDevice => Some("chr"),
+      EntryKind::BlockDevice => Some("blk"),
+      _ => None,
+    }
+  }
+
+  fn special_from_name(name: &str) -> Option<EntryKind> {
+    match name {
+      "fifo" => Some(EntryKind::Fifo),
+      "sock" => Some(EntryKind::Socket),
+      "chr" => Some(EntryKind::CharDevice),
+      "blk" => Some(EntryKind::BlockDevice),
+      _ => None,
+    }
+  }
+}
+
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TreeEntry {
+  pub id: u64,
+  pub name: Vec<u8>,
+  pub kind: EntryKind,
+
+  /// Size of the file data. Unknown for directories and entries committed before sizes were
+  /// recorded.
+  pub size: Option<u64>,
+
+  pub created: u64,
+  pub modified: u64,
+  pub accessed: u64,
+
+  /// Unix mode bits (permissions and file type), owner and group. Unknown for entries committed
+  /// before they were recorded.
+  pub permissions: Option<u64>,
+  pub user_id: Option<u64>,
+  pub group_id: Option<u64>,
+
+  /// Target of a symlink, as stored in the link (i.e. not resolved).
+  pub link_target: Option<Vec<u8>>,
+
+  /// Device and inode of a file with more than one hard link. Entries of a snapshot with the same
+  /// value are links to the same file.
+  pub hard_link: Option<(u64, u64)>,
+
+  pub xattrs: XAttrs,
+
+  /// Major and minor number of a character or block device.
+  pub device: Option<(u64, u64)>,
+
+  /// Top hash and persistent reference of the hash tree with the file data or directory listing.
+  /// Both are empty for symlinks and special files.
+  pub hash: Hash,
+  pub persistent_ref: Vec<u8>,
+}
+
+
+fn bytes_from_json(j: &json::Json) -> Option<Vec<u8>> {
+  j.as_array().and_then(|a| {
+    let bytes: Vec<u8> = a.iter()
+      .filter_map(|x| x.as_u64().and_then(|v| if v <= 0xff { Some(v as u8) } else { None }))
+      .collect();
+    if bytes.len() == a.len() { Some(bytes) } else { None }
+  })
+}
+
+/// Encode extended attributes as a list of `[name, value]` pairs.
+pub fn xattrs_to_json(xattrs: &XAttrs) -> json::Json {
+  let pairs: Vec<json::Json> = xattrs.iter().map(|(name, value)| {
+    vec![name.to_json(), value.to_json()].to_json()
+  }).collect();
+  pairs.to_json()
+}
+
+/// Decode extended attributes encoded by `xattrs_to_json`.
+pub fn xattrs_from_json(j: &json::Json) -> Option<XAttrs> {
+  let mut xattrs = BTreeMap::new();
+  for pair in match j.as_array() { Some(a) => a, None => return None }.iter() {
+    match pair.as_array() {
+      Some(p) if p.len() == 2 => match (bytes_from_json(&p[0]), bytes_from_json(&p[1])) {
+        (Some(name), Some(value)) => { xattrs.insert(name, value); },
+        _ => return None,
+      },
+      _ => return None,
+    }
+  }
+  Some(xattrs)
+}
+
+
+impl TreeEntry {
+
+  pub fn is_directory(&self) -> bool {
+    self.kind == EntryKind::Directory
+  }
+
+  pub fn is_symlink(&self) -> bool {
+    self.kind == EntryKind::Symlink
+  }
+
+  /// Whether this is a FIFO, socket or device node.
+  pub fn is_special(&self) -> bool {
+    self.kind.special_mode().is_some()
+  }
+
+  /// Whether the metadata of this entry matches `other`, ignoring content and access time.
+  pub fn same_metadata(&self, other: &TreeEntry) -> bool {
+    self.size == other.size && self.created == other.created && self.modified == other.modified &&
+      self.permissions == other.permissions && self.user_id == other.user_id &&
+      self.group_id == other.group_id && self.xattrs == other.xattrs
+  }
+
+  /// Decode an entry of a committed directory listing.
+  ///
+  /// Listings do not store the key index id of entries (older listings may), so the id is 0. The
+  /// access time is the modification time if it is not recorded.
+  pub fn from_json(j: &json::Json) -> Option<TreeEntry> {
+    let m = match j.as_object() {
+      Some(m) => m,
+      None => return None,
+    };
+
+    // TODO(jos): Replace all uses of JSON with either protocol bufffers or cap'n proto.
+    let link_target = m.get("link").and_then(bytes_from_json);
+    let special = m.get("type").and_then(|x| x.as_string()).map(EntryKind::special_from_name);
+    let (kind, hash_key, ref_key) = if m.contains_key("dir_hash") {
+      (EntryKind::Directory, "dir_hash", "dir_ref")
+    } else if link_target.is_some() {
+      (EntryKind::Symlink, "", "")
+    } else if let Some(special_opt) = special {
+      match special_opt {
+        Some(kind) => (kind, "", ""),
+        None => return None,
+      }
+    } else {
+      (EntryKind::File, "data_hash", "data_ref")
+    };
+
+    let xattrs = match m.get("xattr") {
+      Some(x) => match xattrs_from_json(x) {
+        Some(xattrs) => xattrs,
+        None => return None,
+      },
+      None => BTreeMap::new(),
+    };
+
+    let name_opt = m.get("name").and_then(bytes_from_json);
+    let (hash_opt, ref_opt) = if hash_key.len() == 0 {
+      (Some(vec![]), Some(vec![]))
+    } else {
+      (m.get(hash_key).and_then(bytes_from_json), m.get(ref_key).and_then(bytes_from_json))
+    };
+    let modified = m.get("mt").and_then(|x| x.as_u64()).unwrap_or(0);
+    match (name_opt, hash_opt, ref_opt) {
+      (Some(name), Some(hash), Some(persistent_ref)) => Some(TreeEntry{
+        id: m.get("id").and_then(|x| x.as_u64()).unwrap_or(0),
+        name: name,
+        kind: kind,
+        size: m.get("sz").and_then(|x| x.as_u64()),
+        created: m.get("ct").and_then(|x| x.as_u64()).unwrap_or(0),
+        modified: modified,
+        accessed: m.get("at").and_then(|x| x.as_u64()).unwrap_or(modified),
+        permissions: m.get("mode").and_then(|x| x.as_u64()),
+        user_id: m.get("uid").and_then(|x| x.as_u64()),
+        group_id: m.get("gid").and_then(|x| x.as_u64()),
+        link_target: link_target,
+        hard_link: m.get("dev").and_then(|x| x.as_u64()).and_then(|dev| {
+          m.get("ino").and_then(|x| x.as_u64()).map(|ino| (dev, ino))
+        }),
+        xattrs: xattrs,
+        device: m.get("major").and_then(|x| x.as_u64()).and_then(|major| {
+          m.get("minor").and_then(|x| x.as_u64()).map(|minor| (major, minor))
+        }),
+        hash: Hash{bytes: hash},
+        persistent_ref: persistent_ref,
+      }),
+      _ => None,
+    }
+  }
+
+  /// Decode a chunk of a committed directory listing. Fails if any of its entries can not be
+  /// decoded, as skipping them would silently leave them out of restores and listings.
+  pub fn list_from_json(j: &json::Json) -> HatResult<Vec<TreeEntry>> {
+    let entries = match j.as_array() {
+      Some(entries) => entries,
+      None => return Err(HatError::CorruptData("Directory listing is not a list".to_string())),
+    };
+    let mut out = Vec::with_capacity(entries.len());
+    for entry in entries.iter() {
+      match TreeEntry::from_json(entry) {
+        Some(e) => out.push(e),
+        None => return Err(HatError::CorruptData(
+          format!("Could not decode directory entry {}", entry))),
+      }
+    }
+    Ok(out)
+  }
+}
+
+impl ToJson for TreeEntry {
+  fn to_json(&self) -> json::Json {
+    // The key index id changes without the entry changing, and would make every snapshot store
+    // new listings:
+    let mut m = BTreeMap::new();
+    m.insert("name".to_string(), self.name.to_json());
+    m.insert("ct".to_string(), self.created.to_json());
+    m.insert("mt".to_string(), self.modified.to_json());
+    m.insert("at".to_string(), self.accessed.to_json());
+    if let Some(size) = self.size {
+      m.insert("sz".to_string(), size.to_json());
+    }
+    if let Some(mode) = self.permissions {
+      m.insert("mode".to_string(), mode.to_json());
+    }
+    if let Some(uid) = self.user_id {
+      m.insert("uid".to_string(), uid.to_json());
+    }
+    if let Some(gid) = self.group_id {
+      m.insert("gid".to_string(), gid.to_json());
+    }
+    if let Some((dev, ino)) = self.hard_link {
+      m.insert("dev".to_string(), dev.to_json());
+      m.insert("ino".to_string(), ino.to_json());
+    }
+    if !self.xattrs.is_empty() {
+      m.insert("xattr".to_string(), xattrs_to_json(&self.xattrs));
+    }
+    if let Some((major, minor)) = self.device {
+      m.insert("major".to_string(), major.to_json());
+      m.insert("minor".to_string(), minor.to_json());
+    }
+
+    match self.kind {
+      EntryKind::File => {
+        m.insert("data_hash".to_string(), self.hash.bytes.to_json());
+        m.insert("data_ref".to_string(), self.persistent_ref.to_json());
+      },
+      EntryKind::Directory => {
+        m.insert("dir_hash".to_string(), self.hash.bytes.to_json());
+        m.insert("dir_ref".to_string(), self.persistent_ref.to_json());
+      },
+      EntryKind::Symlink => {
+        // Without a target, the entry decodes as corrupt:
+        if let Some(ref target) = self.link_target {
+          m.insert("link".to_string(), target.to_json());
+        }
+      },
+      EntryKind::Fifo | EntryKind::Socket | EntryKind::CharDevice | EntryKind::BlockDevice => {
+        m.insert("type".to_string(), self.kind.special_name().unwrap().to_json());
+      },
+    }
+
+    json::Json::Object(m)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use errors::{HatError};
+  use hash_index::{Hash};
+  use rustc_serialize::json;
+  use rustc_serialize::json::{ToJson};
+  use std::collections::{BTreeMap};
+
+  #[test]
+  fn identity() {
+    let mut xattrs = BTreeMap::new();
+    xattrs.insert(b"user.comment".to_vec(), b"hello".to_vec());
+    xattrs.insert(b"system.posix_acl_access".to_vec(), vec![2, 0, 0, 0, 1, 0, 6, 0]);
+
+    let file = TreeEntry{id: 1, name: b"foo".to_vec(), kind: EntryKind::File, size: Some(4),
+                         created: 2, modified: 5, accessed: 3,
+                         permissions: Some(0o100644), user_id: Some(1000), group_id: None,
+                         link_target: None, hard_link: Some((2049, 1234)), xattrs: xattrs,
+                         device: None,
+                         hash: Hash::new(b"data"), persistent_ref: b"ref".to_vec()};
+    // Names are raw bytes, and need not be valid UTF-8:
+    let dir = TreeEntry{kind: EntryKind::Directory, name: b"r\xe9sum\xe9".to_vec(), size: None,
+                        hard_link: None, xattrs: BTreeMap::new(), ..file.clone()};
+    let link = TreeEntry{kind: EntryKind::Symlink, name: b"baz".to_vec(), size: None,
+                         link_target: Some(b"../foo".to_vec()), hard_link: None,
+                         hash: Hash{bytes: vec![]},
+                         persistent_ref: vec![], ..file.clone()};
+    let fifo = TreeEntry{kind: EntryKind::Fifo, name: b"fifo".to_vec(), size: None,
+                         hard_link: None, hash: Hash{bytes: vec![]}, persistent_ref: vec![],
+                         ..file.clone()};
+    let tty = TreeEntry{kind: EntryKind::CharDevice, name: b"tty".to_vec(), device: Some((4, 1)),
+                        ..fifo.clone()};
+
+    let listing = vec![file.clone(), dir.clone(), link.clone(), fifo.clone(), tty.clone()];
+    // The id is not stored:
+    let decoded: Vec<TreeEntry> = listing.iter().map(|e| TreeEntry{id: 0, ..e.clone()}).collect();
+    assert_eq!(TreeEntry::list_from_json(&listing.to_json()), Ok(decoded));
+
+    let json = file.to_json();
+    let m = json.as_object().unwrap();
+    assert!(!m.contains_key("id"));
+  }
+
+  #[test]
+  fn invalid_listing() {
+    let file = TreeEntry{id: 1, name: b"foo".to_vec(), kind: EntryKind::File, size: None,
+                         created: 0, modified: 0, accessed: 0,
+                         permissions: None, user_id: None, group_id: None,
+                         link_target: None, hard_link: None, xattrs: BTreeMap::new(),
+                         device: None, hash: Hash::new(b"data"), persistent_ref: vec![]};
+    let valid = file.to_json();
+    let no_hash = json::Json::from_str(r#"{"name": [98, 97, 114]}"#).unwrap();
+    let bad_type = json::Json::from_str(r#"{"name": [98], "type": "door"}"#).unwrap();
+    let mut bad_name = valid.as_object().unwrap().clone();
+    bad_name.insert("name".to_string(), vec![300u64].to_json());
+
+    assert!(TreeEntry::list_from_json(&valid).is_err());
+    for bad in vec![no_hash, bad_type, json::Json::Object(bad_name)].into_iter() {
+      match TreeEntry::list_from_json(&vec![valid.clone(), bad].to_json()) {
+        Err(HatError::CorruptData(_)) => (),
+        res => panic!("Unexpected result: {:?}", res),
+      }
+    }
+  }
+
+  #[test]
+  fn special_kinds() {
+    assert_eq!(EntryKind::special_from_mode(0o010644), Some(EntryKind::Fifo));
+    assert_eq!(EntryKind::special_from_mode(0o140755), Some(EntryKind::Socket));
+    assert_eq!(EntryKind::special_from_mode(0o020620), Some(EntryKind::CharDevice));
+    assert_eq!(EntryKind::special_from_mode(0o060660), Some(EntryKind::BlockDevice));
+    assert_eq!(EntryKind::special_from_mode(0o100644), None);
+    assert_eq!(EntryKind::special_from_mode(0o040755), None);
+
+    // Entries with a mode are what their mode says, whether they have data or not:
+    assert!(EntryKind::is_directory_mode(Some(0o040755), false));
+    assert!(!EntryKind::is_directory_mode(Some(0o100644), false));
+    assert!(EntryKind::is_directory_mode(None, false));
+    assert!(!EntryKind::is_directory_mode(None, true));
+
+    for kind in [EntryKind::Fifo, EntryKind::Socket,
+                 EntryKind::CharDevice, EntryKind::BlockDevice].iter() {
+      assert_eq!(EntryKind::special_from_mode(kind.special_mode().unwrap()), Some(*kind));
+    }
+  }
+}
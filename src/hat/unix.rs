@@ -0,0 +1,161 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Unix file system calls that the standard library does not provide.
+
+use libc;
+use std::collections::{BTreeMap};
+use std::ffi::{CString};
+use std::io;
+use std::os::unix::ffi::{OsStrExt};
+use std::path::{Path};
+use std::ptr;
+
+
+extern {
+  fn lchown(path: *const libc::c_char, uid: libc::uid_t, gid: libc::gid_t) -> libc::c_int;
+  fn mknod(path: *const libc::c_char, mode: libc::mode_t, dev: libc::dev_t) -> libc::c_int;
+
+  fn llistxattr(path: *const libc::c_char, list: *mut libc::c_char,
+                size: libc::size_t) -> libc::ssize_t;
+  fn lgetxattr(path: *const libc::c_char, name: *const libc::c_char, value: *mut libc::c_void,
+               size: libc::size_t) -> libc::ssize_t;
+  fn lsetxattr(path: *const libc::c_char, name: *const libc::c_char, value: *const libc::c_void,
+               size: libc::size_t, flags: libc::c_int) -> libc::c_int;
+}
+
+
+fn c_path(path: &Path) -> io::Result<CString> {
+  Ok(try!(CString::new(path.as_os_str().as_bytes())))
+}
+
+fn check(res: libc::c_int) -> io::Result<()> {
+  if res == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+fn check_size(res: libc::ssize_t) -> io::Result<usize> {
+  if res >= 0 { Ok(res as usize) } else { Err(io::Error::last_os_error()) }
+}
+
+/// Read a value with a call that reports its size when given an empty buffer. The value can grow
+/// between the two calls, in which case we try again.
+fn read_sized<F: Fn(*mut u8, usize) -> libc::ssize_t>(f: F) -> io::Result<Vec<u8>> {
+  loop {
+    let size = try!(check_size(f(ptr::null_mut(), 0)));
+    let mut buf: Vec<u8> = Vec::with_capacity(size);
+    match check_size(f(buf.as_mut_ptr(), size)) {
+      Ok(len) => {
+        unsafe { buf.set_len(len) };
+        return Ok(buf);
+      },
+      Err(ref e) if e.raw_os_error() == Some(libc::ERANGE) => continue,
+      Err(e) => return Err(e),
+    }
+  }
+}
+
+
+/// Change the owner and group of `path`, without following symlinks.
+pub fn set_owner(path: &Path, uid: u64, gid: u64) -> io::Result<()> {
+  let p = try!(c_path(path));
+  check(unsafe { lchown(p.as_ptr(), uid as libc::uid_t, gid as libc::gid_t) })
+}
+
+/// Create a FIFO, socket or device node at `path`. `mode` includes the file type bits, and `dev`
+/// is only used for devices.
+pub fn make_node(path: &Path, mode: u64, dev: u64) -> io::Result<()> {
+  let p = try!(c_path(path));
+  check(unsafe { mknod(p.as_ptr(), mode as libc::mode_t, dev as libc::dev_t) })
+}
+
+/// Split a device number into its major and minor number (using the Linux encoding).
+pub fn device_numbers(dev: u64) -> (u64, u64) {
+  let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & 0xfffff000);
+  let minor = (dev & 0xff) | ((dev >> 12) & 0xffffff00);
+  (major, minor)
+}
+
+/// Combine a major and minor number into a device number; the inverse of `device_numbers`.
+pub fn make_device(major: u64, minor: u64) -> u64 {
+  (minor & 0xff) | ((major & 0xfff) << 8) |
+    ((minor & 0xffffff00) << 12) | ((major & 0xfffff000) << 32)
+}
+
+/// Whether `e` means that the file system does not support the operation (e.g. xattrs).
+pub fn is_unsupported(e: &io::Error) -> bool {
+  e.raw_os_error() == Some(libc::ENOTSUP)
+}
+
+/// Whether `e` means that we lack the privileges for the operation (e.g. creating device nodes
+/// when not running as root).
+pub fn is_not_permitted(e: &io::Error) -> bool {
+  e.raw_os_error() == Some(libc::EPERM) || e.raw_os_error() == Some(libc::EACCES)
+}
+
+/// Read all extended attributes of `path` (including POSIX ACLs and security labels), without
+/// following symlinks. File systems without xattr support have none.
+pub fn xattrs(path: &Path) -> io::Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+  let p = try!(c_path(path));
+  let mut attrs = BTreeMap::new();
+
+  let names = match read_sized(|buf, size| unsafe {
+    llistxattr(p.as_ptr(), buf as *mut libc::c_char, size as libc::size_t)
+  }) {
+    Ok(names) => names,
+    Err(ref e) if is_unsupported(e) => return Ok(attrs),
+    Err(e) => return Err(e),
+  };
+
+  for name in names.split(|&b| b == 0).filter(|n| n.len() > 0) {
+    let c_name = try!(CString::new(name.to_vec()));
+    match read_sized(|buf, size| unsafe {
+      lgetxattr(p.as_ptr(), c_name.as_ptr(), buf as *mut libc::c_void, size as libc::size_t)
+    }) {
+      Ok(value) => { attrs.insert(name.to_vec(), value); },
+      // The attribute was removed after we listed it:
+      Err(ref e) if e.raw_os_error() == Some(libc::ENODATA) => (),
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(attrs)
+}
+
+/// Set the extended attribute `name` of `path`, without following symlinks.
+pub fn set_xattr(path: &Path, name: &[u8], value: &[u8]) -> io::Result<()> {
+  let p = try!(c_path(path));
+  let c_name = try!(CString::new(name.to_vec()));
+  check(unsafe {
+    lsetxattr(p.as_ptr(), c_name.as_ptr(), value.as_ptr() as *const libc::c_void,
+              value.len() as libc::size_t, 0)
+  })
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn device_numbers_roundtrip() {
+    // /dev/tty1 and /dev/sda:
+    assert_eq!(device_numbers(0x0401), (4, 1));
+    assert_eq!(device_numbers(0x0800), (8, 0));
+    assert_eq!(make_device(4, 1), 0x0401);
+
+    for &(major, minor) in [(0, 0), (4, 1), (259, 3), (4095, 255), (4096, 256),
+                            (0xffffffff, 0xffffffff)].iter() {
+      assert_eq!(device_numbers(make_device(major, minor)), (major, minor));
+    }
+  }
+}
@@ -0,0 +1,367 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Consistency checking of committed snapshots.
+//!
+//! The verifier walks the directory trees of snapshots and checks every chunk they reference: the
+//! hash must be committed in the hash index, its persistent reference must be a `BlobID`, and the
+//! blob must exist in the backend and be long enough to contain the chunk. Optionally, the chunk
+//! data is hashed again to detect corruption.
+//!
+//! Data shared between snapshots is only checked once, so damage is reported for the first
+//! snapshot (and path) found to refer to it.
+
+use rustc_serialize::json;
+use std::collections::{HashSet};
+use std::ffi::{OsStr};
+use std::os::unix::ffi::{OsStrExt};
+use std::path::PathBuf;
+use std::str;
+
+use blob_store::{BlobID, BlobStoreBackend};
+use errors::{HatError, HatResult};
+use hash_index;
+use hash_index::{Hash, HashIndexProcess};
+use hash_tree;
+use snapshot_index::{SnapshotInfo};
+use tree_entry::{TreeEntry};
+
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Damage {
+  /// The hash is not in the hash index.
+  MissingHash(Hash),
+
+  /// The hash is in the hash index, but its data was never committed to a blob.
+  UncommittedHash(Hash),
+
+  /// The persistent reference of the hash is not a valid `BlobID`.
+  InvalidReference(Hash),
+
+  /// The blob containing the hash's data could not be read from the backend.
+  MissingBlob{hash: Hash, blob: Vec<u8>, error: HatError},
+
+  /// The blob containing the hash's data is shorter than the chunk's byte range.
+  TruncatedBlob{hash: Hash, blob: Vec<u8>, length: usize, expected: usize},
+
+  /// The data does not match its hash.
+  Corrupt(Hash),
+
+  /// A chunk of a directory listing could not be decoded.
+  InvalidListing(Hash),
+}
+
+/// Damage found in a snapshot.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DamageEntry {
+  pub family: String,
+  pub snapshot_id: u64,
+
+  /// Path of the damaged file or directory, relative to the snapshot root.
+  pub path: PathBuf,
+
+  pub damage: Damage,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Report {
+  pub snapshots: usize,
+  pub directories: usize,
+  pub chunks: usize,
+  pub damage: Vec<DamageEntry>,
+}
+
+impl Report {
+  pub fn is_ok(&self) -> bool {
+    self.damage.len() == 0
+  }
+}
+
+
+pub struct Verifier<B> {
+  hash_index: HashIndexProcess,
+  backend: B,
+
+  /// Whether to hash the data of every chunk again.
+  check_data: bool,
+
+  /// Most recently read blob; consecutive chunks are usually stored in the same blob.
+  last_blob: Option<(Vec<u8>, HatResult<Vec<u8>>)>,
+
+  checked_chunks: HashSet<Vec<u8>>,
+  checked_dirs: HashSet<Vec<u8>>,
+
+  report: Report,
+}
+
+impl <B: BlobStoreBackend> Verifier<B> {
+
+  pub fn new(hash_index: HashIndexProcess, backend: B, check_data: bool) -> Verifier<B> {
+    Verifier{hash_index: hash_index, backend: backend, check_data: check_data, last_blob: None,
+             checked_chunks: HashSet::new(), checked_dirs: HashSet::new(),
+             report: Report{snapshots: 0, directories: 0, chunks: 0, damage: vec![]}}
+  }
+
+  /// Check the complete directory tree of `snapshot`. Damage goes into the report; failing to
+  /// read the local hash index is an error.
+  pub fn verify_snapshot(&mut self, snapshot: &SnapshotInfo) -> HatResult<()> {
+    self.report.snapshots += 1;
+    let mut path = PathBuf::new();
+    self.verify_dir(snapshot, &mut path, snapshot.hash.clone())
+  }
+
+  pub fn report(self) -> Report {
+    self.report
+  }
+
+  fn damaged(&mut self, snapshot: &SnapshotInfo, path: &PathBuf, damage: Damage) {
+    self.report.damage.push(DamageEntry{family: snapshot.family.clone(), snapshot_id: snapshot.id,
+                                        path: path.clone(), damage: damage});
+  }
+
+  fn verify_dir(&mut self, snapshot: &SnapshotInfo, path: &mut PathBuf, dir_hash: Hash)
+                -> HatResult<()> {
+    if !self.checked_dirs.insert(dir_hash.bytes.clone()) {
+      return Ok(());
+    }
+    self.report.directories += 1;
+
+    let mut chunks = Vec::new();
+    try!(self.verify_tree(snapshot, path, dir_hash, Some(&mut chunks)));
+
+    for (hash, chunk) in chunks.into_iter() {
+      if chunk.len() == 0 {
+        continue;
+      }
+      let listing = str::from_utf8(&chunk[..]).ok().and_then(|s| json::Json::from_str(s).ok());
+      // A listing with an entry that can not be decoded is as damaged as one that can not be
+      // parsed at all, since restoring it would leave the entry out:
+      let entries = match listing.as_ref().map(TreeEntry::list_from_json) {
+        Some(Ok(entries)) => entries,
+        _ => {
+          self.damaged(snapshot, path, Damage::InvalidListing(hash));
+          continue;
+        },
+      };
+
+      for entry in entries.into_iter() {
+        path.push(OsStr::from_bytes(&entry.name[..]));
+        if entry.is_directory() {
+          try!(self.verify_dir(snapshot, path, entry.hash));
+        } else if entry.hash.bytes.len() > 0 {
+          try!(self.verify_tree(snapshot, path, entry.hash, None));
+        }
+        path.pop();
+      }
+    }
+    Ok(())
+  }
+
+  /// Check the hash tree with top hash `hash`. If `leaves` is given, the data of its leaf chunks is
+  /// collected there (in order); otherwise, sub-trees that were already checked are skipped.
+  fn verify_tree(&mut self, snapshot: &SnapshotInfo, path: &PathBuf, hash: Hash,
+                 mut leaves: Option<&mut Vec<(Hash, Vec<u8>)>>) -> HatResult<()> {
+    if leaves.is_none() && self.checked_chunks.contains(&hash.bytes) {
+      return Ok(());
+    }
+
+    let data = match try!(self.fetch_chunk(&hash)) {
+      Ok(data) => data,
+      Err(damage) => {
+        self.checked_chunks.insert(hash.bytes);
+        return Ok(self.damaged(snapshot, path, damage));
+      },
+    };
+    self.checked_chunks.insert(hash.bytes.clone());
+    self.report.chunks += 1;
+
+    match hash_tree::hash_refs_from_bytes(&data[..]) {
+      Some(children) => {
+        if self.check_data && hash_tree::internal_node_hash(&children) != hash {
+          return Ok(self.damaged(snapshot, path, Damage::Corrupt(hash)));
+        }
+        for child in children.into_iter() {
+          let child_leaves = match leaves {
+            Some(ref mut l) => Some(&mut **l),
+            None => None,
+          };
+          try!(self.verify_tree(snapshot, path, Hash{bytes: child.hash}, child_leaves));
+        }
+      },
+      None => {
+        if self.check_data && Hash::new(&data[..]) != hash {
+          return Ok(self.damaged(snapshot, path, Damage::Corrupt(hash)));
+        }
+        if let Some(l) = leaves {
+          l.push((hash, data));
+        }
+      },
+    }
+    Ok(())
+  }
+
+  /// Locate and read the data of a single chunk.
+  fn fetch_chunk(&mut self, hash: &Hash) -> HatResult<Result<Vec<u8>, Damage>> {
+    let persistent_ref = match self.hash_index.send_reply(
+      hash_index::Msg::FetchPersistentRef(hash.clone())) {
+      hash_index::Reply::PersistentRef(r) => r,
+      hash_index::Reply::Retry => return Ok(Err(Damage::UncommittedHash(hash.clone()))),
+      hash_index::Reply::HashNotKnown => return Ok(Err(Damage::MissingHash(hash.clone()))),
+      hash_index::Reply::Error(e) => return Err(e),
+      _ => return Err(HatError::unexpected_reply("hash index")),
+    };
+
+    let blob_id = match BlobID::try_from_bytes(&persistent_ref[..]) {
+      Some(id) => id,
+      None => return Ok(Err(Damage::InvalidReference(hash.clone()))),
+    };
+    if blob_id.is_empty() {
+      return Ok(Ok(vec![]));
+    }
+
+    let cached = match self.last_blob {
+      Some((ref name, _)) => &name[..] == blob_id.name(),
+      None => false,
+    };
+    if !cached {
+      let res = self.backend.retrieve(blob_id.name());
+      self.last_blob = Some((blob_id.name().to_vec(), res));
+    }
+
+    Ok(match self.last_blob {
+      Some((_, Err(ref e))) =>
+        Err(Damage::MissingBlob{hash: hash.clone(), blob: blob_id.name().to_vec(),
+                                error: e.clone()}),
+      Some((_, Ok(ref blob))) if blob.len() < blob_id.end() || blob_id.begin() > blob_id.end() =>
+        Err(Damage::TruncatedBlob{hash: hash.clone(), blob: blob_id.name().to_vec(),
+                                  length: blob.len(), expected: blob_id.end()}),
+      Some((_, Ok(ref blob))) => Ok(blob[blob_id.begin() .. blob_id.end()].to_vec()),
+      None => unreachable!(),
+    })
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use rustc_serialize::json;
+  use rustc_serialize::json::{ToJson};
+  use std::collections::{BTreeMap};
+  use std::path::PathBuf;
+
+  use blob_store;
+  use blob_store::tests::{MemoryBackend, DevNullBackend};
+  use hash_index;
+  use hash_index::{Hash, HashIndex};
+  use hash_tree::{SimpleHashTreeWriter};
+  use key_store::{HashStoreBackend};
+  use process::{Process};
+  use snapshot_index::{SnapshotInfo};
+  use tree_entry::{TreeEntry, EntryKind};
+
+  fn write_snapshot<B: 'static + blob_store::BlobStoreBackend + Send + Clone>(backend: B)
+    -> (hash_index::HashIndexProcess, SnapshotInfo) {
+    write_snapshot_with(backend, vec![])
+  }
+
+  /// Write a snapshot with a single file, and `extra` entries in the listing of its root.
+  fn write_snapshot_with<B: 'static + blob_store::BlobStoreBackend + Send + Clone>(
+    backend: B, extra: Vec<json::Json>) -> (hash_index::HashIndexProcess, SnapshotInfo) {
+    let hi_p = Process::new(Box::new(move|| { HashIndex::new_for_testing() }));
+    let bs_p = Process::new(Box::new(move|| { blob_store::BlobStore::new_for_testing(backend,
+                                                                                      1024) }));
+    let tree_backend = HashStoreBackend::new(hi_p.clone(), bs_p.clone());
+
+    let mut file = SimpleHashTreeWriter::new(4, tree_backend.clone());
+    for i in 0..10u8 {
+      file.append(vec![i; 100]).unwrap();
+    }
+    let (file_hash, file_ref) = file.hash().unwrap();
+
+    let entry = TreeEntry{id: 1, name: b"file".to_vec(), kind: EntryKind::File, size: Some(1000),
+                          created: 0, modified: 0, accessed: 0,
+                          permissions: None, user_id: None, group_id: None, link_target: None,
+                          hard_link: None, xattrs: BTreeMap::new(), device: None,
+                          hash: file_hash, persistent_ref: file_ref};
+    let mut dir = SimpleHashTreeWriter::new(4, tree_backend);
+    let mut listing = vec![entry.to_json()];
+    listing.extend(extra.into_iter());
+    dir.append(listing.to_json().to_string().as_bytes().to_vec()).unwrap();
+    let (dir_hash, dir_ref) = dir.hash().unwrap();
+
+    bs_p.send_reply(blob_store::Msg::Flush);
+    hi_p.send_reply(hash_index::Msg::Flush);
+
+    (hi_p, SnapshotInfo{id: 1, family: "family".to_string(), created: 0,
+                        hash: dir_hash, tree_ref: dir_ref, metadata: Default::default()})
+  }
+
+  #[test]
+  fn intact() {
+    let backend = MemoryBackend::new();
+    let (hi_p, snapshot) = write_snapshot(backend.clone());
+
+    let mut verifier = Verifier::new(hi_p, backend, true);
+    verifier.verify_snapshot(&snapshot).unwrap();
+    let report = verifier.report();
+    assert!(report.is_ok());
+    assert_eq!(report.snapshots, 1);
+    assert_eq!(report.directories, 1);
+  }
+
+  #[test]
+  fn missing_blobs() {
+    let (hi_p, snapshot) = write_snapshot(DevNullBackend);
+
+    let mut verifier = Verifier::new(hi_p, DevNullBackend, false);
+    verifier.verify_snapshot(&snapshot).unwrap();
+    let report = verifier.report();
+    assert_eq!(report.damage.len(), 1);
+    match report.damage[0].damage {
+      Damage::MissingBlob{ref hash, ..} => assert_eq!(hash, &snapshot.hash),
+      ref d => panic!("Unexpected damage: {:?}", d),
+    }
+  }
+
+  #[test]
+  fn missing_hashes() {
+    let (_, snapshot) = write_snapshot(MemoryBackend::new());
+    let hi_p = Process::new(Box::new(move|| { HashIndex::new_for_testing() }));
+
+    let mut verifier = Verifier::new(hi_p, MemoryBackend::new(), false);
+    verifier.verify_snapshot(&snapshot).unwrap();
+    let report = verifier.report();
+    assert_eq!(report.damage, vec![DamageEntry{family: "family".to_string(), snapshot_id: 1,
+                                               path: PathBuf::new(),
+                                               damage: Damage::MissingHash(snapshot.hash)}]);
+  }
+
+  #[test]
+  fn invalid_listing_entry() {
+    let backend = MemoryBackend::new();
+    let bad_entry = json::Json::from_str(r#"{"name": [120]}"#).unwrap();
+    let (hi_p, snapshot) = write_snapshot_with(backend.clone(), vec![bad_entry]);
+
+    let mut verifier = Verifier::new(hi_p, backend, false);
+    verifier.verify_snapshot(&snapshot).unwrap();
+    let report = verifier.report();
+    assert_eq!(report.damage.len(), 1);
+    match report.damage[0].damage {
+      Damage::InvalidListing(_) => (),
+      ref d => panic!("Unexpected damage: {:?}", d),
+    }
+  }
+}